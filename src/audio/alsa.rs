@@ -0,0 +1,510 @@
+//! Simple mixer control through ALSA, for systems without a sound server.
+//!
+//! Volumes are fractions of the nominal volume like with PulseAudio. Elements with a dB range
+//! are mapped through the same cubic curve, with 0dB as the nominal volume. Elements without one
+//! are treated as linear between their minimum and maximum.
+
+use std::io::{Error, ErrorKind};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use ::alsa::mixer::{MilliBel, Mixer, Selem, SelemChannelId, SelemId};
+use ::alsa::{poll, PollDescriptors, Round};
+
+use super::{
+    broadcast, check_absolute, AudioEvent, Backend, Listener, SoundDevice, VolumePolicy,
+    VolumeScale,
+};
+
+/// Elements tried in order when none is requested.
+pub const DEFAULT_ELEMENTS: &[&str] = &["Master", "PCM", "Capture"];
+
+pub struct AlsaSoundDevice {
+    mixer: Mixer,
+    card: String,
+    element: String,
+    capture: bool,
+    scale: VolumeScale,
+    policy: VolumePolicy,
+    volume: f32,
+    muted: bool,
+    listeners: Arc<Mutex<Vec<Listener>>>,
+}
+
+fn mixer_error(e: ::alsa::Error) -> Error {
+    Error::new(ErrorKind::Other, e)
+}
+
+impl AlsaSoundDevice {
+    /// Open the mixer of a card, such as `default` or `hw:0`, and control the named element, or
+    /// the first of `DEFAULT_ELEMENTS` the card has.
+    pub fn new(card: &str, element: Option<&str>) -> Result<Self, Error> {
+        let mixer = Mixer::new(card, false).map_err(mixer_error)?;
+
+        let candidates = match element {
+            Some(element) => vec![element],
+            None => DEFAULT_ELEMENTS.to_vec(),
+        };
+        let found = candidates.into_iter().find_map(|name| {
+            mixer
+                .find_selem(&SelemId::new(name, 0))
+                .filter(|selem| selem.has_volume())
+                .map(|selem| (name.to_string(), !selem.has_playback_volume()))
+        });
+        let (element, capture) = match found {
+            Some(v) => v,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    "no such mixer element",
+                ))
+            }
+        };
+
+        let mut device = AlsaSoundDevice {
+            mixer,
+            card: card.to_string(),
+            element,
+            capture,
+            scale: VolumeScale::default(),
+            policy: VolumePolicy::default(),
+            volume: 0.0,
+            muted: false,
+            listeners: Arc::new(Mutex::new(Vec::new())),
+        };
+        device.volume = device.fraction()?;
+        device.muted = device.muted();
+        Ok(device)
+    }
+
+    /// The name of the element being controlled.
+    pub fn element_name(&self) -> String {
+        self.element.clone()
+    }
+
+    /// Whether the element controls capture rather than playback.
+    pub fn is_capture(&self) -> bool {
+        self.capture
+    }
+
+    /// List the elements of the card that have a volume.
+    pub fn elements(&self) -> Vec<String> {
+        self.mixer
+            .iter()
+            .filter_map(Selem::new)
+            .filter(|selem| selem.has_volume())
+            .filter_map(|selem| selem.get_id().get_name().ok().map(|n| n.to_string()))
+            .collect()
+    }
+
+    /// The scale used by `volume`, `add_volume` and `set_volume`.
+    pub fn volume_scale(&self) -> VolumeScale {
+        self.scale
+    }
+
+    pub fn set_volume_scale(&mut self, scale: VolumeScale) {
+        self.scale = scale;
+    }
+
+    /// The limits `add_volume` and `set_volume` are clamped to. Elements have no base volume, so
+    /// the hard maximum defaults to 0dB.
+    pub fn volume_policy(&self) -> VolumePolicy {
+        self.policy
+    }
+
+    pub fn set_volume_policy(&mut self, policy: VolumePolicy) {
+        self.policy = policy;
+    }
+
+    /// The dB range of the element, if it has one.
+    pub fn db_range(&self) -> Option<(f32, f32)> {
+        let selem = self.selem().ok()?;
+        self.db_range_of(&selem)
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.scale.from_fraction(self.fraction().unwrap_or(0.0))
+    }
+
+    pub fn muted(&self) -> bool {
+        let selem = match self.selem() {
+            Ok(selem) => selem,
+            Err(_) => return false,
+        };
+        self.channels(&selem).into_iter().any(|channel| {
+            let switch = if self.capture {
+                selem.get_capture_switch(channel)
+            } else {
+                selem.get_playback_switch(channel)
+            };
+            switch.map_or(false, |on| on == 0)
+        })
+    }
+
+    pub fn channel_names(&self) -> Vec<String> {
+        match self.selem() {
+            Ok(selem) => self
+                .channels(&selem)
+                .into_iter()
+                .map(|channel| channel.to_string())
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    pub fn channel_volumes(&self) -> Vec<f32> {
+        match self.selem() {
+            Ok(selem) => self
+                .channels(&selem)
+                .into_iter()
+                .map(|channel| self.get_fraction(&selem, channel).unwrap_or(0.0))
+                .collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    pub fn add_volume(&mut self, step: f32, cap: Option<f32>) -> Result<(), Error> {
+        let loudest = self.loudest()?;
+        let mut target = self.scale.step(loudest, step);
+        if step > 0.0 {
+            if let Some(cap) = cap {
+                target = target.min(self.scale.to_fraction(cap));
+            }
+        }
+        let target = self.apply_policy(loudest, target);
+        self.scale_to(target)
+    }
+
+    /// Set the volume of the loudest channel, keeping the ratio between channels.
+    pub fn set_volume(&mut self, val: f32, cap: Option<f32>) -> Result<(), Error> {
+        check_absolute(val, self.scale)?;
+        let mut target = self.scale.to_fraction(val);
+        if let Some(cap) = cap {
+            target = target.min(self.scale.to_fraction(cap));
+        }
+        let target = self.apply_policy(self.loudest()?, target);
+        self.scale_to(target)
+    }
+
+    pub fn set_muted(&mut self, muted: bool) -> Result<(), Error> {
+        let selem = self.selem()?;
+        let on = if muted { 0 } else { 1 };
+        if self.capture && selem.has_capture_switch() {
+            selem.set_capture_switch_all(on).map_err(mixer_error)
+        } else if !self.capture && selem.has_playback_switch() {
+            selem.set_playback_switch_all(on).map_err(mixer_error)
+        } else {
+            Err(Error::new(
+                ErrorKind::Other,
+                "mixer element cannot be muted",
+            ))
+        }
+    }
+
+    /// Wait for changes to the mixer through its poll descriptors, and report the resulting
+    /// events. A timeout of `None` waits indefinitely.
+    pub fn wait_events(&mut self, timeout: Option<Duration>) -> Result<Vec<AudioEvent>, Error> {
+        let mut fds = self.mixer.get().map_err(mixer_error)?;
+        let timeout = timeout.map_or(-1, |t| t.as_millis() as i32);
+        if poll::poll(&mut fds, timeout).map_err(mixer_error)? == 0 {
+            return Ok(Vec::new());
+        }
+        self.mixer.handle_events().map_err(mixer_error)?;
+
+        let mut events = Vec::new();
+        let volume = self.fraction()?;
+        if volume != self.volume {
+            self.volume = volume;
+            events.push(AudioEvent::VolumeChanged {
+                sink: self.element.clone(),
+                volume,
+            });
+        }
+        let muted = self.muted();
+        if muted != self.muted {
+            self.muted = muted;
+            events.push(AudioEvent::MuteChanged {
+                sink: self.element.clone(),
+                muted,
+            });
+        }
+        Ok(events)
+    }
+
+    /// Subscribe to audio events, watched for by a thread with its own handle on the mixer. The
+    /// thread exits on the first event after the receiver hangs up.
+    pub fn subscribe(&self) -> Receiver<AudioEvent> {
+        let (tx, rx) = channel();
+        // Boost is not a mixer change, so it is reported by this device rather than the thread.
+        let boost = tx.clone();
+        self.listeners
+            .lock()
+            .unwrap()
+            .push(Box::new(move |event| boost.send(event.clone()).is_ok()));
+        let card = self.card.clone();
+        let element = self.element.clone();
+        let _ = thread::Builder::new()
+            .name("alsa_events".to_string())
+            .spawn(move || {
+                let mut device = match AlsaSoundDevice::new(&card, Some(&element)) {
+                    Ok(device) => device,
+                    Err(_) => return,
+                };
+                while let Ok(events) = device.wait_events(None) {
+                    for event in events {
+                        if tx.send(event).is_err() {
+                            return;
+                        }
+                    }
+                }
+            });
+        rx
+    }
+
+    // Clamp a target volume according to the volume policy, and report it if it engages boost.
+    fn apply_policy(&self, current: f32, target: f32) -> f32 {
+        let (target, boosted) = self.policy.apply(target, current, None);
+        if boosted {
+            broadcast(
+                &self.listeners,
+                AudioEvent::BoostEngaged {
+                    sink: self.element.clone(),
+                    volume: target,
+                },
+            );
+        }
+        target
+    }
+
+    fn selem(&self) -> Result<Selem<'_>, Error> {
+        self.mixer
+            .find_selem(&SelemId::new(&self.element, 0))
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, "mixer element went away"))
+    }
+
+    fn channels(&self, selem: &Selem) -> Vec<SelemChannelId> {
+        SelemChannelId::all()
+            .iter()
+            .cloned()
+            .filter(|channel| *channel != SelemChannelId::Unknown && *channel != SelemChannelId::Last)
+            .filter(|channel| {
+                if self.capture {
+                    selem.has_capture_channel(*channel)
+                } else {
+                    selem.has_playback_channel(*channel)
+                }
+            })
+            .collect()
+    }
+
+    fn db_range_of(&self, selem: &Selem) -> Option<(f32, f32)> {
+        let (min, max) = if self.capture {
+            selem.get_capture_db_range()
+        } else {
+            selem.get_playback_db_range()
+        };
+        if min < max {
+            Some((min.to_db(), max.to_db()))
+        } else {
+            None
+        }
+    }
+
+    fn raw_range(&self, selem: &Selem) -> (i64, i64) {
+        if self.capture {
+            selem.get_capture_volume_range()
+        } else {
+            selem.get_playback_volume_range()
+        }
+    }
+
+    fn get_fraction(&self, selem: &Selem, channel: SelemChannelId) -> Result<f32, Error> {
+        if self.db_range_of(selem).is_some() {
+            let db = if self.capture {
+                selem.get_capture_vol_db(channel)
+            } else {
+                selem.get_playback_vol_db(channel)
+            };
+            return Ok(VolumeScale::Decibel.to_fraction(db.map_err(mixer_error)?.to_db()));
+        }
+
+        let (min, max) = self.raw_range(selem);
+        let raw = if self.capture {
+            selem.get_capture_volume(channel)
+        } else {
+            selem.get_playback_volume(channel)
+        };
+        let raw = raw.map_err(mixer_error)?;
+        if max > min {
+            Ok((raw - min) as f32 / (max - min) as f32)
+        } else {
+            Ok(0.0)
+        }
+    }
+
+    fn set_fraction(&self, selem: &Selem, channel: SelemChannelId, fraction: f32) -> Result<(), Error> {
+        if let Some((min, max)) = self.db_range_of(selem) {
+            let db = VolumeScale::Decibel.from_fraction(fraction).clamp(min, max);
+            let res = if self.capture {
+                selem.set_capture_db(channel, MilliBel::from_db(db), Round::Floor)
+            } else {
+                selem.set_playback_db(channel, MilliBel::from_db(db), Round::Floor)
+            };
+            return res.map_err(mixer_error);
+        }
+
+        let (min, max) = self.raw_range(selem);
+        let raw = min + (fraction.clamp(0.0, 1.0) * (max - min) as f32).round() as i64;
+        let res = if self.capture {
+            selem.set_capture_volume(channel, raw)
+        } else {
+            selem.set_playback_volume(channel, raw)
+        };
+        res.map_err(mixer_error)
+    }
+
+    // The average volume over all channels, as a fraction of the nominal volume.
+    fn fraction(&self) -> Result<f32, Error> {
+        let volumes = self.channel_volumes();
+        if volumes.is_empty() {
+            return Err(Error::new(
+                ErrorKind::Other,
+                "unable to read mixer volume",
+            ));
+        }
+        Ok(volumes.iter().sum::<f32>() / volumes.len() as f32)
+    }
+
+    fn loudest(&self) -> Result<f32, Error> {
+        let selem = self.selem()?;
+        let mut loudest: f32 = 0.0;
+        for channel in self.channels(&selem) {
+            loudest = loudest.max(self.get_fraction(&selem, channel)?);
+        }
+        Ok(loudest)
+    }
+
+    // Scale all channels so that the loudest one ends up at the target.
+    fn scale_to(&mut self, target: f32) -> Result<(), Error> {
+        let selem = self.selem()?;
+        let loudest = self.loudest()?;
+        for channel in self.channels(&selem) {
+            let fraction = if loudest > 0.0 {
+                self.get_fraction(&selem, channel)? * target / loudest
+            } else {
+                target
+            };
+            self.set_fraction(&selem, channel, fraction)?;
+        }
+        Ok(())
+    }
+}
+
+impl SoundDevice for AlsaSoundDevice {
+    fn backend(&self) -> Backend {
+        Backend::Alsa
+    }
+
+    fn volume_scale(&self) -> VolumeScale {
+        AlsaSoundDevice::volume_scale(self)
+    }
+
+    fn set_volume_scale(&mut self, scale: VolumeScale) {
+        AlsaSoundDevice::set_volume_scale(self, scale)
+    }
+
+    fn volume_policy(&self) -> VolumePolicy {
+        AlsaSoundDevice::volume_policy(self)
+    }
+
+    fn set_volume_policy(&mut self, policy: VolumePolicy) {
+        AlsaSoundDevice::set_volume_policy(self, policy)
+    }
+
+    fn volume(&self) -> f32 {
+        AlsaSoundDevice::volume(self)
+    }
+
+    fn muted(&self) -> bool {
+        AlsaSoundDevice::muted(self)
+    }
+
+    fn channel_names(&self) -> Vec<String> {
+        AlsaSoundDevice::channel_names(self)
+    }
+
+    fn channel_volumes(&self) -> Vec<f32> {
+        AlsaSoundDevice::channel_volumes(self)
+    }
+
+    fn add_volume(&mut self, step: f32, cap: Option<f32>) -> Result<(), Error> {
+        AlsaSoundDevice::add_volume(self, step, cap)
+    }
+
+    fn set_volume(&mut self, val: f32, cap: Option<f32>) -> Result<(), Error> {
+        AlsaSoundDevice::set_volume(self, val, cap)
+    }
+
+    fn set_muted(&mut self, muted: bool) -> Result<(), Error> {
+        AlsaSoundDevice::set_muted(self, muted)
+    }
+
+    fn subscribe(&self) -> Receiver<AudioEvent> {
+        AlsaSoundDevice::subscribe(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::process::Command;
+
+    // Needs a sound card to hold the softvol control, such as one from the snd-dummy module, so
+    // this is run explicitly with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn softvol_volume_roundtrip() {
+        let config = env::temp_dir().join("platformctl_test.asoundrc");
+        let config_path = format!("/usr/share/alsa/alsa.conf:{}", config.display());
+
+        // The environment is shared with the tests running in parallel, so rather than changing
+        // it, run this test again in a process that has the configuration set.
+        if env::var("ALSA_CONFIG_PATH").ok() != Some(config_path.clone()) {
+            fs::write(
+                &config,
+                "pcm.platformctl_test {\n\
+                 \ttype softvol\n\
+                 \tslave.pcm \"null\"\n\
+                 \tcontrol { name \"Platformctl Test\" card 0 }\n\
+                 }\n",
+            )
+            .unwrap();
+            let status = Command::new(env::current_exe().unwrap())
+                .args([
+                    "--ignored",
+                    "--exact",
+                    "audio::alsa::tests::softvol_volume_roundtrip",
+                ])
+                .env("ALSA_CONFIG_PATH", config_path)
+                .status()
+                .unwrap();
+            assert!(status.success());
+            return;
+        }
+
+        // The softvol control is only created once its PCM has been opened.
+        drop(::alsa::PCM::new("platformctl_test", ::alsa::Direction::Playback, false).unwrap());
+
+        let mut dev = AlsaSoundDevice::new("hw:0", Some("Platformctl Test")).unwrap();
+        assert!(dev.db_range().is_some());
+        dev.set_volume(0.5, None).unwrap();
+        assert!((dev.volume() - 0.5).abs() < 0.02);
+        dev.add_volume(0.1, Some(0.55)).unwrap();
+        assert!((dev.volume() - 0.55).abs() < 0.02);
+        assert!(dev.set_volume(-0.5, None).is_err());
+    }
+}
@@ -0,0 +1,193 @@
+//! Keep the speaker and microphone mute LEDs found on many laptops, such as `platform::mute`
+//! and `platform::micmute`, in sync with the sound server.
+//!
+//! The kernel only drives these LEDs itself when muting through its own mixer, so they drift out
+//! of sync when muting through PulseAudio.
+
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+#[cfg(feature = "pulseaudio")]
+use super::pulseaudio::PulseAudioSoundDevice;
+#[cfg(feature = "pulseaudio")]
+use super::AudioEvent;
+
+/// A mute LED under `/sys/class/leds`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MuteLed {
+    path: PathBuf,
+    max_brightness: u64,
+}
+
+fn read_u64(path: &Path) -> Result<u64, Error> {
+    fs::read_to_string(path)?
+        .trim()
+        .parse()
+        .map_err(|_e| Error::new(ErrorKind::InvalidData, "unable to parse value"))
+}
+
+impl MuteLed {
+    pub fn open(path: PathBuf) -> Result<Self, Error> {
+        let max_brightness = read_u64(&path.join("max_brightness"))?;
+        Ok(MuteLed {
+            path,
+            max_brightness,
+        })
+    }
+
+    /// Find the LED whose name ends with `::<function>`, such as `mute` or `micmute`, under the
+    /// sysfs tree at `sysfs`. Platform LEDs are preferred over ones driven by the sound card.
+    pub fn find(sysfs: &Path, function: &str) -> Result<Option<Self>, Error> {
+        let entries = match sysfs.join("class").join("leds").read_dir() {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let suffix = format!("::{}", function);
+        let mut found = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.ends_with(&suffix) {
+                found.push((!name.starts_with("platform::"), name, entry.path()));
+            }
+        }
+        found.sort();
+        match found.into_iter().next() {
+            Some((_, _, path)) => MuteLed::open(path).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_on(&self) -> Result<bool, Error> {
+        Ok(read_u64(&self.path.join("brightness"))? > 0)
+    }
+
+    pub fn set(&self, on: bool) -> Result<(), Error> {
+        let brightness = if on { self.max_brightness } else { 0 };
+        fs::write(self.path.join("brightness"), brightness.to_string())
+    }
+}
+
+/// The speaker and microphone mute LEDs of a machine, either of which may be missing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MuteLeds {
+    pub mute: Option<MuteLed>,
+    pub micmute: Option<MuteLed>,
+}
+
+impl MuteLeds {
+    /// Detect the LEDs under `/sys`.
+    pub fn detect() -> Result<Self, Error> {
+        MuteLeds::detect_in(Path::new("/sys"))
+    }
+
+    pub fn detect_in(sysfs: &Path) -> Result<Self, Error> {
+        Ok(MuteLeds {
+            mute: MuteLed::find(sysfs, "mute")?,
+            micmute: MuteLed::find(sysfs, "micmute")?,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.mute.is_none() && self.micmute.is_none()
+    }
+
+    /// Light the LEDs of whichever of the speaker and microphone are muted.
+    pub fn update(&self, speaker_muted: bool, mic_muted: bool) -> Result<(), Error> {
+        if let Some(led) = &self.mute {
+            led.set(speaker_muted)?;
+        }
+        if let Some(led) = &self.micmute {
+            led.set(mic_muted)?;
+        }
+        Ok(())
+    }
+
+    /// Follow the mute state of the default sink and source until the connection goes away.
+    #[cfg(feature = "pulseaudio")]
+    pub fn sync(&self, device: &PulseAudioSoundDevice) -> Result<(), Error> {
+        let events = device.subscribe();
+        let mut last = None;
+        loop {
+            let source = device.default_source();
+            let mic_muted = device
+                .sources()?
+                .into_iter()
+                .find(|s| s.name == source)
+                .map_or(false, |s| s.muted);
+            let state = (device.muted(), mic_muted);
+            if last != Some(state) {
+                self.update(state.0, state.1)?;
+                last = Some(state);
+            }
+
+            // Wait for a change that may affect either LED.
+            loop {
+                match events.recv() {
+                    Err(_) => {
+                        return Err(Error::new(ErrorKind::Other, "sound server went away"));
+                    }
+                    Ok(AudioEvent::MuteChanged { .. })
+                    | Ok(AudioEvent::DefaultSinkChanged { .. })
+                    | Ok(AudioEvent::SourceMuteChanged { .. })
+                    | Ok(AudioEvent::DefaultSourceChanged { .. })
+                    | Ok(AudioEvent::ServerRestarted) => break,
+                    Ok(_) => {}
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    // A fake sysfs tree with the given LEDs, all off.
+    fn sysfs(name: &str, leds: &[&str]) -> PathBuf {
+        let root = env::temp_dir().join(format!("platformctl_{}_{}", name, ::std::process::id()));
+        for led in leds {
+            let dir = root.join("class").join("leds").join(led);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("brightness"), "0\n").unwrap();
+            fs::write(dir.join("max_brightness"), "1\n").unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn detects_leds() {
+        let root = sysfs(
+            "leds_detect",
+            &["input3::capslock", "hda::mute", "platform::mute", "hda::micmute"],
+        );
+        let leds = MuteLeds::detect_in(&root).unwrap();
+        let led_path = |name: &str| root.join("class").join("leds").join(name);
+        assert_eq!(leds.mute.as_ref().unwrap().path(), led_path("platform::mute"));
+        assert_eq!(leds.micmute.as_ref().unwrap().path(), led_path("hda::micmute"));
+
+        leds.update(false, true).unwrap();
+        assert!(!leds.mute.as_ref().unwrap().is_on().unwrap());
+        assert!(leds.micmute.as_ref().unwrap().is_on().unwrap());
+        assert_eq!(fs::read_to_string(led_path("hda::micmute").join("brightness")).unwrap(), "1");
+        leds.update(true, false).unwrap();
+        assert!(leds.mute.as_ref().unwrap().is_on().unwrap());
+        assert!(!leds.micmute.as_ref().unwrap().is_on().unwrap());
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn missing_leds() {
+        let root = sysfs("leds_missing", &["input3::capslock"]);
+        assert!(MuteLeds::detect_in(&root).unwrap().is_empty());
+        assert!(MuteLeds::detect_in(&root.join("nonexistent")).unwrap().is_empty());
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,614 @@
+//! In-memory audio backend, for testing code built on `SoundDevice` without a sound server.
+//!
+//! A `MockServer` holds sinks, sources and streams, and hands out devices controlling its sinks.
+//! Tests keep the server to script events such as unplugging a sink, and to assert on the
+//! operations the devices received.
+
+use std::io::{Error, ErrorKind};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Mutex};
+
+use super::{
+    broadcast, check_absolute, AudioEvent, Backend, Listener, SinkSelector, SoundDevice,
+    VolumePolicy, VolumeScale,
+};
+
+/// A sink or source held by a `MockServer`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockNode {
+    pub index: u32,
+    pub name: String,
+    pub description: String,
+    /// Per-channel volumes, as fractions of the nominal volume.
+    pub volumes: Vec<f32>,
+    pub muted: bool,
+}
+
+/// A playback stream held by a `MockServer`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MockStream {
+    pub index: u32,
+    pub name: String,
+    /// The name of the sink the stream plays to.
+    pub sink: String,
+    pub volume: f32,
+    pub muted: bool,
+}
+
+/// An operation received by a mock device, with values as passed by the caller.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MockOperation {
+    AddVolume {
+        sink: String,
+        step: f32,
+        cap: Option<f32>,
+        scale: VolumeScale,
+    },
+    SetVolume {
+        sink: String,
+        value: f32,
+        cap: Option<f32>,
+        scale: VolumeScale,
+    },
+    SetMuted {
+        sink: String,
+        muted: bool,
+    },
+}
+
+struct MockState {
+    next_index: u32,
+    default_sink: Option<String>,
+    sinks: Vec<MockNode>,
+    sources: Vec<MockNode>,
+    streams: Vec<MockStream>,
+    operations: Vec<MockOperation>,
+    failing: bool,
+}
+
+/// An in-memory sound server. Clones share the same state.
+#[derive(Clone)]
+pub struct MockServer {
+    state: Arc<Mutex<MockState>>,
+    listeners: Arc<Mutex<Vec<Listener>>>,
+}
+
+pub struct MockSoundDevice {
+    server: MockServer,
+    selector: SinkSelector,
+    scale: VolumeScale,
+    policy: VolumePolicy,
+}
+
+fn node(index: u32, name: &str, description: &str, channels: usize) -> MockNode {
+    MockNode {
+        index,
+        name: name.to_string(),
+        description: description.to_string(),
+        volumes: vec![1.0; channels],
+        muted: false,
+    }
+}
+
+impl MockServer {
+    /// Create a server without any sinks, sources or streams.
+    pub fn new() -> Self {
+        MockServer {
+            state: Arc::new(Mutex::new(MockState {
+                next_index: 0,
+                default_sink: None,
+                sinks: Vec::new(),
+                sources: Vec::new(),
+                streams: Vec::new(),
+                operations: Vec::new(),
+                failing: false,
+            })),
+            listeners: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Add a sink at the nominal volume, returning its index. The first sink added becomes the
+    /// default one.
+    pub fn add_sink(&self, name: &str, description: &str, channels: usize) -> u32 {
+        let index = {
+            let mut state = self.state.lock().unwrap();
+            let index = state.next_index;
+            state.next_index += 1;
+            state.sinks.push(node(index, name, description, channels));
+            if state.default_sink.is_none() {
+                state.default_sink = Some(name.to_string());
+            }
+            index
+        };
+        broadcast(
+            &self.listeners,
+            AudioEvent::SinkAdded {
+                index,
+                sink: name.to_string(),
+            },
+        );
+        index
+    }
+
+    /// Add a source at the nominal volume, returning its index.
+    pub fn add_source(&self, name: &str, description: &str, channels: usize) -> u32 {
+        let mut state = self.state.lock().unwrap();
+        let index = state.next_index;
+        state.next_index += 1;
+        state.sources.push(node(index, name, description, channels));
+        index
+    }
+
+    /// Add a stream playing to the named sink, returning its index. Unlike sinks, streams come
+    /// and go silently, as no backend reports them through events.
+    pub fn add_stream(&self, name: &str, sink: &str) -> u32 {
+        let mut state = self.state.lock().unwrap();
+        let index = state.next_index;
+        state.next_index += 1;
+        state.streams.push(MockStream {
+            index,
+            name: name.to_string(),
+            sink: sink.to_string(),
+            volume: 1.0,
+            muted: false,
+        });
+        index
+    }
+
+    /// Remove a stream, silently like `add_stream`.
+    pub fn remove_stream(&self, index: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.streams.retain(|stream| stream.index != index);
+    }
+
+    pub fn sinks(&self) -> Vec<MockNode> {
+        self.state.lock().unwrap().sinks.clone()
+    }
+
+    pub fn sources(&self) -> Vec<MockNode> {
+        self.state.lock().unwrap().sources.clone()
+    }
+
+    pub fn streams(&self) -> Vec<MockStream> {
+        self.state.lock().unwrap().streams.clone()
+    }
+
+    pub fn sink(&self, name: &str) -> Option<MockNode> {
+        let state = self.state.lock().unwrap();
+        state.sinks.iter().find(|sink| sink.name == name).cloned()
+    }
+
+    pub fn default_sink(&self) -> Option<String> {
+        self.state.lock().unwrap().default_sink.clone()
+    }
+
+    /// Make the named sink the default one, as if changed by another client. Like the sound
+    /// servers, this only reports an actual change.
+    pub fn set_default_sink(&self, name: &str) -> Result<(), Error> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if !state.sinks.iter().any(|sink| sink.name == name) {
+                return Err(Error::new(ErrorKind::NotFound, "no such sink"));
+            }
+            if state.default_sink.as_deref() == Some(name) {
+                return Ok(());
+            }
+            state.default_sink = Some(name.to_string());
+        }
+        broadcast(
+            &self.listeners,
+            AudioEvent::DefaultSinkChanged {
+                sink: name.to_string(),
+            },
+        );
+        Ok(())
+    }
+
+    /// Change the channel volumes of a sink, as if changed by another client.
+    pub fn set_sink_volumes(&self, name: &str, volumes: &[f32]) -> Result<(), Error> {
+        let volume = {
+            let mut state = self.state.lock().unwrap();
+            let sink = match state.sinks.iter_mut().find(|sink| sink.name == name) {
+                Some(sink) => sink,
+                None => return Err(Error::new(ErrorKind::NotFound, "no such sink")),
+            };
+            sink.volumes = volumes.to_vec();
+            average(&sink.volumes)
+        };
+        broadcast(
+            &self.listeners,
+            AudioEvent::VolumeChanged {
+                sink: name.to_string(),
+                volume,
+            },
+        );
+        Ok(())
+    }
+
+    /// Remove a sink, as if it was unplugged. Its streams move to the new default sink, which is
+    /// the first remaining one if the removed sink was the default.
+    pub fn unplug(&self, name: &str) -> Result<(), Error> {
+        let (index, default) = {
+            let mut state = self.state.lock().unwrap();
+            let pos = match state.sinks.iter().position(|sink| sink.name == name) {
+                Some(pos) => pos,
+                None => return Err(Error::new(ErrorKind::NotFound, "no such sink")),
+            };
+            let index = state.sinks.remove(pos).index;
+
+            let mut default = None;
+            if state.default_sink.as_deref() == Some(name) {
+                state.default_sink = state.sinks.first().map(|sink| sink.name.clone());
+                default = state.default_sink.clone();
+            }
+            if let Some(target) = state.default_sink.clone() {
+                for stream in state.streams.iter_mut().filter(|s| s.sink == name) {
+                    stream.sink = target.clone();
+                }
+            }
+            (index, default)
+        };
+
+        broadcast(
+            &self.listeners,
+            AudioEvent::SinkRemoved {
+                index,
+                sink: name.to_string(),
+            },
+        );
+        if let Some(sink) = default {
+            broadcast(&self.listeners, AudioEvent::DefaultSinkChanged { sink });
+        }
+        Ok(())
+    }
+
+    /// Report a server restart to every subscriber.
+    pub fn restart(&self) {
+        broadcast(&self.listeners, AudioEvent::ServerRestarted);
+    }
+
+    /// Make every following change fail, as if rejected by the server.
+    pub fn set_failing(&self, failing: bool) {
+        self.state.lock().unwrap().failing = failing;
+    }
+
+    /// The operations received by devices of this server, oldest first.
+    pub fn operations(&self) -> Vec<MockOperation> {
+        self.state.lock().unwrap().operations.clone()
+    }
+
+    pub fn clear_operations(&self) {
+        self.state.lock().unwrap().operations.clear();
+    }
+
+    /// Create a device controlling a sink of this server.
+    pub fn device(&self, sink: SinkSelector) -> Result<MockSoundDevice, Error> {
+        let mut device = MockSoundDevice {
+            server: self.clone(),
+            selector: SinkSelector::FollowDefault,
+            scale: VolumeScale::default(),
+            policy: VolumePolicy::default(),
+        };
+        device.set_sink(sink)?;
+        Ok(device)
+    }
+}
+
+impl Default for MockServer {
+    fn default() -> Self {
+        MockServer::new()
+    }
+}
+
+fn average(volumes: &[f32]) -> f32 {
+    if volumes.is_empty() {
+        return 0.0;
+    }
+    volumes.iter().sum::<f32>() / volumes.len() as f32
+}
+
+impl MockSoundDevice {
+    /// Change which sink this device controls.
+    pub fn set_sink(&mut self, sink: SinkSelector) -> Result<(), Error> {
+        if let SinkSelector::Fixed(name) = &sink {
+            if self.server.sink(name).is_none() {
+                return Err(Error::new(ErrorKind::NotFound, "no such sink"));
+            }
+        }
+        self.selector = sink;
+        Ok(())
+    }
+
+    /// The name of the sink currently being controlled.
+    pub fn sink_name(&self) -> String {
+        let state = self.server.state.lock().unwrap();
+        self.target(&state).unwrap_or_default()
+    }
+
+    // The sink being controlled. A fixed sink that went away falls back to the default one.
+    fn target(&self, state: &MockState) -> Option<String> {
+        if let SinkSelector::Fixed(name) = &self.selector {
+            if state.sinks.iter().any(|sink| &sink.name == name) {
+                return Some(name.clone());
+            }
+        }
+        state.default_sink.clone()
+    }
+
+    fn current(&self) -> Option<MockNode> {
+        let state = self.server.state.lock().unwrap();
+        let name = self.target(&state)?;
+        state.sinks.iter().find(|sink| sink.name == name).cloned()
+    }
+
+    // Record an operation and apply it to the controlled sink within the volume policy, then
+    // report the change.
+    fn apply<F>(&mut self, operation: F) -> Result<(), Error>
+    where
+        F: FnOnce(&str) -> MockOperation,
+    {
+        let mut events = Vec::new();
+        {
+            let mut state = self.server.state.lock().unwrap();
+            let name = match self.target(&state) {
+                Some(name) => name,
+                None => return Err(Error::new(ErrorKind::NotFound, "no sink to control")),
+            };
+            let operation = operation(&name);
+            state.operations.push(operation.clone());
+            if state.failing {
+                return Err(Error::new(ErrorKind::Other, "mock request failed"));
+            }
+
+            let sink = state
+                .sinks
+                .iter_mut()
+                .find(|sink| sink.name == name)
+                .unwrap();
+            match operation {
+                MockOperation::AddVolume {
+                    step, cap, scale, ..
+                } => {
+                    let loudest = sink.volumes.iter().cloned().fold(0.0, f32::max);
+                    let mut target = scale.step(loudest, step);
+                    if step > 0.0 {
+                        if let Some(cap) = cap {
+                            target = target.min(scale.to_fraction(cap));
+                        }
+                    }
+                    let (target, boosted) = self.policy.apply(target, loudest, None);
+                    if boosted {
+                        events.push(AudioEvent::BoostEngaged {
+                            sink: name.clone(),
+                            volume: target,
+                        });
+                    }
+                    scale_to(&mut sink.volumes, target);
+                }
+                MockOperation::SetVolume {
+                    value, cap, scale, ..
+                } => {
+                    let loudest = sink.volumes.iter().cloned().fold(0.0, f32::max);
+                    let mut target = scale.to_fraction(value);
+                    if let Some(cap) = cap {
+                        target = target.min(scale.to_fraction(cap));
+                    }
+                    let (target, boosted) = self.policy.apply(target, loudest, None);
+                    if boosted {
+                        events.push(AudioEvent::BoostEngaged {
+                            sink: name.clone(),
+                            volume: target,
+                        });
+                    }
+                    scale_to(&mut sink.volumes, target);
+                }
+                MockOperation::SetMuted { muted, .. } => sink.muted = muted,
+            }
+            events.push(match operation {
+                MockOperation::SetMuted { muted, .. } => {
+                    AudioEvent::MuteChanged { sink: name, muted }
+                }
+                _ => AudioEvent::VolumeChanged {
+                    sink: name,
+                    volume: average(&sink.volumes),
+                },
+            });
+        }
+        for event in events {
+            broadcast(&self.server.listeners, event);
+        }
+        Ok(())
+    }
+}
+
+// Scale all channels so that the loudest one ends up at the target.
+fn scale_to(volumes: &mut [f32], target: f32) {
+    let loudest = volumes.iter().cloned().fold(0.0, f32::max);
+    for volume in volumes.iter_mut() {
+        *volume = if loudest > 0.0 {
+            *volume * target / loudest
+        } else {
+            target
+        };
+    }
+}
+
+impl SoundDevice for MockSoundDevice {
+    fn backend(&self) -> Backend {
+        Backend::Mock
+    }
+
+    fn volume_scale(&self) -> VolumeScale {
+        self.scale
+    }
+
+    fn set_volume_scale(&mut self, scale: VolumeScale) {
+        self.scale = scale;
+    }
+
+    fn volume_policy(&self) -> VolumePolicy {
+        self.policy
+    }
+
+    fn set_volume_policy(&mut self, policy: VolumePolicy) {
+        self.policy = policy;
+    }
+
+    fn volume(&self) -> f32 {
+        let volume = self.current().map_or(0.0, |sink| average(&sink.volumes));
+        self.scale.from_fraction(volume)
+    }
+
+    fn muted(&self) -> bool {
+        self.current().map_or(false, |sink| sink.muted)
+    }
+
+    fn channel_names(&self) -> Vec<String> {
+        let channels = self.current().map_or(0, |sink| sink.volumes.len());
+        (0..channels).map(|channel| channel.to_string()).collect()
+    }
+
+    fn channel_volumes(&self) -> Vec<f32> {
+        self.current().map_or_else(Vec::new, |sink| sink.volumes)
+    }
+
+    fn add_volume(&mut self, step: f32, cap: Option<f32>) -> Result<(), Error> {
+        let scale = self.scale;
+        self.apply(|sink| MockOperation::AddVolume {
+            sink: sink.to_string(),
+            step,
+            cap,
+            scale,
+        })
+    }
+
+    fn set_volume(&mut self, val: f32, cap: Option<f32>) -> Result<(), Error> {
+        check_absolute(val, self.scale)?;
+        let scale = self.scale;
+        self.apply(|sink| MockOperation::SetVolume {
+            sink: sink.to_string(),
+            value: val,
+            cap,
+            scale,
+        })
+    }
+
+    fn set_muted(&mut self, muted: bool) -> Result<(), Error> {
+        self.apply(|sink| MockOperation::SetMuted {
+            sink: sink.to_string(),
+            muted,
+        })
+    }
+
+    fn subscribe(&self) -> Receiver<AudioEvent> {
+        let (tx, rx) = channel();
+        self.server
+            .listeners
+            .lock()
+            .unwrap()
+            .push(Box::new(move |event| tx.send(event.clone()).is_ok()));
+        rx
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_operations() {
+        let server = MockServer::new();
+        server.add_sink("speakers", "Speakers", 2);
+        let mut dev = server.device(SinkSelector::FollowDefault).unwrap();
+        let events = dev.subscribe();
+
+        dev.set_volume(0.5, None).unwrap();
+        dev.add_volume(0.1, Some(0.55)).unwrap();
+        dev.set_muted(true).unwrap();
+        assert!((dev.volume() - 0.55).abs() < 1e-5);
+        assert!(dev.muted());
+
+        assert_eq!(
+            server.operations()[2],
+            MockOperation::SetMuted {
+                sink: "speakers".to_string(),
+                muted: true,
+            }
+        );
+        assert_eq!(events.try_iter().count(), 3);
+    }
+
+    #[test]
+    fn policy_limits_volume() {
+        let server = MockServer::new();
+        server.add_sink("speakers", "Speakers", 2);
+        let mut dev = server.device(SinkSelector::FollowDefault).unwrap();
+        let events = dev.subscribe();
+
+        dev.set_volume(1.5, None).unwrap();
+        assert!((dev.volume() - 1.0).abs() < 1e-5);
+        dev.set_volume_policy(VolumePolicy {
+            boost: Some(1.2),
+            ..VolumePolicy::default()
+        });
+        dev.add_volume(0.5, None).unwrap();
+        assert!((dev.volume() - 1.2).abs() < 1e-5);
+
+        let boosts: Vec<AudioEvent> = events
+            .try_iter()
+            .filter(|event| matches!(event, AudioEvent::BoostEngaged { .. }))
+            .collect();
+        assert_eq!(
+            boosts,
+            vec![AudioEvent::BoostEngaged {
+                sink: "speakers".to_string(),
+                volume: 1.2,
+            }]
+        );
+    }
+
+    #[test]
+    fn unplug_moves_to_default() {
+        let server = MockServer::new();
+        server.add_sink("speakers", "Speakers", 2);
+        server.add_sink("headset", "USB Headset", 2);
+        let stream = server.add_stream("music", "headset");
+        let dev = server
+            .device(SinkSelector::Fixed("headset".to_string()))
+            .unwrap();
+        let events = dev.subscribe();
+
+        server.unplug("headset").unwrap();
+        assert_eq!(dev.sink_name(), "speakers");
+        assert_eq!(server.streams()[0].index, stream);
+        assert_eq!(server.streams()[0].sink, "speakers");
+
+        // Already the default, so there is nothing to report.
+        server.set_default_sink("speakers").unwrap();
+        server.restart();
+        let events: Vec<AudioEvent> = events.try_iter().collect();
+        assert_eq!(
+            events,
+            vec![
+                AudioEvent::SinkRemoved {
+                    index: 1,
+                    sink: "headset".to_string(),
+                },
+                AudioEvent::ServerRestarted,
+            ]
+        );
+    }
+
+    #[test]
+    fn failing_server_rejects_changes() {
+        let server = MockServer::new();
+        server.add_sink("speakers", "Speakers", 1);
+        let mut dev = server.device(SinkSelector::FollowDefault).unwrap();
+
+        server.set_failing(true);
+        assert!(dev.set_muted(true).is_err());
+        assert!(!dev.muted());
+        assert_eq!(server.operations().len(), 1);
+        assert!(dev.set_volume(-1.0, None).is_err());
+    }
+}
@@ -1 +1,649 @@
+#[cfg(feature = "alsa")]
+pub mod alsa;
+pub mod led;
+#[cfg(feature = "testing")]
+pub mod mock;
+#[cfg(feature = "pipewire")]
+pub mod pipewire;
+#[cfg(feature = "pulseaudio")]
 pub mod pulseaudio;
+pub mod push_to_talk;
+pub mod wav;
+
+use std::env;
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+use std::sync::mpsc::Receiver;
+#[cfg(any(feature = "pulseaudio", feature = "pipewire", feature = "alsa", feature = "testing"))]
+use std::sync::Mutex;
+
+/// The scale volumes are read and stepped in.
+///
+/// All scales are anchored at the nominal volume (100%, 0dB), and convert using the same cubic
+/// mapping PulseAudio uses in `pa_sw_volume_to_linear` and `pa_sw_volume_to_dB`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VolumeScale {
+    /// Fraction of the nominal volume, as presented by pactl and pavucontrol.
+    Linear,
+    /// Amplitude factor, the cube of the nominal fraction.
+    Cubic,
+    /// Decibels relative to the nominal volume.
+    Decibel,
+}
+
+impl VolumeScale {
+    /// Convert a fraction of the nominal volume to a value in this scale.
+    pub fn from_fraction(self, fraction: f32) -> f32 {
+        let fraction = fraction.max(0.0);
+        match self {
+            VolumeScale::Linear => fraction,
+            VolumeScale::Cubic => fraction.powi(3),
+            VolumeScale::Decibel => 60.0 * fraction.log10(),
+        }
+    }
+
+    /// Convert a value in this scale to a fraction of the nominal volume.
+    pub fn to_fraction(self, value: f32) -> f32 {
+        match self {
+            VolumeScale::Linear => value.max(0.0),
+            VolumeScale::Cubic => value.max(0.0).cbrt(),
+            VolumeScale::Decibel => 10f32.powf(value / 60.0),
+        }
+    }
+
+    /// Step a fraction of the nominal volume by an amount in this scale, returning the resulting
+    /// fraction.
+    ///
+    /// Silence is infinitely quiet in decibels, so steps up start from `DECIBEL_FLOOR` instead.
+    pub fn step(self, fraction: f32, step: f32) -> f32 {
+        let mut current = self.from_fraction(fraction);
+        if self == VolumeScale::Decibel && step > 0.0 {
+            current = current.max(DECIBEL_FLOOR);
+        }
+        self.to_fraction(current + step)
+    }
+}
+
+/// The volume steps up in decibels start from when quieter, a thousandth of the nominal amplitude.
+pub const DECIBEL_FLOOR: f32 = -60.0;
+
+impl Default for VolumeScale {
+    fn default() -> Self {
+        VolumeScale::Linear
+    }
+}
+
+impl FromStr for VolumeScale {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "linear" => Ok(VolumeScale::Linear),
+            "cubic" => Ok(VolumeScale::Cubic),
+            "db" | "dB" | "decibel" => Ok(VolumeScale::Decibel),
+            _ => Err(Error::new(ErrorKind::InvalidInput, "unknown volume scale")),
+        }
+    }
+}
+
+/// Parse a volume value such as `0.4`, `40%` or `-3dB`.
+///
+/// Values with a `%` suffix are linear percentages and values with a `dB` suffix are decibels.
+/// Bare numbers are interpreted in the provided default scale.
+pub fn parse_volume(s: &str, default: VolumeScale) -> Result<(f32, VolumeScale), Error> {
+    let s = s.trim();
+    let (num, scale, divisor) = if let Some(num) = s.strip_suffix('%') {
+        (num, VolumeScale::Linear, 100.0)
+    } else if s.len() > 2 && s.get(s.len() - 2..).map_or(false, |u| u.eq_ignore_ascii_case("db")) {
+        (&s[..s.len() - 2], VolumeScale::Decibel, 1.0)
+    } else {
+        (s, default, 1.0)
+    };
+
+    let value: f32 = num
+        .trim()
+        .parse()
+        .map_err(|_e| Error::new(ErrorKind::InvalidInput, "unable to parse volume"))?;
+
+    if value.is_nan() {
+        return Err(Error::new(ErrorKind::InvalidInput, "volume must be a number"));
+    }
+
+    Ok((value / divisor, scale))
+}
+
+/// Which sink a sound device controls.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SinkSelector {
+    /// Follow the default sink, re-targeting whenever it changes.
+    FollowDefault,
+    /// Control the named sink. While it is missing, the default sink is used instead.
+    Fixed(String),
+}
+
+impl Default for SinkSelector {
+    fn default() -> Self {
+        SinkSelector::FollowDefault
+    }
+}
+
+/// Match text against a shell-style glob, supporting `*` and `?`.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    // Greedy matching with backtracking to the last star.
+    let (mut p, mut t) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((sp, st)) = star {
+            p = sp + 1;
+            t = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// A change in audio state.
+///
+/// Volumes are reported as fractions of the nominal volume, regardless of the scale used by the
+/// device they were received from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AudioEvent {
+    VolumeChanged { sink: String, volume: f32 },
+    MuteChanged { sink: String, muted: bool },
+    DefaultSinkChanged { sink: String },
+    SinkAdded { index: u32, sink: String },
+    SinkRemoved { index: u32, sink: String },
+    /// The connection to the sound server was lost and has been re-established.
+    ServerRestarted,
+    /// A volume change went past the hard maximum of the volume policy.
+    BoostEngaged { sink: String, volume: f32 },
+    /// The server rejected a change made by a non-blocking device.
+    OperationFailed { sink: String, operation: SinkOperation },
+    /// A sink was suspended, closing its device, or resumed.
+    SinkSuspended { sink: String, suspended: bool },
+    /// A source was suspended, closing its device, or resumed.
+    SourceSuspended { source: String, suspended: bool },
+    SourceMuteChanged { source: String, muted: bool },
+    DefaultSourceChanged { source: String },
+    /// The battery level of a Bluetooth sink or card changed, in percent.
+    BatteryChanged { device: String, battery: u8 },
+    /// A Bluetooth sink or card switched codecs.
+    CodecChanged { device: String, codec: String },
+}
+
+/// A change made to a sink by a device.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SinkOperation {
+    /// Any change to the volume of the sink, including its balance and fade.
+    SetVolume,
+    SetMuted,
+}
+
+/// Bluetooth details of a device, as exposed through its properties by PulseAudio and PipeWire.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BluetoothInfo {
+    /// Battery level in percent, if the headset reports it.
+    pub battery: Option<u8>,
+    /// The codec in use, such as `sbc`, `aac` or `ldac`.
+    pub codec: Option<String>,
+}
+
+impl BluetoothInfo {
+    const BATTERY_KEYS: &'static [&'static str] =
+        &["bluetooth.battery", "api.bluez5.battery", "bluez5.battery"];
+    const CODEC_KEYS: &'static [&'static str] =
+        &["bluetooth.codec", "api.bluez5.codec", "bluetooth.a2dp_codec"];
+
+    /// Read the Bluetooth details from device properties, or `None` for non-Bluetooth devices.
+    pub fn from_properties<F>(get: F) -> Option<Self>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let first = |keys: &[&str]| keys.iter().filter_map(|key| get(key)).next();
+        // Levels are reported as e.g. "80%", or as a bare number.
+        let battery = first(Self::BATTERY_KEYS)
+            .and_then(|v| v.trim().trim_end_matches('%').trim().parse::<u8>().ok())
+            .filter(|v| *v <= 100);
+        let codec = first(Self::CODEC_KEYS).filter(|v| !v.is_empty());
+        let bluetooth = get("device.bus").map_or(false, |bus| bus == "bluetooth")
+            || get("device.api").map_or(false, |api| api == "bluez5");
+        if bluetooth || battery.is_some() || codec.is_some() {
+            Some(BluetoothInfo { battery, codec })
+        } else {
+            None
+        }
+    }
+
+    // Fill in what this device does not report from another, such as its card.
+    #[cfg(feature = "pulseaudio")]
+    pub(crate) fn or(self, other: Option<&BluetoothInfo>) -> Self {
+        match other {
+            Some(other) => BluetoothInfo {
+                battery: self.battery.or(other.battery),
+                codec: self.codec.or_else(|| other.codec.clone()),
+            },
+            None => self,
+        }
+    }
+
+    // The events describing changes from `old`, for the named device.
+    #[cfg(any(feature = "pulseaudio", feature = "pipewire"))]
+    pub(crate) fn changes(&self, old: &BluetoothInfo, device: &str) -> Vec<AudioEvent> {
+        let mut events = Vec::new();
+        if let Some(battery) = self.battery.filter(|b| old.battery != Some(*b)) {
+            events.push(AudioEvent::BatteryChanged {
+                device: device.to_string(),
+                battery,
+            });
+        }
+        if let Some(codec) = self.codec.as_ref().filter(|c| old.codec.as_ref() != Some(*c)) {
+            events.push(AudioEvent::CodecChanged {
+                device: device.to_string(),
+                codec: codec.clone(),
+            });
+        }
+        events
+    }
+}
+
+// A subscriber to audio events, returning false once it has hung up.
+#[cfg(any(feature = "pulseaudio", feature = "pipewire", feature = "alsa", feature = "testing"))]
+pub(crate) type Listener = Box<dyn Fn(&AudioEvent) -> bool + Send>;
+
+#[cfg(any(feature = "pulseaudio", feature = "pipewire", feature = "alsa", feature = "testing"))]
+pub(crate) fn broadcast(listeners: &Mutex<Vec<Listener>>, event: AudioEvent) {
+    listeners.lock().unwrap().retain(|listener| listener(&event));
+}
+
+/// Operations shared by every audio backend.
+///
+/// Volumes are read and changed in the device's volume scale, and events report fractions of
+/// the nominal volume.
+pub trait SoundDevice {
+    /// The backend this device belongs to.
+    fn backend(&self) -> Backend;
+    fn volume_scale(&self) -> VolumeScale;
+    fn set_volume_scale(&mut self, scale: VolumeScale);
+    /// The limits `add_volume` and `set_volume` are clamped to. Changes boosted past the hard
+    /// maximum are reported with `AudioEvent::BoostEngaged`.
+    fn volume_policy(&self) -> VolumePolicy;
+    fn set_volume_policy(&mut self, policy: VolumePolicy);
+    fn volume(&self) -> f32;
+    fn muted(&self) -> bool;
+    fn channel_names(&self) -> Vec<String>;
+    fn channel_volumes(&self) -> Vec<f32>;
+    fn add_volume(&mut self, step: f32, cap: Option<f32>) -> Result<(), Error>;
+    /// Set the volume of the loudest channel, keeping the ratio between channels.
+    fn set_volume(&mut self, val: f32, cap: Option<f32>) -> Result<(), Error>;
+    fn set_muted(&mut self, muted: bool) -> Result<(), Error>;
+    /// Subscribe to audio events.
+    fn subscribe(&self) -> Receiver<AudioEvent>;
+}
+
+/// An audio backend, which may or may not be enabled in this build.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    PulseAudio,
+    PipeWire,
+    Alsa,
+    /// The in-memory backend of the `testing` feature.
+    #[cfg(feature = "testing")]
+    Mock,
+}
+
+/// The order backends are probed in by `open_default`, unless overridden.
+pub const DEFAULT_BACKENDS: &[Backend] = &[Backend::PulseAudio, Backend::PipeWire, Backend::Alsa];
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Backend::PulseAudio => "pulseaudio",
+            Backend::PipeWire => "pipewire",
+            Backend::Alsa => "alsa",
+            #[cfg(feature = "testing")]
+            Backend::Mock => "mock",
+        })
+    }
+}
+
+impl FromStr for Backend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "pulseaudio" | "pulse" => Ok(Backend::PulseAudio),
+            "pipewire" => Ok(Backend::PipeWire),
+            "alsa" => Ok(Backend::Alsa),
+            #[cfg(feature = "testing")]
+            "mock" => Ok(Backend::Mock),
+            _ => Err(Error::new(ErrorKind::InvalidInput, "unknown audio backend")),
+        }
+    }
+}
+
+/// Parse a comma-separated list of backends, such as `pipewire,alsa`.
+pub fn parse_backends(s: &str) -> Result<Vec<Backend>, Error> {
+    s.split(',').map(|backend| backend.parse()).collect()
+}
+
+/// The backends `open_default` probes, in order. The `PLATFORMCTL_AUDIO_BACKENDS` environment
+/// variable overrides `DEFAULT_BACKENDS`.
+pub fn default_backends() -> Result<Vec<Backend>, Error> {
+    match env::var("PLATFORMCTL_AUDIO_BACKENDS") {
+        Ok(v) => parse_backends(&v),
+        Err(_) => Ok(DEFAULT_BACKENDS.to_vec()),
+    }
+}
+
+/// Open a device on the first of the default backends that is available.
+pub fn open_default() -> Result<Box<dyn SoundDevice>, Error> {
+    open(&default_backends()?)
+}
+
+/// Open a device on the first of the given backends that is available, controlling its default
+/// sink. The error from the last backend tried is returned if none are.
+pub fn open(backends: &[Backend]) -> Result<Box<dyn SoundDevice>, Error> {
+    let mut err = Error::new(ErrorKind::NotFound, "no audio backend to try");
+    for backend in backends {
+        match open_backend(*backend) {
+            Ok(device) => return Ok(device),
+            Err(e) => err = e,
+        }
+    }
+    Err(err)
+}
+
+fn open_backend(backend: Backend) -> Result<Box<dyn SoundDevice>, Error> {
+    match backend {
+        #[cfg(feature = "pulseaudio")]
+        Backend::PulseAudio => Ok(Box::new(pulseaudio::PulseAudioSoundDevice::new(
+            SinkSelector::FollowDefault,
+            true,
+        )?)),
+        #[cfg(feature = "pipewire")]
+        Backend::PipeWire => Ok(Box::new(pipewire::PipeWireSoundDevice::new(
+            SinkSelector::FollowDefault,
+        )?)),
+        #[cfg(feature = "alsa")]
+        Backend::Alsa => Ok(Box::new(alsa::AlsaSoundDevice::new("default", None)?)),
+        // There is no server to connect to, so mock devices come from a `MockServer`.
+        #[cfg(feature = "testing")]
+        Backend::Mock => Err(Error::new(
+            ErrorKind::InvalidInput,
+            "mock devices are created through a MockServer",
+        )),
+        #[allow(unreachable_patterns)]
+        _ => Err(Error::new(
+            ErrorKind::NotFound,
+            format!("the {} backend is not enabled in this build", backend),
+        )),
+    }
+}
+
+/// Limits applied to every volume change, to avoid over-amplifying sinks.
+///
+/// Volumes are clamped to a hard maximum, which defaults to 100%, or to the base volume reported
+/// by the sink (the volume at which it is unamplified) if that is higher. Boosting past the hard
+/// maximum must be explicitly allowed, and is then limited to the boost value. A change is never
+/// clamped below the current volume, so lowering a sink that is over the limit still works.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct VolumePolicy {
+    /// Hard maximum, as a fraction of the nominal volume.
+    pub max: Option<f32>,
+    /// Allow boosting past the hard maximum up to this fraction of the nominal volume.
+    pub boost: Option<f32>,
+}
+
+impl VolumePolicy {
+    /// The hard maximum for a sink with the given base volume.
+    pub fn hard_max(&self, base: Option<f32>) -> f32 {
+        self.max.unwrap_or_else(|| base.map_or(1.0, |base| base.max(1.0)))
+    }
+
+    /// The highest volume allowed for a sink with the given base volume.
+    pub fn limit(&self, base: Option<f32>) -> f32 {
+        let hard = self.hard_max(base);
+        match self.boost {
+            Some(boost) => boost.max(hard),
+            None => hard,
+        }
+    }
+
+    /// Clamp a target volume for a sink at the `current` volume with the given base volume, also
+    /// returning whether the change raises it past the hard maximum.
+    pub fn apply(&self, target: f32, current: f32, base: Option<f32>) -> (f32, bool) {
+        let target = target.min(self.limit(base).max(current));
+        (target, target > current && target > self.hard_max(base))
+    }
+}
+
+/// A requested volume change, as accepted by the command line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VolumeChange {
+    /// Step the current volume by the given amount.
+    Step(f32, VolumeScale),
+    /// Set the volume to the given absolute value.
+    Set(f32, VolumeScale),
+}
+
+impl VolumeChange {
+    /// Parse a volume change. Values prefixed with `=` are absolute, everything else is a step.
+    pub fn parse(s: &str, default: VolumeScale) -> Result<Self, Error> {
+        match s.trim().strip_prefix('=') {
+            Some(v) => {
+                let (value, scale) = parse_volume(v, default)?;
+                check_absolute(value, scale)?;
+                Ok(VolumeChange::Set(value, scale))
+            }
+            None => {
+                let (value, scale) = parse_volume(s, default)?;
+                Ok(VolumeChange::Step(value, scale))
+            }
+        }
+    }
+}
+
+/// Check that a value is usable as an absolute volume in the given scale.
+///
+/// Only decibels can be negative, as they are relative to the nominal volume.
+pub fn check_absolute(value: f32, scale: VolumeScale) -> Result<(), Error> {
+    if value.is_nan() {
+        return Err(Error::new(ErrorKind::InvalidInput, "volume must be a number"));
+    }
+    if value < 0.0 && scale != VolumeScale::Decibel {
+        return Err(Error::new(ErrorKind::InvalidInput, "volume must not be negative"));
+    }
+    Ok(())
+}
+
+impl FromStr for VolumeChange {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        VolumeChange::parse(s, VolumeScale::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scale_roundtrip() {
+        for scale in &[VolumeScale::Linear, VolumeScale::Cubic, VolumeScale::Decibel] {
+            let v = scale.from_fraction(0.5);
+            assert!((scale.to_fraction(v) - 0.5).abs() < 1e-5);
+        }
+        assert_eq!(VolumeScale::Decibel.from_fraction(1.0), 0.0);
+        assert!((VolumeScale::Cubic.from_fraction(0.5) - 0.125).abs() < 1e-6);
+    }
+
+    #[test]
+    fn decibel_steps_from_silence() {
+        let up = VolumeScale::Decibel.step(0.0, 3.0);
+        assert!((VolumeScale::Decibel.from_fraction(up) + 57.0).abs() < 1e-3);
+        assert_eq!(VolumeScale::Decibel.step(0.0, -3.0), 0.0);
+        assert!((VolumeScale::Linear.step(0.0, 0.05) - 0.05).abs() < 1e-6);
+    }
+
+    #[test]
+    fn bluetooth_properties() {
+        let props = |pairs: &'static [(&'static str, &'static str)]| {
+            move |key: &str| {
+                pairs
+                    .iter()
+                    .find(|(k, _)| *k == key)
+                    .map(|(_, v)| v.to_string())
+            }
+        };
+        assert_eq!(
+            BluetoothInfo::from_properties(props(&[
+                ("device.bus", "bluetooth"),
+                ("bluetooth.battery", "80%"),
+                ("bluetooth.codec", "aac"),
+            ])),
+            Some(BluetoothInfo {
+                battery: Some(80),
+                codec: Some("aac".to_string()),
+            })
+        );
+        assert_eq!(
+            BluetoothInfo::from_properties(props(&[
+                ("api.bluez5.battery", "55"),
+                ("api.bluez5.codec", "ldac"),
+            ])),
+            Some(BluetoothInfo {
+                battery: Some(55),
+                codec: Some("ldac".to_string()),
+            })
+        );
+        assert_eq!(
+            BluetoothInfo::from_properties(props(&[("device.bus", "bluetooth")])),
+            Some(BluetoothInfo::default())
+        );
+        assert_eq!(
+            BluetoothInfo::from_properties(props(&[("device.bus", "usb")])),
+            None
+        );
+    }
+
+    #[cfg(any(feature = "pulseaudio", feature = "pipewire"))]
+    #[test]
+    fn bluetooth_changes() {
+        let old = BluetoothInfo {
+            battery: Some(80),
+            codec: Some("sbc".to_string()),
+        };
+        let new = BluetoothInfo {
+            battery: Some(70),
+            codec: Some("sbc".to_string()),
+        };
+        assert_eq!(
+            new.changes(&old, "bluez_sink.phones"),
+            vec![AudioEvent::BatteryChanged {
+                device: "bluez_sink.phones".to_string(),
+                battery: 70,
+            }]
+        );
+        assert!(old.changes(&old, "bluez_sink.phones").is_empty());
+    }
+
+    #[test]
+    fn parse_steps() {
+        assert_eq!(
+            "+5%".parse::<VolumeChange>().unwrap(),
+            VolumeChange::Step(0.05, VolumeScale::Linear)
+        );
+        assert_eq!(
+            "-3dB".parse::<VolumeChange>().unwrap(),
+            VolumeChange::Step(-3.0, VolumeScale::Decibel)
+        );
+        assert_eq!(
+            "-0.1".parse::<VolumeChange>().unwrap(),
+            VolumeChange::Step(-0.1, VolumeScale::Linear)
+        );
+        assert_eq!(
+            VolumeChange::parse("2", VolumeScale::Decibel).unwrap(),
+            VolumeChange::Step(2.0, VolumeScale::Decibel)
+        );
+        assert!("loud".parse::<VolumeChange>().is_err());
+        assert!("NaN".parse::<VolumeChange>().is_err());
+    }
+
+    #[test]
+    fn globs() {
+        assert!(glob_match("*USB*", "Logitech USB Headset Analog Stereo"));
+        assert!(glob_match("alsa_output.*", "alsa_output.pci-0000_00_1f.3"));
+        assert!(glob_match("sink?", "sink1"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("*USB", "USB Headset"));
+        assert!(!glob_match("sink?", "sink"));
+    }
+
+    #[test]
+    fn policy_limits() {
+        let policy = VolumePolicy::default();
+        assert_eq!(policy.limit(None), 1.0);
+        assert_eq!(policy.limit(Some(0.6)), 1.0);
+        assert_eq!(policy.limit(Some(1.2)), 1.2);
+
+        // A sink above a low base volume is neither halved on a step up nor raised on a step down.
+        assert_eq!(policy.apply(1.05, 1.0, Some(0.5)), (1.0, false));
+        assert_eq!(policy.apply(0.95, 1.0, Some(0.5)), (0.95, false));
+        let policy = VolumePolicy {
+            max: Some(0.5),
+            boost: None,
+        };
+        assert_eq!(policy.apply(1.05, 0.8, Some(0.5)), (0.8, false));
+        assert_eq!(policy.apply(0.75, 0.8, Some(0.5)), (0.75, false));
+
+        let policy = VolumePolicy {
+            max: Some(0.8),
+            boost: Some(1.5),
+        };
+        assert_eq!(policy.hard_max(Some(0.6)), 0.8);
+        assert_eq!(policy.limit(Some(0.6)), 1.5);
+        assert_eq!(policy.apply(2.0, 0.5, None), (1.5, true));
+        assert_eq!(policy.apply(0.5, 0.4, None), (0.5, false));
+    }
+
+    #[test]
+    fn backend_lists() {
+        assert_eq!(
+            parse_backends("pipewire, alsa").unwrap(),
+            vec![Backend::PipeWire, Backend::Alsa]
+        );
+        assert_eq!("pulse".parse::<Backend>().unwrap(), Backend::PulseAudio);
+        assert!(parse_backends("pipewire,jack").is_err());
+        assert_eq!(Backend::PulseAudio.to_string(), "pulseaudio");
+        assert!(open(&[]).is_err());
+    }
+
+    #[test]
+    fn parse_sets() {
+        assert_eq!(
+            "=40%".parse::<VolumeChange>().unwrap(),
+            VolumeChange::Set(0.4, VolumeScale::Linear)
+        );
+        assert_eq!(
+            "=-6dB".parse::<VolumeChange>().unwrap(),
+            VolumeChange::Set(-6.0, VolumeScale::Decibel)
+        );
+        assert!("=-0.4".parse::<VolumeChange>().is_err());
+        assert!("=-40%".parse::<VolumeChange>().is_err());
+        assert!("=nan".parse::<VolumeChange>().is_err());
+        assert!(VolumeChange::parse("=-0.2", VolumeScale::Cubic).is_err());
+    }
+}
@@ -0,0 +1,1143 @@
+//! Native PipeWire backend.
+//!
+//! Sinks are nodes with the `Audio/Sink` media class. Their volume and mute state are read from
+//! the node's Props parameter, and the default sink is tracked through the `default` metadata
+//! object, the same way WirePlumber and `pw-metadata` do. Sinks of a sound card are changed
+//! through the active Route of the card's device instead, like pipewire-pulse does, so that the
+//! session manager keeps the route in step and saves it.
+//!
+//! PipeWire objects cannot leave the thread running their loop, so the connection lives on its own
+//! thread and requests are passed to it over a PipeWire channel.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io::{Cursor, Error, ErrorKind};
+use std::rc::Rc;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use ::pipewire as pw;
+use pw::core::PW_ID_CORE;
+use pw::device::{Device, DeviceListener};
+use pw::main_loop::MainLoop;
+use pw::metadata::{Metadata, MetadataListener};
+use pw::node::{Node, NodeListener};
+use pw::proxy::ProxyT;
+use pw::registry::{GlobalObject, Registry};
+use pw::spa::param::ParamType;
+use pw::spa::pod::deserialize::PodDeserializer;
+use pw::spa::pod::serialize::PodSerializer;
+use pw::spa::pod::{Object, Pod, Property, Value, ValueArray};
+use pw::spa::sys::{
+    SPA_PARAM_Props, SPA_PARAM_ROUTE_device, SPA_PARAM_ROUTE_index, SPA_PARAM_ROUTE_props,
+    SPA_PARAM_ROUTE_save, SPA_PARAM_Route, SPA_PROP_channelMap, SPA_PROP_channelVolumes,
+    SPA_PROP_mute, SPA_TYPE_OBJECT_ParamRoute, SPA_TYPE_OBJECT_Props,
+};
+use pw::spa::utils::dict::DictRef;
+use pw::types::ObjectType;
+
+use super::{
+    broadcast, check_absolute, AudioEvent, Backend, BluetoothInfo, Listener, SinkSelector,
+    SoundDevice, VolumePolicy, VolumeScale,
+};
+
+// Called once the server has processed a request.
+type Completion = Box<dyn FnOnce(bool) + Send>;
+
+// Metadata keys holding the default sink, as JSON objects with a `name` field. The configured key
+// is the one to write, the other one reflects the sink actually in use.
+const DEFAULT_SINK_KEY: &str = "default.audio.sink";
+const CONFIGURED_SINK_KEY: &str = "default.configured.audio.sink";
+
+#[derive(Clone)]
+struct PipeWireNodeInfo {
+    name: String,
+    description: String,
+    // Per-channel linear amplitudes, as stored in the Props parameter.
+    volumes: Vec<f32>,
+    // Channel positions, as `spa_audio_channel` values.
+    positions: Vec<u32>,
+    muted: bool,
+    bluetooth: Option<BluetoothInfo>,
+    // The device of a sound card sink, and the card device it plays to within the profile, from
+    // the `device.id` and `card.profile.device` properties.
+    card_device: Option<(u32, i32)>,
+}
+
+// An active route of a device, from its Route parameter.
+#[derive(Clone, Copy)]
+struct PipeWireRoute {
+    index: i32,
+    device: i32,
+}
+
+struct PipeWireState {
+    // Why the connection was lost, once it is.
+    error: Option<String>,
+    synced: bool,
+    default_sink: Option<String>,
+    nodes: HashMap<u32, PipeWireNodeInfo>,
+    // Active routes of the audio devices, by device id.
+    routes: HashMap<u32, Vec<PipeWireRoute>>,
+}
+
+enum PipeWireRequest {
+    Sync(Completion),
+    SetVolumes(u32, Vec<f32>, Completion),
+    SetMute(u32, bool, Completion),
+    SetDefaultSink(String, Completion),
+    Quit,
+}
+
+// A request waiting for the roundtrip issued after it.
+struct PipeWirePending {
+    // The sequence number of the roundtrip.
+    seq: i32,
+    // The proxy the request was made on, whose errors fail the request.
+    proxy: u32,
+    failed: bool,
+    done: Completion,
+}
+
+/// An audio sink known to the PipeWire server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PipeWireSink {
+    pub id: u32,
+    pub name: String,
+    pub description: String,
+    pub volume: f32,
+    pub muted: bool,
+    /// Bluetooth details, as reported through the `api.bluez5.*` node properties.
+    pub bluetooth: Option<BluetoothInfo>,
+}
+
+pub struct PipeWireSoundDevice {
+    sender: pw::channel::Sender<PipeWireRequest>,
+    state: Arc<Mutex<PipeWireState>>,
+    selector: SinkSelector,
+    scale: VolumeScale,
+    policy: VolumePolicy,
+    listeners: Arc<Mutex<Vec<Listener>>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+// Proxies bound on the loop thread, which must stay alive to keep receiving events.
+#[derive(Default)]
+struct PipeWireProxies {
+    nodes: HashMap<u32, (Node, NodeListener)>,
+    devices: HashMap<u32, (Device, DeviceListener)>,
+    metadata: Option<(Metadata, MetadataListener)>,
+}
+
+fn pipewire_error(e: pw::Error) -> Error {
+    Error::new(ErrorKind::Other, e.to_string())
+}
+
+// The average volume of a set of channel amplitudes, as a fraction of the nominal volume.
+fn fraction(volumes: &[f32]) -> f32 {
+    if volumes.is_empty() {
+        return 0.0;
+    }
+    volumes
+        .iter()
+        .map(|v| VolumeScale::Cubic.to_fraction(*v))
+        .sum::<f32>()
+        / volumes.len() as f32
+}
+
+// The short name PipeWire uses for a channel position, such as `FL`.
+fn position_name(position: u32) -> String {
+    const NAMES: &[&str] = &[
+        "UNK", "NA", "MONO", "FL", "FR", "FC", "LFE", "SL", "SR", "FLC", "FRC", "RC", "RL", "RR",
+        "TC", "TFL", "TFC", "TFR", "TRL", "TRC", "TRR", "RLC", "RRC",
+    ];
+    // Auxiliary channels are numbered from SPA_AUDIO_CHANNEL_START_Aux.
+    match NAMES.get(position as usize) {
+        Some(name) => name.to_string(),
+        None if (0x1000..0x2000).contains(&position) => format!("AUX{}", position - 0x1000),
+        None => "UNK".to_string(),
+    }
+}
+
+// Extract the name from a metadata value such as `{ "name": "alsa_output.pci" }`.
+fn json_name(value: &str) -> Option<String> {
+    let rest = &value[value.find("\"name\"")? + "\"name\"".len()..];
+    let rest = rest.trim_start().strip_prefix(':')?.trim_start();
+    let mut chars = rest.strip_prefix('"')?.chars();
+    let mut name = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(name),
+            '\\' => name.push(match chars.next()? {
+                'b' => '\u{8}',
+                'f' => '\u{c}',
+                'n' => '\n',
+                'r' => '\r',
+                't' => '\t',
+                'u' => {
+                    let code: String = chars.by_ref().take(4).collect();
+                    char::from_u32(u32::from_str_radix(&code, 16).ok()?)?
+                }
+                // Quotes, backslashes and slashes stand for themselves.
+                c => c,
+            }),
+            c => name.push(c),
+        }
+    }
+}
+
+// Quote a string as a JSON string, such as a sink name for a metadata value.
+fn json_string(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// Serialize a parameter and pass it to `f`, returning whether that could be done.
+fn with_pod<F>(value: &Value, f: F) -> bool
+where
+    F: FnOnce(&Pod),
+{
+    let bytes = match PodSerializer::serialize(Cursor::new(Vec::new()), value) {
+        Ok((cursor, _)) => cursor.into_inner(),
+        Err(_) => return false,
+    };
+    match Pod::from_bytes(&bytes) {
+        Some(pod) => {
+            f(pod);
+            true
+        }
+        None => false,
+    }
+}
+
+// Set a property of a sink, through the active route of its device if it has one, and through
+// the node's Props parameter otherwise. Returns the id of the proxy the request was made on, or
+// `None` if it could not be issued.
+fn set_props(
+    proxies: &PipeWireProxies,
+    state: &Mutex<PipeWireState>,
+    id: u32,
+    property: Property,
+) -> Option<u32> {
+    let props = Value::Object(Object {
+        type_: SPA_TYPE_OBJECT_Props,
+        id: SPA_PARAM_Props,
+        properties: vec![property],
+    });
+
+    let state = state.lock().unwrap();
+    let route = state
+        .nodes
+        .get(&id)
+        .and_then(|node| node.card_device)
+        .and_then(|(device, card_device)| {
+            let route = state
+                .routes
+                .get(&device)?
+                .iter()
+                .find(|route| route.device == card_device)?;
+            let (device, _) = proxies.devices.get(&device)?;
+            Some((device, *route))
+        });
+    if let Some((device, route)) = route {
+        let value = Value::Object(Object {
+            type_: SPA_TYPE_OBJECT_ParamRoute,
+            id: SPA_PARAM_Route,
+            properties: vec![
+                Property::new(SPA_PARAM_ROUTE_index, Value::Int(route.index)),
+                Property::new(SPA_PARAM_ROUTE_device, Value::Int(route.device)),
+                Property::new(SPA_PARAM_ROUTE_props, props),
+                Property::new(SPA_PARAM_ROUTE_save, Value::Bool(true)),
+            ],
+        });
+        let issued = with_pod(&value, |pod| device.set_param(ParamType::Route, 0, pod));
+        return Some(device.upcast_ref().id()).filter(|_| issued);
+    }
+
+    // Virtual sinks have no device, and keep their volume in the node itself.
+    let (node, _) = proxies.nodes.get(&id)?;
+    let issued = with_pod(&props, |pod| node.set_param(ParamType::Props, 0, pod));
+    Some(node.upcast_ref().id()).filter(|_| issued)
+}
+
+// Track the active routes of a device, which are reported one by one, starting from index 0
+// whenever they change.
+fn route_callback(state: &Arc<Mutex<PipeWireState>>, id: u32, index: u32, param: Option<&Pod>) {
+    let properties = match param.map(|pod| PodDeserializer::deserialize_any_from(pod.as_bytes())) {
+        Some(Ok((_, Value::Object(object)))) => object.properties,
+        _ => return,
+    };
+    let (mut route_index, mut route_device) = (None, None);
+    for property in properties {
+        match (property.key, property.value) {
+            (SPA_PARAM_ROUTE_index, Value::Int(index)) => route_index = Some(index),
+            (SPA_PARAM_ROUTE_device, Value::Int(device)) => route_device = Some(device),
+            _ => {}
+        }
+    }
+
+    let mut state = state.lock().unwrap();
+    let routes = state.routes.entry(id).or_default();
+    if index == 0 {
+        routes.clear();
+    }
+    if let (Some(index), Some(device)) = (route_index, route_device) {
+        routes.push(PipeWireRoute { index, device });
+    }
+}
+
+// Pick up the properties of a node that are not part of its registry global, and report changes
+// to its Bluetooth details.
+fn node_info_callback(
+    state: &Arc<Mutex<PipeWireState>>,
+    listeners: &Arc<Mutex<Vec<Listener>>>,
+    id: u32,
+    props: Option<&DictRef>,
+) {
+    let props = match props {
+        Some(props) => props,
+        None => return,
+    };
+    let device = props.get("device.id").and_then(|id| id.parse().ok());
+    let card_device = props
+        .get("card.profile.device")
+        .and_then(|device| device.parse().ok());
+    let bluetooth = BluetoothInfo::from_properties(|key| props.get(key).map(String::from));
+
+    let events = {
+        let mut state = state.lock().unwrap();
+        let synced = state.synced;
+        let node = match state.nodes.get_mut(&id) {
+            Some(node) => node,
+            None => return,
+        };
+        node.card_device = device.zip(card_device);
+        let events = match (&node.bluetooth, &bluetooth) {
+            (Some(old), Some(new)) if synced => new.changes(old, &node.name),
+            _ => Vec::new(),
+        };
+        node.bluetooth = bluetooth;
+        events
+    };
+    for event in events {
+        broadcast(listeners, event);
+    }
+}
+
+fn param_callback(
+    state: &Arc<Mutex<PipeWireState>>,
+    listeners: &Arc<Mutex<Vec<Listener>>>,
+    id: u32,
+    param: Option<&Pod>,
+) {
+    let properties = match param.map(|pod| PodDeserializer::deserialize_any_from(pod.as_bytes())) {
+        Some(Ok((_, Value::Object(object)))) => object.properties,
+        _ => return,
+    };
+
+    let mut events = Vec::new();
+    {
+        let mut state = state.lock().unwrap();
+        let synced = state.synced;
+        let node = match state.nodes.get_mut(&id) {
+            Some(node) => node,
+            None => return,
+        };
+        for property in properties {
+            match (property.key, property.value) {
+                (SPA_PROP_channelVolumes, Value::ValueArray(ValueArray::Float(volumes))) => {
+                    if node.volumes != volumes {
+                        node.volumes = volumes;
+                        events.push(AudioEvent::VolumeChanged {
+                            sink: node.name.clone(),
+                            volume: fraction(&node.volumes),
+                        });
+                    }
+                }
+                (SPA_PROP_channelMap, Value::ValueArray(ValueArray::Id(positions))) => {
+                    node.positions = positions.into_iter().map(|position| position.0).collect();
+                }
+                (SPA_PROP_mute, Value::Bool(muted)) => {
+                    if node.muted != muted {
+                        node.muted = muted;
+                        events.push(AudioEvent::MuteChanged {
+                            sink: node.name.clone(),
+                            muted,
+                        });
+                    }
+                }
+                _ => {}
+            }
+        }
+        // The initial state of each node is not a change.
+        if !synced {
+            events.clear();
+        }
+    }
+    for event in events {
+        broadcast(listeners, event);
+    }
+}
+
+fn global_callback(
+    registry: &Registry,
+    proxies: &Rc<RefCell<PipeWireProxies>>,
+    state: &Arc<Mutex<PipeWireState>>,
+    listeners: &Arc<Mutex<Vec<Listener>>>,
+    global: &GlobalObject<&DictRef>,
+) {
+    let props = match global.props {
+        Some(props) => props,
+        None => return,
+    };
+    match global.type_ {
+        ObjectType::Node if props.get("media.class") == Some("Audio/Sink") => {
+            let name = match props.get("node.name") {
+                Some(name) => name.to_string(),
+                None => return,
+            };
+            let node: Node = match registry.bind(global) {
+                Ok(node) => node,
+                Err(_) => return,
+            };
+
+            let id = global.id;
+            let cb_state = state.clone();
+            let cb_listeners = listeners.clone();
+            let info_state = state.clone();
+            let info_listeners = listeners.clone();
+            let listener = node
+                .add_listener_local()
+                .info(move |info| {
+                    node_info_callback(&info_state, &info_listeners, id, info.props())
+                })
+                .param(move |_seq, param_type, _index, _next, param| {
+                    if param_type == ParamType::Props {
+                        param_callback(&cb_state, &cb_listeners, id, param);
+                    }
+                })
+                .register();
+            node.subscribe_params(&[ParamType::Props]);
+
+            let synced = {
+                let mut state = state.lock().unwrap();
+                state.nodes.insert(
+                    id,
+                    PipeWireNodeInfo {
+                        name: name.clone(),
+                        description: props
+                            .get("node.description")
+                            .unwrap_or_default()
+                            .to_string(),
+                        volumes: Vec::new(),
+                        positions: Vec::new(),
+                        muted: false,
+                        // Filled in from the full node properties, once they arrive.
+                        bluetooth: None,
+                        card_device: None,
+                    },
+                );
+                state.synced
+            };
+            proxies.borrow_mut().nodes.insert(id, (node, listener));
+            if synced {
+                broadcast(
+                    listeners,
+                    AudioEvent::SinkAdded {
+                        index: id,
+                        sink: name,
+                    },
+                );
+            }
+        }
+        ObjectType::Device if props.get("media.class") == Some("Audio/Device") => {
+            let device: Device = match registry.bind(global) {
+                Ok(device) => device,
+                Err(_) => return,
+            };
+
+            let id = global.id;
+            let cb_state = state.clone();
+            let listener = device
+                .add_listener_local()
+                .param(move |_seq, param_type, index, _next, param| {
+                    if param_type == ParamType::Route {
+                        route_callback(&cb_state, id, index, param);
+                    }
+                })
+                .register();
+            device.subscribe_params(&[ParamType::Route]);
+            proxies.borrow_mut().devices.insert(id, (device, listener));
+        }
+        ObjectType::Metadata if props.get("metadata.name") == Some("default") => {
+            let metadata: Metadata = match registry.bind(global) {
+                Ok(metadata) => metadata,
+                Err(_) => return,
+            };
+
+            let cb_state = state.clone();
+            let cb_listeners = listeners.clone();
+            let listener = metadata
+                .add_listener_local()
+                .property(move |subject, key, _type, value| {
+                    if subject != PW_ID_CORE || key != Some(DEFAULT_SINK_KEY) {
+                        return 0;
+                    }
+                    let sink = value.and_then(json_name);
+                    let changed = {
+                        let mut state = cb_state.lock().unwrap();
+                        let changed = state.synced && state.default_sink != sink;
+                        state.default_sink = sink.clone();
+                        changed
+                    };
+                    if let (true, Some(sink)) = (changed, sink) {
+                        broadcast(&cb_listeners, AudioEvent::DefaultSinkChanged { sink });
+                    }
+                    0
+                })
+                .register();
+            proxies.borrow_mut().metadata = Some((metadata, listener));
+        }
+        _ => {}
+    }
+}
+
+fn run(
+    state: Arc<Mutex<PipeWireState>>,
+    listeners: Arc<Mutex<Vec<Listener>>>,
+    requests: pw::channel::Receiver<PipeWireRequest>,
+    ready: Sender<Result<(), Error>>,
+) -> Result<(), Error> {
+    let mainloop = MainLoop::new(None).map_err(pipewire_error)?;
+    let context = pw::context::Context::new(&mainloop).map_err(pipewire_error)?;
+    let core = context.connect(None).map_err(pipewire_error)?;
+    let registry = Rc::new(core.get_registry().map_err(pipewire_error)?);
+    let proxies = Rc::new(RefCell::new(PipeWireProxies::default()));
+
+    let cb_registry = registry.clone();
+    let cb_proxies = proxies.clone();
+    let cb_state = state.clone();
+    let cb_listeners = listeners.clone();
+    let remove_proxies = proxies.clone();
+    let remove_state = state.clone();
+    let remove_listeners = listeners.clone();
+    let _registry_listener = registry
+        .add_listener_local()
+        .global(move |global| {
+            global_callback(&cb_registry, &cb_proxies, &cb_state, &cb_listeners, global)
+        })
+        .global_remove(move |id| {
+            {
+                let mut proxies = remove_proxies.borrow_mut();
+                proxies.nodes.remove(&id);
+                proxies.devices.remove(&id);
+            }
+            let removed = {
+                let mut state = remove_state.lock().unwrap();
+                state.routes.remove(&id);
+                state.nodes.remove(&id)
+            };
+            if let Some(node) = removed {
+                broadcast(
+                    &remove_listeners,
+                    AudioEvent::SinkRemoved {
+                        index: id,
+                        sink: node.name,
+                    },
+                );
+            }
+        })
+        .register();
+
+    // Requests complete once a roundtrip issued after them is done, as the server handles
+    // everything from a client in order, and reports a rejected request before that.
+    let pending: Rc<RefCell<Vec<PipeWirePending>>> = Rc::new(RefCell::new(Vec::new()));
+    let done_pending = pending.clone();
+    let error_pending = pending.clone();
+    let _core_listener = core
+        .add_listener_local()
+        .done(move |id, seq| {
+            if id != PW_ID_CORE {
+                return;
+            }
+            let mut pending = done_pending.borrow_mut();
+            while let Some(pos) = pending.iter().position(|p| p.seq == seq.seq()) {
+                let request = pending.remove(pos);
+                (request.done)(!request.failed);
+            }
+        })
+        .error({
+            let mainloop = mainloop.clone();
+            let state = state.clone();
+            move |id, _seq, _res, message| {
+                // Errors on the core itself mean the connection is gone. Leaving the loop drops
+                // the pending completions, which fails their requests with this error.
+                if id == PW_ID_CORE {
+                    state.lock().unwrap().error = Some(message.to_string());
+                    mainloop.quit();
+                    return;
+                }
+                // Anything else is a proxy rejecting a request. The bindings do not return the
+                // sequence numbers of the requests made on proxies, so this fails every request
+                // on that proxy still waiting for its roundtrip.
+                for request in error_pending.borrow_mut().iter_mut() {
+                    if request.proxy == id {
+                        request.failed = true;
+                    }
+                }
+            }
+        })
+        .register();
+
+    let cb_mainloop = mainloop.clone();
+    let cb_state = state.clone();
+    let _receiver = requests.attach(mainloop.loop_(), move |request| {
+        // The proxy each request was made on, or `None` if it could not be issued. Roundtrips
+        // are made on the core.
+        let (done, proxy) = match request {
+            PipeWireRequest::Quit => {
+                cb_mainloop.quit();
+                return;
+            }
+            PipeWireRequest::Sync(done) => (done, Some(PW_ID_CORE)),
+            PipeWireRequest::SetVolumes(id, volumes, done) => {
+                let property = Property::new(
+                    SPA_PROP_channelVolumes,
+                    Value::ValueArray(ValueArray::Float(volumes)),
+                );
+                (done, set_props(&proxies.borrow(), &cb_state, id, property))
+            }
+            PipeWireRequest::SetMute(id, muted, done) => {
+                let property = Property::new(SPA_PROP_mute, Value::Bool(muted));
+                (done, set_props(&proxies.borrow(), &cb_state, id, property))
+            }
+            PipeWireRequest::SetDefaultSink(name, done) => {
+                let proxies = proxies.borrow();
+                let proxy = match &proxies.metadata {
+                    Some((metadata, _)) => {
+                        metadata.set_property(
+                            PW_ID_CORE,
+                            CONFIGURED_SINK_KEY,
+                            Some("Spa:String:JSON"),
+                            Some(&format!("{{ \"name\": {} }}", json_string(&name))),
+                        );
+                        Some(metadata.upcast_ref().id())
+                    }
+                    None => None,
+                };
+                (done, proxy)
+            }
+        };
+
+        let proxy = match proxy {
+            Some(proxy) => proxy,
+            None => {
+                done(false);
+                return;
+            }
+        };
+        match core.sync(0) {
+            Ok(seq) => pending.borrow_mut().push(PipeWirePending {
+                seq: seq.seq(),
+                proxy,
+                failed: false,
+                done,
+            }),
+            Err(_) => done(false),
+        }
+    });
+
+    let _ = ready.send(Ok(()));
+    mainloop.run();
+    Ok(())
+}
+
+fn completion() -> (Completion, Receiver<bool>) {
+    let (tx, rx) = channel();
+    (
+        Box::new(move |success| {
+            let _ = tx.send(success);
+        }),
+        rx,
+    )
+}
+
+impl PipeWireSoundDevice {
+    pub fn new(sink: SinkSelector) -> Result<Self, Error> {
+        pw::init();
+
+        let state = Arc::new(Mutex::new(PipeWireState {
+            error: None,
+            synced: false,
+            default_sink: None,
+            nodes: HashMap::new(),
+            routes: HashMap::new(),
+        }));
+        let listeners = Arc::new(Mutex::new(Vec::new()));
+
+        let (sender, requests) = pw::channel::channel();
+        let (ready, started) = channel();
+        let loop_state = state.clone();
+        let loop_listeners = listeners.clone();
+        let thread = thread::Builder::new()
+            .name("pw_main".to_string())
+            .spawn(move || {
+                if let Err(e) = run(loop_state, loop_listeners, requests, ready.clone()) {
+                    let _ = ready.send(Err(e));
+                }
+            })?;
+        started
+            .recv()
+            .map_err(|_e| Error::new(ErrorKind::Other, "unable to connect to pipewire"))??;
+
+        let mut device = PipeWireSoundDevice {
+            sender,
+            state,
+            selector: SinkSelector::FollowDefault,
+            scale: VolumeScale::default(),
+            policy: VolumePolicy::default(),
+            listeners,
+            thread: Some(thread),
+        };
+
+        // The first roundtrip binds every sink, the second one collects their parameters.
+        device.roundtrip()?;
+        device.roundtrip()?;
+        device.state.lock().unwrap().synced = true;
+        device.set_sink(sink)?;
+
+        Ok(device)
+    }
+
+    /// Change which sink this device controls.
+    pub fn set_sink(&mut self, sink: SinkSelector) -> Result<(), Error> {
+        if let SinkSelector::Fixed(name) = &sink {
+            let state = self.state.lock().unwrap();
+            if !state.nodes.values().any(|node| &node.name == name) {
+                return Err(Error::new(ErrorKind::NotFound, "no such sink"));
+            }
+        }
+        self.selector = sink;
+        Ok(())
+    }
+
+    /// The name of the sink currently being controlled.
+    pub fn sink_name(&self) -> String {
+        let state = self.state.lock().unwrap();
+        self.node(&state)
+            .map(|(_, node)| node.name.clone())
+            .unwrap_or_default()
+    }
+
+    /// List the audio sinks, ordered by id.
+    pub fn sinks(&self) -> Vec<PipeWireSink> {
+        let state = self.state.lock().unwrap();
+        let mut sinks: Vec<PipeWireSink> = state
+            .nodes
+            .iter()
+            .map(|(id, node)| PipeWireSink {
+                id: *id,
+                name: node.name.clone(),
+                description: node.description.clone(),
+                volume: fraction(&node.volumes),
+                muted: node.muted,
+                bluetooth: node.bluetooth.clone(),
+            })
+            .collect();
+        sinks.sort_by_key(|sink| sink.id);
+        sinks
+    }
+
+    /// Make the named sink the default one.
+    pub fn set_default_sink(&self, name: &str) -> Result<(), Error> {
+        let (done, wait) = completion();
+        self.send(PipeWireRequest::SetDefaultSink(name.to_string(), done))?;
+        self.wait_for(wait)
+    }
+
+    /// The scale used by `volume`, `add_volume` and `set_volume`.
+    pub fn volume_scale(&self) -> VolumeScale {
+        self.scale
+    }
+
+    pub fn set_volume_scale(&mut self, scale: VolumeScale) {
+        self.scale = scale;
+    }
+
+    /// The limits `add_volume` and `set_volume` are clamped to. Nodes have no base volume, so the
+    /// hard maximum defaults to 100%.
+    pub fn volume_policy(&self) -> VolumePolicy {
+        self.policy
+    }
+
+    pub fn set_volume_policy(&mut self, policy: VolumePolicy) {
+        self.policy = policy;
+    }
+
+    /// Subscribe to audio events. The receiver is dropped from the subscriber list once it hangs
+    /// up.
+    pub fn subscribe(&self) -> Receiver<AudioEvent> {
+        let (tx, rx) = channel();
+        self.listeners
+            .lock()
+            .unwrap()
+            .push(Box::new(move |event| tx.send(event.clone()).is_ok()));
+        rx
+    }
+
+    pub fn volume(&self) -> f32 {
+        let state = self.state.lock().unwrap();
+        let volume = self
+            .node(&state)
+            .map_or(0.0, |(_, node)| fraction(&node.volumes));
+        self.scale.from_fraction(volume)
+    }
+
+    pub fn muted(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        self.node(&state).map_or(false, |(_, node)| node.muted)
+    }
+
+    pub fn channel_names(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        match self.node(&state) {
+            Some((_, node)) => node.positions.iter().map(|p| position_name(*p)).collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn channel_volumes(&self) -> Vec<f32> {
+        let state = self.state.lock().unwrap();
+        match self.node(&state) {
+            Some((_, node)) => node
+                .volumes
+                .iter()
+                .map(|v| VolumeScale::Cubic.to_fraction(*v))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn add_volume(&mut self, step: f32, cap: Option<f32>) -> Result<(), Error> {
+        let (id, volumes) = self.current_volumes()?;
+        let loudest = volumes
+            .iter()
+            .map(|v| VolumeScale::Cubic.to_fraction(*v))
+            .fold(0.0, f32::max);
+
+        let mut target = self.scale.step(loudest, step);
+        if step > 0.0 {
+            if let Some(cap) = cap {
+                target = target.min(self.scale.to_fraction(cap));
+            }
+        }
+        let target = self.apply_policy(id, loudest, target);
+        self.scale_to(id, volumes, loudest, target)
+    }
+
+    /// Set the volume of the loudest channel, keeping the ratio between channels.
+    pub fn set_volume(&mut self, val: f32, cap: Option<f32>) -> Result<(), Error> {
+        check_absolute(val, self.scale)?;
+        let (id, volumes) = self.current_volumes()?;
+        let loudest = volumes
+            .iter()
+            .map(|v| VolumeScale::Cubic.to_fraction(*v))
+            .fold(0.0, f32::max);
+
+        let mut target = self.scale.to_fraction(val);
+        if let Some(cap) = cap {
+            target = target.min(self.scale.to_fraction(cap));
+        }
+        let target = self.apply_policy(id, loudest, target);
+        self.scale_to(id, volumes, loudest, target)
+    }
+
+    pub fn set_muted(&mut self, muted: bool) -> Result<(), Error> {
+        let id = {
+            let state = self.state.lock().unwrap();
+            match self.node(&state) {
+                Some((id, _)) => id,
+                None => return Err(Error::new(ErrorKind::NotFound, "no sink to control")),
+            }
+        };
+        let (done, wait) = completion();
+        self.send(PipeWireRequest::SetMute(id, muted, done))?;
+        self.wait_for(wait)
+    }
+
+    // The node being controlled. A fixed sink that went away falls back to the default one.
+    fn node<'a>(&self, state: &'a PipeWireState) -> Option<(u32, &'a PipeWireNodeInfo)> {
+        let by_name = |name: &str| {
+            state
+                .nodes
+                .iter()
+                .find(|(_, node)| node.name == name)
+                .map(|(id, node)| (*id, node))
+        };
+        let fixed = match &self.selector {
+            SinkSelector::Fixed(name) => by_name(name),
+            SinkSelector::FollowDefault => None,
+        };
+        fixed.or_else(|| state.default_sink.as_ref().and_then(|name| by_name(name)))
+    }
+
+    fn current_volumes(&self) -> Result<(u32, Vec<f32>), Error> {
+        let state = self.state.lock().unwrap();
+        match self.node(&state) {
+            Some((id, node)) if !node.volumes.is_empty() => Ok((id, node.volumes.clone())),
+            _ => Err(Error::new(ErrorKind::Other, "unable to set volume")),
+        }
+    }
+
+    // Clamp a target volume according to the volume policy, and report it if it engages boost.
+    fn apply_policy(&self, id: u32, current: f32, target: f32) -> f32 {
+        let (target, boosted) = self.policy.apply(target, current, None);
+        if boosted {
+            let sink = match self.state.lock().unwrap().nodes.get(&id) {
+                Some(node) => node.name.clone(),
+                None => return target,
+            };
+            broadcast(
+                &self.listeners,
+                AudioEvent::BoostEngaged {
+                    sink,
+                    volume: target,
+                },
+            );
+        }
+        target
+    }
+
+    // Scale all channels so that the loudest one ends up at the target.
+    fn scale_to(&self, id: u32, volumes: Vec<f32>, loudest: f32, target: f32) -> Result<(), Error> {
+        let volumes = volumes
+            .into_iter()
+            .map(|v| {
+                let fraction = if loudest > 0.0 {
+                    VolumeScale::Cubic.to_fraction(v) * target / loudest
+                } else {
+                    target
+                };
+                VolumeScale::Cubic.from_fraction(fraction)
+            })
+            .collect();
+
+        let (done, wait) = completion();
+        self.send(PipeWireRequest::SetVolumes(id, volumes, done))?;
+        self.wait_for(wait)
+    }
+
+    fn roundtrip(&self) -> Result<(), Error> {
+        let (done, wait) = completion();
+        self.send(PipeWireRequest::Sync(done))?;
+        self.wait_for(wait)
+    }
+
+    fn send(&self, request: PipeWireRequest) -> Result<(), Error> {
+        if let Some(e) = self.connection_error() {
+            return Err(e);
+        }
+        self.sender
+            .send(request)
+            .map_err(|_e| Error::new(ErrorKind::Other, "unable to send pipewire request"))
+    }
+
+    fn wait_for(&self, wait: Receiver<bool>) -> Result<(), Error> {
+        let success = wait.recv().map_err(|_e| {
+            self.connection_error()
+                .unwrap_or_else(|| Error::new(ErrorKind::Other, "pipewire request was dropped"))
+        })?;
+        if !success {
+            return Err(Error::new(ErrorKind::Other, "pipewire request failed"));
+        }
+        Ok(())
+    }
+
+    // The error the server closed the connection with, if it did.
+    fn connection_error(&self) -> Option<Error> {
+        let state = self.state.lock().unwrap();
+        state.error.as_ref().map(|message| {
+            Error::new(
+                ErrorKind::Other,
+                format!("lost connection to pipewire: {}", message),
+            )
+        })
+    }
+}
+
+impl Drop for PipeWireSoundDevice {
+    fn drop(&mut self) {
+        let _ = self.send(PipeWireRequest::Quit);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl SoundDevice for PipeWireSoundDevice {
+    fn backend(&self) -> Backend {
+        Backend::PipeWire
+    }
+
+    fn volume_scale(&self) -> VolumeScale {
+        PipeWireSoundDevice::volume_scale(self)
+    }
+
+    fn set_volume_scale(&mut self, scale: VolumeScale) {
+        PipeWireSoundDevice::set_volume_scale(self, scale)
+    }
+
+    fn volume_policy(&self) -> VolumePolicy {
+        PipeWireSoundDevice::volume_policy(self)
+    }
+
+    fn set_volume_policy(&mut self, policy: VolumePolicy) {
+        PipeWireSoundDevice::set_volume_policy(self, policy)
+    }
+
+    fn volume(&self) -> f32 {
+        PipeWireSoundDevice::volume(self)
+    }
+
+    fn muted(&self) -> bool {
+        PipeWireSoundDevice::muted(self)
+    }
+
+    fn channel_names(&self) -> Vec<String> {
+        PipeWireSoundDevice::channel_names(self)
+    }
+
+    fn channel_volumes(&self) -> Vec<f32> {
+        PipeWireSoundDevice::channel_volumes(self)
+    }
+
+    fn add_volume(&mut self, step: f32, cap: Option<f32>) -> Result<(), Error> {
+        PipeWireSoundDevice::add_volume(self, step, cap)
+    }
+
+    fn set_volume(&mut self, val: f32, cap: Option<f32>) -> Result<(), Error> {
+        PipeWireSoundDevice::set_volume(self, val, cap)
+    }
+
+    fn set_muted(&mut self, muted: bool) -> Result<(), Error> {
+        PipeWireSoundDevice::set_muted(self, muted)
+    }
+
+    fn subscribe(&self) -> Receiver<AudioEvent> {
+        PipeWireSoundDevice::subscribe(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use std::time::Duration;
+
+    #[test]
+    fn parse_metadata_name() {
+        assert_eq!(
+            json_name("{ \"name\": \"alsa_output.pci-0000_00_1f.3\" }"),
+            Some("alsa_output.pci-0000_00_1f.3".to_string())
+        );
+        assert_eq!(json_name("{\"name\":\"sink\"}"), Some("sink".to_string()));
+        assert_eq!(json_name("{ \"other\": 1 }"), None);
+        assert_eq!(
+            json_name(r#"{ "name": "my \"sink\" \\ \u00e9" }"#),
+            Some("my \"sink\" \\ é".to_string())
+        );
+        assert_eq!(json_name(r#"{ "name": "unterminated\" }"#), None);
+
+        let name = "odd \"name\" with \\ and \n";
+        assert_eq!(json_string(name), r#""odd \"name\" with \\ and \u000a""#);
+        assert_eq!(
+            json_name(&format!("{{ \"name\": {} }}", json_string(name))),
+            Some(name.to_string())
+        );
+    }
+
+    #[test]
+    fn position_names() {
+        assert_eq!(position_name(3), "FL");
+        assert_eq!(position_name(6), "LFE");
+        assert_eq!(position_name(0x1002), "AUX2");
+    }
+
+    // These tests need a running PipeWire daemon, such as one configured with only null sinks.
+    // They are run explicitly with `cargo test -- --ignored`.
+    struct NullSink {
+        name: String,
+    }
+
+    impl NullSink {
+        fn create(name: &str) -> NullSink {
+            Command::new("pw-cli")
+                .args(&[
+                    "create-node",
+                    "adapter",
+                    &format!(
+                        "{{ factory.name=support.null-audio-sink node.name={} \
+                         media.class=Audio/Sink object.linger=true audio.position=[FL FR] }}",
+                        name
+                    ),
+                ])
+                .output()
+                .unwrap();
+            NullSink {
+                name: name.to_string(),
+            }
+        }
+    }
+
+    impl Drop for NullSink {
+        fn drop(&mut self) {
+            let _ = Command::new("pw-cli")
+                .args(&["destroy", &self.name])
+                .output();
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn set_volume_on_null_sink() {
+        let sink = NullSink::create("platformctl_test_pipewire");
+        let mut dev = PipeWireSoundDevice::new(SinkSelector::Fixed(sink.name.clone())).unwrap();
+        assert_eq!(dev.sink_name(), sink.name);
+
+        dev.set_volume(0.5, None).unwrap();
+        assert!((dev.volume() - 0.5).abs() < 0.01);
+        dev.set_muted(true).unwrap();
+        assert!(dev.muted());
+    }
+
+    #[test]
+    #[ignore]
+    fn default_sink_follows_metadata() {
+        let sink = NullSink::create("platformctl_test_pipewire_default");
+        let dev = PipeWireSoundDevice::new(SinkSelector::FollowDefault).unwrap();
+        let events = dev.subscribe();
+        dev.set_default_sink(&sink.name).unwrap();
+
+        let changed = loop {
+            match events.recv_timeout(Duration::from_secs(5)) {
+                Ok(AudioEvent::DefaultSinkChanged { sink }) => break sink,
+                Ok(_) => {}
+                Err(_) => panic!("timed out waiting for the default sink to change"),
+            }
+        };
+        assert_eq!(changed, sink.name);
+        assert_eq!(dev.sink_name(), sink.name);
+    }
+}
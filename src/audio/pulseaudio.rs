@@ -1,465 +1,2375 @@
+#[cfg(feature = "async")]
+pub mod asynchronous;
+pub mod autoswitch;
+pub mod scene;
+
 use std::cell::RefCell;
 use std::collections::HashMap;
-use std::ops::Deref;
-use std::rc::Rc;
-use std::sync::mpsc::{channel, Sender};
-use std::sync::{Arc, Mutex};
+use std::env;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::marker::PhantomData;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::rc::{Rc, Weak};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
+use std::time::{Duration, SystemTime};
+
+use super::wav::{SampleFormat, WavSample};
+use super::{
+    broadcast, check_absolute, glob_match, AudioEvent, Backend, BluetoothInfo, Listener,
+    SinkOperation, SinkSelector, SoundDevice, VolumePolicy, VolumeScale,
+};
 
 use libpulse_binding::callbacks::ListResult;
+use libpulse_binding::channelmap::{Map, Position};
 use libpulse_binding::context::{
-    flags, introspect::ServerInfo, introspect::SinkInfo, subscribe::subscription_masks,
-    subscribe::Facility, subscribe::Operation as SubscribeOperation, Context, State as PulseState,
+    flags, introspect::CardInfo, introspect::ServerInfo, introspect::SinkInfo,
+    introspect::SourceInfo, subscribe::subscription_masks, subscribe::Facility,
+    subscribe::Operation as SubscribeOperation, Context, State as PulseState,
 };
-use libpulse_binding::mainloop::standard::IterateResult;
-use libpulse_binding::mainloop::standard::Mainloop;
+use libpulse_binding::def::{BufferAttr, SinkState, SourceState};
+use libpulse_binding::mainloop::threaded::Mainloop;
 use libpulse_binding::proplist::{properties, Proplist};
-use libpulse_binding::volume::{ChannelVolumes, Volume, VOLUME_NORM};
+use libpulse_binding::sample::{Format, Spec};
+use libpulse_binding::stream::{PeekResult, SeekMode, State as StreamState, Stream};
+use libpulse_binding::volume::{ChannelVolumes, Volume, VOLUME_MAX, VOLUME_NORM};
+
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+// The name feedback sounds are uploaded to the sample cache under.
+const FEEDBACK_SAMPLE: &str = "platformctl-feedback";
+
+// The default feedback sound, a short pop.
+const FEEDBACK_WAV: &[u8] = include_bytes!("feedback.wav");
+
+// Identifies peak meter streams, across all connections.
+static NEXT_METER: AtomicU32 = AtomicU32::new(0);
+
+// Called once the server has processed a request.
+type Completion = Box<dyn FnOnce(bool) + Send>;
 
+// Called with the client state after it changed, along with the event describing the change.
+type ClientListener = Arc<dyn Fn(Arc<Mutex<PulseAudioClient>>, Option<AudioEvent>) + Send + Sync>;
+
+// The single connection to the server, driven by a threaded mainloop.
+//
+// Callbacks run on the mainloop thread with the mainloop lock held. Everything else only touches
+// the context (and the reference counts of its handles) after taking the lock through `mainloop`,
+// which also serializes callers, so the connection can be shared between threads.
 struct PulseAudioConnection {
-    mainloop: Rc<RefCell<Mainloop>>,
-    context: Rc<RefCell<Context>>,
+    context: Confined<Rc<RefCell<Context>>>,
+    handle: Confined<PulseAudioHandle>,
+    client: Arc<Mutex<PulseAudioClient>>,
+    listener: ClientListener,
+    options: PulseAudioOptions,
+    // Samples uploaded to the sample cache, to upload again after reconnecting.
+    samples: Mutex<HashMap<String, Arc<WavSample>>>,
+    state: Sender<()>,
+    mainloop: Mutex<SharedMainloop>,
+}
+
+// Proof that the mainloop lock is held by the current thread, which it cannot leave.
+struct MainloopLock(PhantomData<*const ()>);
+
+impl MainloopLock {
+    // Safety: the mainloop lock must be held for as long as the returned value lives.
+    unsafe fn assume_held() -> Self {
+        MainloopLock(PhantomData)
+    }
+}
+
+// A value holding `Rc`s that are shared with the callbacks, only reachable with the mainloop
+// lock held.
+struct Confined<T>(T);
+
+// Safety: the value is only reachable through `get`, and callbacks only run with the mainloop
+// lock held, so its reference counts are never touched from two threads at once. It is dropped
+// with the connection, after the mainloop has stopped.
+unsafe impl<T> Send for Confined<T> {}
+unsafe impl<T> Sync for Confined<T> {}
+
+impl<T> Confined<T> {
+    fn get<'a>(&'a self, _lock: &'a MainloopLock) -> &'a T {
+        &self.0
+    }
+}
+
+// The threaded mainloop, to be used behind a mutex.
+struct SharedMainloop(Mainloop);
+
+// Safety: the `Rc` inside the mainloop is never kept by anything else, only cloned for the
+// duration of calls into it, which the mutex serializes.
+unsafe impl Send for SharedMainloop {}
+
+// What the callbacks need to update the client and issue follow-up requests.
+#[derive(Clone)]
+struct PulseAudioHandle {
+    context: Weak<RefCell<Context>>,
+    client: Arc<Mutex<PulseAudioClient>>,
+    listener: ClientListener,
+    // Upload streams, kept alive until they are done.
+    uploads: Rc<RefCell<Vec<Rc<RefCell<Stream>>>>>,
+    // Peak meter streams, kept alive until their meter is dropped.
+    meters: Rc<RefCell<HashMap<u32, Rc<RefCell<Stream>>>>>,
 }
 
 #[derive(Clone)]
 struct PulseAudioSinkInfo {
+    index: u32,
+    description: String,
+    volume: ChannelVolumes,
+    channel_map: Map,
+    base_volume: Volume,
+    mute: bool,
+    suspended: bool,
+    active_port: Option<String>,
+    properties: HashMap<String, String>,
+    card: Option<u32>,
+    bluetooth: Option<BluetoothInfo>,
+}
+
+#[derive(Clone)]
+struct PulseAudioSourceInfo {
+    name: String,
+    description: String,
     volume: ChannelVolumes,
     mute: bool,
+    suspended: bool,
+    active_port: Option<String>,
+    // Whether this is the monitor of a sink.
+    monitor: bool,
 }
 
 struct PulseAudioClient {
-    sender: Sender<PulseAudioClientRequest>,
     default_sink: String,
+    default_source: String,
     sinks: HashMap<String, PulseAudioSinkInfo>,
+    sink_names: HashMap<u32, String>,
+    // Sources are kept by index, as removal events only carry that.
+    sources: HashMap<u32, PulseAudioSourceInfo>,
+    cards: HashMap<u32, PulseAudioCard>,
 }
 
 enum PulseAudioClientRequest {
-    GetDefaultDevice(Option<Sender<bool>>),
-    GetSinkInfoByIndex(Option<Sender<bool>>, u32),
-    GetSinkInfoByName(Option<Sender<bool>>, String),
-    SetSinkVolumeByName(Option<Sender<bool>>, String, ChannelVolumes),
-    SetSinkMuteByName(Option<Sender<bool>>, String, bool),
+    GetDefaultDevice(Option<Completion>),
+    GetSinkInfoByIndex(Option<Completion>, u32, bool),
+    GetSinkInfoByName(Option<Completion>, String),
+    GetSinkInfoList(Option<Completion>),
+    SetSinkVolumeByName(Option<Completion>, String, ChannelVolumes, Option<FeedbackCue>),
+    SetSinkMuteByName(Option<Completion>, String, bool),
+    UploadSample(Option<Completion>, String, Arc<WavSample>),
+    PlaySample(Option<Completion>, String, String),
+    StartMeter(Option<Completion>, u32, String, u32, Sender<f32>),
+    StopMeter(u32),
+    LoadModule(Sender<u32>, String, String),
+    UnloadModule(Option<Completion>, u32),
+    GetModuleInfoList(Sender<Vec<PulseAudioModule>>),
+    GetSourceInfoByIndex(Option<Completion>, u32),
+    GetSourceInfoList(Option<Completion>),
+    // Sinks and sources are given by name or index.
+    SuspendSink(Option<Completion>, DeviceId, bool),
+    SuspendSource(Option<Completion>, DeviceId, bool),
+    GetCardInfoByIndex(Option<Completion>, u32),
+    GetCardInfoList(Option<Completion>),
+    SetCardProfileByName(Option<Completion>, String, String),
+    SetSinkPortByName(Option<Completion>, String, String),
+    SetSourcePortByName(Option<Completion>, String, String),
+    SetSourceVolumeByName(Option<Completion>, String, ChannelVolumes),
+    SetSourceMuteByName(Option<Completion>, String, bool),
+    SetDefaultSink(Option<Completion>, String),
+    SetDefaultSource(Option<Completion>, String),
+    // Sends the index of each sink input along with the index of its sink.
+    GetSinkInputInfoList(Sender<Vec<(u32, u32)>>),
+    MoveSinkInputByName(Option<Completion>, u32, String),
 }
 
-#[derive(Debug)]
-struct PulseAudioSoundDeviceInner {
-    name: Option<String>,
-    volume: Option<ChannelVolumes>,
-    volume_avg: f32,
-    muted: bool,
-    default_sink: String
+/// How to reach the PulseAudio server, and how to present ourselves to it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PulseAudioOptions {
+    /// The server to connect to, in the format of `PULSE_SERVER`, such as
+    /// `unix:/run/user/1000/pulse/native` or `tcp:audio.example.com:4713`. The default server is
+    /// used if unset.
+    pub server: Option<String>,
+    /// The application name reported to the server.
+    pub app_name: String,
+    /// Additional client properties, such as `application.icon_name`.
+    pub properties: Vec<(String, String)>,
 }
 
-pub struct PulseAudioSoundDevice {
-    client: Arc<Mutex<PulseAudioClient>>,
-    inner: Arc<Mutex<PulseAudioSoundDeviceInner>>,
-    block: bool,
+impl Default for PulseAudioOptions {
+    fn default() -> Self {
+        PulseAudioOptions {
+            server: None,
+            app_name: "platformctl".to_string(),
+            properties: Vec::new(),
+        }
+    }
 }
 
-impl PulseAudioConnection {
-    fn new() -> Result<Self, ::std::io::Error> {
-        let mut proplist = Proplist::new().unwrap();
-        proplist
-            .set_str(properties::APPLICATION_NAME, "platformctl")
-            .unwrap();
+/// A sound played on the sink after changing its volume through `add_volume` or `set_volume`,
+/// so the new level can be heard.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Feedback {
+    /// A WAV file to play instead of the bundled sound.
+    pub file: Option<PathBuf>,
+    /// Sounds requested sooner than this after the previous one are skipped, so that holding a
+    /// volume key does not stack them. This also applies across processes, through a file in
+    /// `$XDG_RUNTIME_DIR`; without one, every change gets a sound.
+    pub min_interval: Duration,
+}
+
+impl Default for Feedback {
+    fn default() -> Self {
+        Feedback {
+            file: None,
+            min_interval: Duration::from_millis(150),
+        }
+    }
+}
 
-        let mainloop = Rc::new(RefCell::new(Mainloop::new().unwrap()));
+// A feedback sound due after a volume change, played once the server accepted the change.
+struct FeedbackCue {
+    // The file keeping the time of the last sound, if there is a private place for it.
+    stamp: Option<PathBuf>,
+}
 
-        let context = Rc::new(RefCell::new(
-            Context::new_with_proplist(mainloop.borrow().deref(), "platformctl_context", &proplist)
-                .unwrap(),
+impl FeedbackCue {
+    fn play(self, handle: &PulseAudioHandle, sink: String) {
+        if let Some(stamp) = &self.stamp {
+            let _ = touch(stamp);
+        }
+        handle.send(PulseAudioClientRequest::PlaySample(
+            None,
+            FEEDBACK_SAMPLE.to_string(),
+            sink,
         ));
+    }
+}
 
-        context
-            .borrow_mut()
-            .connect(None, flags::NOFLAGS, None)
-            .map_err(|_e| {
-                ::std::io::Error::new(
-                    ::std::io::ErrorKind::Other,
-                    "unable to connect to pulseaudio context",
-                )
-            })?;
+// Update the modification time of a file, creating it if needed. Symlinks are not followed, so
+// that the file can not be used to truncate another one.
+fn touch(path: &Path) -> io::Result<()> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .custom_flags(libc::O_NOFOLLOW)
+        .open(path)
+        .map(|_| ())
+}
 
-        let mut connection = PulseAudioConnection { mainloop, context };
+/// What a peak meter measures.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MeterTarget {
+    /// The named sink, through its monitor source.
+    Sink(String),
+    /// The named source, such as a microphone.
+    Source(String),
+}
 
-        // Wait for context to be ready
-        loop {
-            connection.iterate(false)?;
-            match connection.context.borrow().get_state() {
-                PulseState::Ready => {
-                    break;
-                }
-                PulseState::Failed | PulseState::Terminated => {}
-                _ => {}
-            }
+/// A sink or source, by name or by index.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeviceId {
+    Name(String),
+    Index(u32),
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeviceId::Name(name) => write!(f, "{}", name),
+            DeviceId::Index(index) => write!(f, "#{}", index),
         }
+    }
+}
+
+/// Peak levels of a sink or source, measured until dropped.
+pub struct PeakMeter {
+    conn: Arc<PulseAudioConnection>,
+    id: u32,
+    levels: Receiver<f32>,
+}
 
-        Ok(connection)
+impl PeakMeter {
+    /// Peak levels from 0.0 to 1.0, one per measuring period. The channel closes if the stream
+    /// goes away, such as when the source is removed or the server restarts.
+    pub fn levels(&self) -> &Receiver<f32> {
+        &self.levels
     }
+}
 
-    fn iterate(&mut self, blocking: bool) -> Result<(), ::std::io::Error> {
-        match self.mainloop.borrow_mut().iterate(blocking) {
-            IterateResult::Quit(_) | IterateResult::Err(_) => Err(::std::io::Error::new(
-                ::std::io::ErrorKind::Other,
-                "unable to iterate pulseaudio state",
-            )),
-            IterateResult::Success(_) => Ok(()),
-        }
+impl Drop for PeakMeter {
+    fn drop(&mut self) {
+        let _ = self.conn.send(PulseAudioClientRequest::StopMeter(self.id));
     }
 }
 
-impl PulseAudioClient {
-    fn new<F>(listener: F) -> Result<Arc<Mutex<Self>>, ::std::io::Error>
-    where
-        F: Fn(Arc<Mutex<Self>>) -> (),
-        F: Send + 'static + Clone,
-    {
-        let (tx, rx) = channel();
+/// A sink known to the PulseAudio server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PulseAudioSink {
+    pub index: u32,
+    pub name: String,
+    pub description: String,
+    pub volume: f32,
+    pub muted: bool,
+    pub suspended: bool,
+    /// Bluetooth details, completed from the sink's card.
+    pub bluetooth: Option<BluetoothInfo>,
+}
 
-        let client = Arc::new(Mutex::new(PulseAudioClient {
-            sender: tx,
-            default_sink: "@DEFAULT_SINK@".to_string(),
-            sinks: HashMap::new(),
-        }));
+/// A sound card known to the PulseAudio server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PulseAudioCard {
+    pub index: u32,
+    pub name: String,
+    /// The active profile, such as `a2dp-sink` or `output:analog-stereo`.
+    pub profile: Option<String>,
+    pub bluetooth: Option<BluetoothInfo>,
+}
 
-        let loop_client = client.clone();
-        let (tx1, rx1) = channel();
-        let _ = thread::Builder::new()
-            .name("pa_writer".to_string())
-            .spawn(move || {
-                let mut conn = match PulseAudioConnection::new() {
-                    Ok(v) => {
-                        tx1.send(true).unwrap();
-                        v
-                    }
-                    Err(_) => {
-                        tx1.send(false).unwrap();
-                        return;
-                    }
-                };
+/// A source known to the PulseAudio server, including sink monitors.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PulseAudioSource {
+    pub index: u32,
+    pub name: String,
+    pub description: String,
+    pub muted: bool,
+    pub suspended: bool,
+}
 
-                // make sure mainloop dispatched everything
-                for _ in 0..10 {
-                    conn.iterate(false).unwrap();
-                }
+/// A module loaded into the PulseAudio server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PulseAudioModule {
+    pub index: u32,
+    pub name: String,
+    pub argument: String,
+}
 
-                loop {
-                    let cl = loop_client.clone();
-                    let l = listener.clone();
-                    match rx.recv() {
-                        Err(_) => return,
-                        Ok(req) => {
-                            let mut introspector = conn.context.borrow_mut().introspect();
-
-                            match req {
-                                PulseAudioClientRequest::GetDefaultDevice(s) => {
-                                    introspector.get_server_info(move |info| {
-                                        let _res = PulseAudioClient::server_info_callback(
-                                            cl.clone(),
-                                            l.clone(),
-                                            info,
-                                        );
-                                        if let Some(s) = &s {
-                                            let _ = s.send(true);
-                                        }
-                                    });
-                                }
-                                PulseAudioClientRequest::GetSinkInfoByIndex(s, index) => {
-                                    introspector.get_sink_info_by_index(index, move |res| {
-                                        PulseAudioClient::sink_info_callback(
-                                            cl.clone(),
-                                            l.clone(),
-                                            res,
-                                        );
-                                        if let Some(s) = &s {
-                                            let _ = s.send(true);
-                                        }
-                                    });
-                                }
-                                PulseAudioClientRequest::GetSinkInfoByName(s, name) => {
-                                    introspector.get_sink_info_by_name(&name, move |res| {
-                                        PulseAudioClient::sink_info_callback(
-                                            cl.clone(),
-                                            l.clone(),
-                                            res,
-                                        );
-                                        if let Some(s) = &s {
-                                            let _ = s.send(true);
-                                        }
-                                    });
-                                }
-                                PulseAudioClientRequest::SetSinkVolumeByName(s, name, volumes) => {
-                                    introspector.set_sink_volume_by_name(&name, &volumes, None);
-                                    if let Some(s) = &s {
-                                        let _ = s.send(true);
-                                    }
-                                }
-                                PulseAudioClientRequest::SetSinkMuteByName(s, name, mute) => {
-                                    introspector.set_sink_mute_by_name(&name, mute, None);
-                                    if let Some(s) = &s {
-                                        let _ = s.send(true);
-                                    }
-                                }
-                            };
-
-                            // send request and receive response
-                            conn.iterate(true).unwrap();
-                            conn.iterate(true).unwrap();
-                        }
-                    }
-                }
-            });
+/// A module to load, with its arguments.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Module {
+    /// A sink that discards everything played to it, e.g. to record application output through
+    /// its monitor.
+    NullSink {
+        name: String,
+        description: Option<String>,
+        channels: Option<u8>,
+    },
+    /// Play a source to a sink, e.g. a microphone to headphones. Unset ends use the defaults.
+    Loopback {
+        source: Option<String>,
+        sink: Option<String>,
+        latency_msec: Option<u32>,
+    },
+    /// A sink that plays to several sinks at once.
+    CombineSink {
+        name: String,
+        description: Option<String>,
+        sinks: Vec<String>,
+    },
+    /// Any other module, with its raw argument string.
+    Other { name: String, argument: String },
+}
 
-        // subscribe
-        let cl2 = client.clone();
-        let (tx2, rx2) = channel();
-        let _ = thread::Builder::new()
-            .name("pa_reader".to_string())
-            .spawn(move || {
-                let conn = match PulseAudioConnection::new() {
-                    Ok(v) => {
-                        tx2.send(true).unwrap();
-                        v
-                    }
-                    Err(_) => {
-                        tx2.send(false).unwrap();
-                        return;
-                    }
-                };
-                // subcribe for events
-                conn.context
-                    .borrow_mut()
-                    .set_subscribe_callback(Some(Box::new(move |facility, operation, index| {
-                        cl2.lock()
-                            .unwrap()
-                            .subscribe_callback(facility, operation, index)
-                    })));
-                conn.context.borrow_mut().subscribe(
-                    subscription_masks::SERVER | subscription_masks::SINK,
-                    |_| {},
-                );
+// Quote a module argument value if needed, as parsed by pa_modargs.
+fn modarg(key: &str, value: &str) -> String {
+    if !value.is_empty() && !value.contains(|c: char| c.is_whitespace() || "\"'\\".contains(c)) {
+        return format!("{}={}", key, value);
+    }
+    let mut quoted = String::with_capacity(value.len() + 2);
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    format!("{}=\"{}\"", key, quoted)
+}
 
-                conn.mainloop.borrow_mut().run().unwrap();
-            });
+impl Module {
+    /// The name of the module, such as `module-null-sink`.
+    pub fn name(&self) -> &str {
+        match self {
+            Module::NullSink { .. } => "module-null-sink",
+            Module::Loopback { .. } => "module-loopback",
+            Module::CombineSink { .. } => "module-combine-sink",
+            Module::Other { name, .. } => name,
+        }
+    }
+
+    /// The argument string passed to the module.
+    pub fn argument(&self) -> String {
+        let description = |description: &Option<String>| {
+            description
+                .as_ref()
+                .map(|d| modarg("sink_properties", &modarg(properties::DEVICE_DESCRIPTION, d)))
+        };
+        let args = match self {
+            Module::NullSink {
+                name,
+                description: desc,
+                channels,
+            } => vec![
+                Some(modarg("sink_name", name)),
+                description(desc),
+                channels.map(|c| format!("channels={}", c)),
+            ],
+            Module::Loopback {
+                source,
+                sink,
+                latency_msec,
+            } => vec![
+                source.as_ref().map(|s| modarg("source", s)),
+                sink.as_ref().map(|s| modarg("sink", s)),
+                latency_msec.map(|l| format!("latency_msec={}", l)),
+            ],
+            Module::CombineSink {
+                name,
+                description: desc,
+                sinks,
+            } => vec![
+                Some(modarg("sink_name", name)),
+                description(desc),
+                Some(modarg("slaves", &sinks.join(","))),
+            ],
+            Module::Other { argument, .. } => return argument.clone(),
+        };
+        args.into_iter().flatten().collect::<Vec<_>>().join(" ")
+    }
+}
 
-        let pa_writer_res = rx1.recv().unwrap();
-        let pa_reader_res = rx2.recv().unwrap();
+#[derive(Debug)]
+struct PulseAudioSoundDeviceInner {
+    selector: SinkSelector,
+    name: Option<String>,
+    volume: Option<ChannelVolumes>,
+    channel_map: Option<Map>,
+    base_volume: Option<Volume>,
+    volume_avg: f32,
+    balance: f32,
+    fade: f32,
+    muted: bool,
+    default_sink: String
+}
 
-        if !pa_writer_res || !pa_reader_res {
-            return Err(::std::io::Error::new(
-                ::std::io::ErrorKind::Other,
-                "unable to start pulseaudio thread",
-            ));
-        }
+pub struct PulseAudioSoundDevice {
+    conn: Arc<PulseAudioConnection>,
+    client: Arc<Mutex<PulseAudioClient>>,
+    inner: Arc<Mutex<PulseAudioSoundDeviceInner>>,
+    block: bool,
+    scale: VolumeScale,
+    policy: VolumePolicy,
+    feedback: Option<Feedback>,
+    listeners: Arc<Mutex<Vec<Listener>>>,
+}
 
-        Ok(client)
+// Create a context on the mainloop and start connecting it, reporting state changes to `state`.
+fn new_context(
+    mainloop: &Mainloop,
+    options: &PulseAudioOptions,
+    state: Sender<()>,
+) -> Result<Context, ::std::io::Error> {
+    let mut proplist = Proplist::new().unwrap();
+    proplist
+        .set_str(properties::APPLICATION_NAME, &options.app_name)
+        .map_err(|_e| {
+            ::std::io::Error::new(
+                ::std::io::ErrorKind::InvalidInput,
+                "invalid application name",
+            )
+        })?;
+    for (key, value) in &options.properties {
+        proplist.set_str(key, value).map_err(|_e| {
+            ::std::io::Error::new(
+                ::std::io::ErrorKind::InvalidInput,
+                format!("invalid property {}", key),
+            )
+        })?;
     }
 
-    fn send(&self, request: PulseAudioClientRequest) -> Result<(), ::std::io::Error> {
-        let res = self.sender.send(request).map_err(|_e| {
+    let mut context = Context::new_with_proplist(mainloop, &options.app_name, &proplist)
+        .ok_or_else(|| {
+            ::std::io::Error::new(
+                ::std::io::ErrorKind::Other,
+                "unable to create pulseaudio context",
+            )
+        })?;
+    context.set_state_callback(Some(Box::new(move || {
+        let _ = state.send(());
+    })));
+
+    // Spawning a local daemon makes no sense when a specific server was asked for.
+    let connect_flags = match options.server {
+        Some(_) => flags::NOAUTOSPAWN,
+        None => flags::NOFLAGS,
+    };
+    context
+        .connect(options.server.as_deref(), connect_flags, None)
+        .map_err(|_e| {
+            ::std::io::Error::new(
+                ::std::io::ErrorKind::Other,
+                "unable to connect to pulseaudio context",
+            )
+        })?;
+    Ok(context)
+}
+
+impl PulseAudioConnection {
+    fn new(
+        options: PulseAudioOptions,
+        client: Arc<Mutex<PulseAudioClient>>,
+        listener: ClientListener,
+    ) -> Result<Arc<Self>, ::std::io::Error> {
+        let mut mainloop = Mainloop::new().ok_or_else(|| {
+            ::std::io::Error::new(
+                ::std::io::ErrorKind::Other,
+                "unable to create pulseaudio mainloop",
+            )
+        })?;
+
+        let (tx, rx) = channel();
+        let context = Rc::new(RefCell::new(new_context(&mainloop, &options, tx.clone())?));
+        mainloop.start().map_err(|_e| {
             ::std::io::Error::new(
                 ::std::io::ErrorKind::Other,
-                "unable to send pulseaudio request",
+                "unable to start pulseaudio mainloop",
             )
+        })?;
+
+        let conn = Arc::new(PulseAudioConnection {
+            handle: Confined(PulseAudioHandle {
+                context: Rc::downgrade(&context),
+                client: client.clone(),
+                listener: listener.clone(),
+                uploads: Rc::new(RefCell::new(Vec::new())),
+                meters: Rc::new(RefCell::new(HashMap::new())),
+            }),
+            context: Confined(context),
+            client,
+            listener,
+            options,
+            samples: Mutex::new(HashMap::new()),
+            state: tx,
+            mainloop: Mutex::new(SharedMainloop(mainloop)),
         });
-        res
+        conn.wait_ready(&rx)?;
+        conn.subscribe();
+
+        // Watch for the server going away, and reconnect once it is back.
+        let watched = Arc::downgrade(&conn);
+        thread::Builder::new()
+            .name("pa_watchdog".to_string())
+            .spawn(move || {
+                for _ in rx.iter() {
+                    let state = match watched.upgrade() {
+                        Some(conn) => conn.state(),
+                        None => return,
+                    };
+                    match state {
+                        PulseState::Failed | PulseState::Terminated => {
+                            PulseAudioConnection::reconnect(&watched, &rx)
+                        }
+                        _ => {}
+                    }
+                }
+            })?;
+
+        Ok(conn)
     }
 
-    fn server_info_callback<F>(s: Arc<Mutex<Self>>, listener: F, server_info: &ServerInfo)
+    // Run with the mainloop lock held.
+    fn locked<F, R>(&self, f: F) -> R
     where
-        F: Fn(Arc<Mutex<Self>>) -> (),
-        F: Send + 'static,
+        F: FnOnce(&mut Context, &PulseAudioHandle) -> R,
+        R: Send,
     {
-        match server_info.default_sink_name.clone() {
-            None => {}
-            Some(default_sink) => {
-                (*s.lock().unwrap()).default_sink = default_sink.into();
-                listener(s);
-            }
-        }
+        self.with_mainloop(|_, context, handle| f(context, handle))
     }
 
-    fn sink_info_callback<F>(s: Arc<Mutex<Self>>, listener: F, result: ListResult<&SinkInfo>)
+    // Like `locked`, also passing the mainloop. Results must be `Send`, so that nothing holding
+    // an `Rc` shared with the callbacks, such as a clone of the handle, outlives the lock.
+    fn with_mainloop<F, R>(&self, f: F) -> R
     where
-        F: Fn(Arc<Mutex<Self>>) -> (),
-        F: Send + 'static,
+        F: FnOnce(&Mainloop, &mut Context, &PulseAudioHandle) -> R,
+        R: Send,
     {
-        match result {
-            ListResult::End | ListResult::Error => {}
-            ListResult::Item(sink_info) => match sink_info.name.clone() {
-                None => {}
-                Some(name) => {
-                    let info = PulseAudioSinkInfo {
-                        volume: sink_info.volume,
-                        mute: sink_info.mute,
-                    };
-                    s.lock().unwrap().sinks.insert(name.into(), info);
-                    listener(s);
-                }
-            },
-        }
+        let mut mainloop = self.mainloop.lock().unwrap();
+        mainloop.0.lock();
+        let res = {
+            // Safety: the marker goes out of scope before the lock is released.
+            let lock = unsafe { MainloopLock::assume_held() };
+            let mut context = self.context.get(&lock).borrow_mut();
+            f(&mainloop.0, &mut context, self.handle.get(&lock))
+        };
+        mainloop.0.unlock();
+        res
     }
 
-    fn subscribe_callback(
-        &self,
-        facility: Option<Facility>,
-        _operation: Option<SubscribeOperation>,
-        index: u32,
-    ) {
-        match facility {
-            None => {}
-            Some(facility) => match facility {
-                Facility::Server => {
-                    let _ = self.send(PulseAudioClientRequest::GetDefaultDevice(None));
-                }
-                Facility::Sink => {
-                    let _ = self.send(PulseAudioClientRequest::GetSinkInfoByIndex(None, index));
+    fn state(&self) -> PulseState {
+        self.locked(|context, _| context.get_state())
+    }
+
+    fn wait_ready(&self, state: &Receiver<()>) -> Result<(), ::std::io::Error> {
+        loop {
+            match self.state() {
+                PulseState::Ready => return Ok(()),
+                PulseState::Failed | PulseState::Terminated => {
+                    return Err(::std::io::Error::new(
+                        ::std::io::ErrorKind::Other,
+                        "pulseaudio context failed",
+                    ));
                 }
                 _ => {}
-            },
+            }
+            state.recv().map_err(|_e| {
+                ::std::io::Error::new(
+                    ::std::io::ErrorKind::Other,
+                    "pulseaudio context went away",
+                )
+            })?;
         }
     }
-}
 
-impl PulseAudioSoundDevice {
-    pub fn new<F>(listener: F, block: bool) -> Result<Self, ::std::io::Error>
-    where
-        F: Fn() -> (),
-        F: Send + 'static + Clone,
-    {
-        let inner = Arc::new(Mutex::new(PulseAudioSoundDeviceInner {
-            name: None,
-            volume: None,
-            volume_avg: 0.0,
-            muted: false,
-            default_sink: "@DEFAULT_SINK@".to_string(),
-        }));
+    fn subscribe(&self) {
+        self.locked(|context, handle| {
+            let handle = handle.clone();
+            context.set_subscribe_callback(Some(Box::new(move |facility, operation, index| {
+                PulseAudioClient::subscribe_callback(&handle, facility, operation, index)
+            })));
+            context.subscribe(
+                subscription_masks::SERVER
+                    | subscription_masks::SINK
+                    | subscription_masks::SOURCE
+                    | subscription_masks::CARD,
+                |_| {},
+            );
+        });
+        // Know every sink and source up front, so that their first state change is reported.
+        let _ = self.send(PulseAudioClientRequest::GetCardInfoList(None));
+        let _ = self.send(PulseAudioClientRequest::GetSinkInfoList(None));
+        let _ = self.send(PulseAudioClientRequest::GetSourceInfoList(None));
+    }
 
-        let cb_inner = inner.clone();
-        let client = PulseAudioClient::new(move |client| {
-            let mut inner = cb_inner.lock().unwrap();
-            inner.default_sink = client.lock().unwrap().default_sink.clone();
-            let name = inner
-                .name
-                .clone()
-                .unwrap_or_else(|| inner.default_sink.clone());
-            let sink_info = match client.lock().unwrap().sinks.get(&name) {
+    // Replace the failed context, retrying until the server is back, and resynchronize. The
+    // connection is only held while trying, so that dropping it in the meantime stops the retries.
+    fn reconnect(watched: &::std::sync::Weak<Self>, state: &Receiver<()>) {
+        let conn = loop {
+            thread::sleep(RECONNECT_INTERVAL);
+            let conn = match watched.upgrade() {
+                Some(conn) => conn,
                 None => return,
-                Some(sink_info) => (*sink_info).clone(),
             };
-
-            inner.volume = Some(sink_info.volume);
-            inner.volume_avg = sink_info.volume.avg().0 as f32 / VOLUME_NORM.0 as f32;
-            inner.muted = sink_info.mute;
-
-            listener();
-        })?;
-
-        let cl = client.clone();
-        let (tx, rx) = channel();
-        {
-            let cl = client.lock().unwrap();
-            cl.send(PulseAudioClientRequest::GetDefaultDevice(Some(tx)))?;
-        }
-        rx.recv().unwrap();
-        let name = {
-            let cl = client.lock().unwrap();
-            cl.default_sink.to_string()
+            let res = conn.with_mainloop(|mainloop, context, _| {
+                new_context(mainloop, &conn.options, conn.state.clone())
+                    .map(|new| *context = new)
+            });
+            if res.and_then(|_| conn.wait_ready(state)).is_ok() {
+                break conn;
+            }
         };
-        (*inner.lock().unwrap()).name = Some(name.clone());
-        let device = PulseAudioSoundDevice {
-            client: cl,
-            inner: inner,
-            block,
+        conn.resync();
+    }
+
+    // Forget what was known about the old server, and fetch everything again.
+    fn resync(&self) {
+        let client = self.client.clone();
+        let default_sink = {
+            let mut client = client.lock().unwrap();
+            client.sinks.clear();
+            client.sink_names.clear();
+            client.sources.clear();
+            client.cards.clear();
+            client.default_sink.clone()
         };
-        let (tx, rx) = channel();
-        {
-            let cl = client.lock().unwrap();
-            cl.send(PulseAudioClientRequest::GetSinkInfoByName(Some(tx), name))?;
+        self.subscribe();
+        let _ = self.send(PulseAudioClientRequest::GetDefaultDevice(None));
+        let _ = self.send(PulseAudioClientRequest::GetSinkInfoByName(None, default_sink));
+        let samples = self.samples.lock().unwrap().clone();
+        for (name, sample) in samples {
+            let _ = self.send(PulseAudioClientRequest::UploadSample(None, name, sample));
         }
-        rx.recv().unwrap();
-
-        Ok(device)
+        (self.listener)(client, Some(AudioEvent::ServerRestarted));
     }
 
-    pub fn volume(&self) -> f32 {
-        let inner = self.inner.lock().unwrap();
-        inner.volume_avg
+    fn send(&self, request: PulseAudioClientRequest) -> Result<(), ::std::io::Error> {
+        self.locked(|context, handle| handle.dispatch(context, request))
     }
+}
 
-    pub fn muted(&self) -> bool {
-        let inner = self.inner.lock().unwrap();
-        inner.muted
+impl Drop for PulseAudioConnection {
+    fn drop(&mut self) {
+        let mainloop = &mut self.mainloop.get_mut().unwrap().0;
+        mainloop.lock();
+        {
+            // Safety: the marker goes out of scope before the lock is released.
+            let lock = unsafe { MainloopLock::assume_held() };
+            self.context.get(&lock).borrow_mut().disconnect();
+        }
+        mainloop.unlock();
+        mainloop.stop();
     }
+}
 
-    pub fn add_volume(&mut self, step: f32, cap: Option<f32>) -> Result<(), ::std::io::Error> {
-       let mut inner = self.inner.lock().unwrap();
-        let mut volume = match inner.volume {
-            Some(volume) => volume,
-            None => {
-                return Err(::std::io::Error::new(
-                    ::std::io::ErrorKind::Other,
-                    "unable to set volume",
-                ))
-            }
-        };
+impl PulseAudioHandle {
+    // Send a request from a callback, which already holds the mainloop lock.
+    fn send(&self, request: PulseAudioClientRequest) {
+        if let Some(context) = self.context.upgrade() {
+            let _ = self.dispatch(&mut context.borrow_mut(), request);
+        }
+    }
 
-        // apply step to volumes
-        let step = (step * VOLUME_NORM.0 as f32).round() as i32;
-        if step > 0 {
-            if let Some(cap) = cap {
-                let cap = (cap * VOLUME_NORM.0 as f32).round() as u32;
-                volume.inc_clamp(Volume(step as u32), Volume(cap));
-            } else {
-                volume.increase(Volume(step as u32));
+    fn dispatch(
+        &self,
+        context: &mut Context,
+        request: PulseAudioClientRequest,
+    ) -> Result<(), ::std::io::Error> {
+        if context.get_state() != PulseState::Ready {
+            return Err(::std::io::Error::new(
+                ::std::io::ErrorKind::Other,
+                "pulseaudio connection lost",
+            ));
+        }
+
+        let mut introspector = context.introspect();
+        let handle = self.clone();
+        match request {
+            PulseAudioClientRequest::GetDefaultDevice(mut s) => {
+                introspector.get_server_info(move |info| {
+                    PulseAudioClient::server_info_callback(&handle, info);
+                    if let Some(done) = s.take() {
+                        done(true);
+                    }
+                });
+            }
+            PulseAudioClientRequest::GetSinkInfoByIndex(mut s, index, added) => {
+                introspector.get_sink_info_by_index(index, move |res| {
+                    let done = match res {
+                        ListResult::Item(_) => false,
+                        _ => true,
+                    };
+                    PulseAudioClient::sink_info_callback(&handle, res, added);
+                    if done {
+                        if let Some(done) = s.take() {
+                            done(true);
+                        }
+                    }
+                });
+            }
+            PulseAudioClientRequest::GetSinkInfoByName(mut s, name) => {
+                introspector.get_sink_info_by_name(&name, move |res| {
+                    let done = match res {
+                        ListResult::Item(_) => false,
+                        _ => true,
+                    };
+                    PulseAudioClient::sink_info_callback(&handle, res, false);
+                    if done {
+                        if let Some(done) = s.take() {
+                            done(true);
+                        }
+                    }
+                });
+            }
+            PulseAudioClientRequest::GetSinkInfoList(mut s) => {
+                introspector.get_sink_info_list(move |res| {
+                    let done = match res {
+                        ListResult::Item(_) => false,
+                        _ => true,
+                    };
+                    PulseAudioClient::sink_info_callback(&handle, res, false);
+                    if done {
+                        if let Some(done) = s.take() {
+                            done(true);
+                        }
+                    }
+                });
+            }
+            PulseAudioClientRequest::SetSinkVolumeByName(mut s, name, volumes, mut cue) => {
+                let sink = name.clone();
+                introspector.set_sink_volume_by_name(
+                    &name,
+                    &volumes,
+                    Some(Box::new(move |success| {
+                        // Only a change the server accepted is worth hearing.
+                        if let (true, Some(cue)) = (success, cue.take()) {
+                            cue.play(&handle, sink.clone());
+                        }
+                        if let Some(done) = s.take() {
+                            done(success);
+                        }
+                    })),
+                );
+            }
+            PulseAudioClientRequest::SetSinkMuteByName(mut s, name, mute) => {
+                introspector.set_sink_mute_by_name(
+                    &name,
+                    mute,
+                    Some(Box::new(move |success| {
+                        if let Some(done) = s.take() {
+                            done(success);
+                        }
+                    })),
+                );
+            }
+            PulseAudioClientRequest::UploadSample(s, name, sample) => {
+                self.upload(context, s, &name, sample)?;
+            }
+            PulseAudioClientRequest::StartMeter(s, id, source, rate, levels) => {
+                self.start_meter(context, s, id, &source, rate, levels)?;
+            }
+            PulseAudioClientRequest::StopMeter(id) => {
+                if let Some(stream) = self.meters.borrow_mut().remove(&id) {
+                    let _ = stream.borrow_mut().disconnect();
+                }
+            }
+            PulseAudioClientRequest::LoadModule(index, name, argument) => {
+                introspector.load_module(&name, &argument, move |res| {
+                    let _ = index.send(res);
+                });
+            }
+            PulseAudioClientRequest::UnloadModule(mut s, index) => {
+                introspector.unload_module(index, move |success| {
+                    if let Some(done) = s.take() {
+                        done(success);
+                    }
+                });
+            }
+            PulseAudioClientRequest::GetModuleInfoList(modules) => {
+                let mut list = Vec::new();
+                let mut modules = Some(modules);
+                introspector.get_module_info_list(move |res| match res {
+                    ListResult::Item(info) => list.push(PulseAudioModule {
+                        index: info.index,
+                        name: info.name.as_ref().map(|n| n.to_string()).unwrap_or_default(),
+                        argument: info
+                            .argument
+                            .as_ref()
+                            .map(|a| a.to_string())
+                            .unwrap_or_default(),
+                    }),
+                    ListResult::End => {
+                        if let Some(modules) = modules.take() {
+                            let _ = modules.send(::std::mem::take(&mut list));
+                        }
+                    }
+                    // Dropping the sender fails the request.
+                    ListResult::Error => {
+                        modules.take();
+                    }
+                });
+            }
+            PulseAudioClientRequest::GetSourceInfoByIndex(mut s, index) => {
+                introspector.get_source_info_by_index(index, move |res| {
+                    let done = match res {
+                        ListResult::Item(_) => false,
+                        _ => true,
+                    };
+                    PulseAudioClient::source_info_callback(&handle, res);
+                    if done {
+                        if let Some(done) = s.take() {
+                            done(true);
+                        }
+                    }
+                });
+            }
+            PulseAudioClientRequest::GetSourceInfoList(mut s) => {
+                introspector.get_source_info_list(move |res| {
+                    let done = match res {
+                        ListResult::Item(_) => false,
+                        _ => true,
+                    };
+                    PulseAudioClient::source_info_callback(&handle, res);
+                    if done {
+                        if let Some(done) = s.take() {
+                            done(true);
+                        }
+                    }
+                });
+            }
+            PulseAudioClientRequest::SuspendSink(mut s, sink, suspend) => {
+                let callback = Some(Box::new(move |success| {
+                    if let Some(done) = s.take() {
+                        done(success);
+                    }
+                }) as Box<dyn FnMut(bool)>);
+                match sink {
+                    DeviceId::Index(index) => {
+                        introspector.suspend_sink_by_index(index, suspend, callback)
+                    }
+                    DeviceId::Name(name) => {
+                        introspector.suspend_sink_by_name(&name, suspend, callback)
+                    }
+                };
+            }
+            PulseAudioClientRequest::SuspendSource(mut s, source, suspend) => {
+                let callback = Some(Box::new(move |success| {
+                    if let Some(done) = s.take() {
+                        done(success);
+                    }
+                }) as Box<dyn FnMut(bool)>);
+                match source {
+                    DeviceId::Index(index) => {
+                        introspector.suspend_source_by_index(index, suspend, callback)
+                    }
+                    DeviceId::Name(name) => {
+                        introspector.suspend_source_by_name(&name, suspend, callback)
+                    }
+                };
+            }
+            PulseAudioClientRequest::GetCardInfoByIndex(mut s, index) => {
+                introspector.get_card_info_by_index(index, move |res| {
+                    let done = match res {
+                        ListResult::Item(_) => false,
+                        _ => true,
+                    };
+                    PulseAudioClient::card_info_callback(&handle, res);
+                    if done {
+                        if let Some(done) = s.take() {
+                            done(true);
+                        }
+                    }
+                });
+            }
+            PulseAudioClientRequest::GetCardInfoList(mut s) => {
+                introspector.get_card_info_list(move |res| {
+                    let done = match res {
+                        ListResult::Item(_) => false,
+                        _ => true,
+                    };
+                    PulseAudioClient::card_info_callback(&handle, res);
+                    if done {
+                        if let Some(done) = s.take() {
+                            done(true);
+                        }
+                    }
+                });
+            }
+            PulseAudioClientRequest::SetCardProfileByName(mut s, card, profile) => {
+                introspector.set_card_profile_by_name(
+                    &card,
+                    &profile,
+                    Some(Box::new(move |success| {
+                        if let Some(done) = s.take() {
+                            done(success);
+                        }
+                    })),
+                );
+            }
+            PulseAudioClientRequest::SetSinkPortByName(mut s, name, port) => {
+                introspector.set_sink_port_by_name(
+                    &name,
+                    &port,
+                    Some(Box::new(move |success| {
+                        if let Some(done) = s.take() {
+                            done(success);
+                        }
+                    })),
+                );
+            }
+            PulseAudioClientRequest::SetSourcePortByName(mut s, name, port) => {
+                introspector.set_source_port_by_name(
+                    &name,
+                    &port,
+                    Some(Box::new(move |success| {
+                        if let Some(done) = s.take() {
+                            done(success);
+                        }
+                    })),
+                );
+            }
+            PulseAudioClientRequest::SetSourceVolumeByName(mut s, name, volumes) => {
+                introspector.set_source_volume_by_name(
+                    &name,
+                    &volumes,
+                    Some(Box::new(move |success| {
+                        if let Some(done) = s.take() {
+                            done(success);
+                        }
+                    })),
+                );
+            }
+            PulseAudioClientRequest::SetSourceMuteByName(mut s, name, mute) => {
+                introspector.set_source_mute_by_name(
+                    &name,
+                    mute,
+                    Some(Box::new(move |success| {
+                        if let Some(done) = s.take() {
+                            done(success);
+                        }
+                    })),
+                );
+            }
+            PulseAudioClientRequest::SetDefaultSink(mut s, name) => {
+                context.set_default_sink(&name, move |success| {
+                    if let Some(done) = s.take() {
+                        done(success);
+                    }
+                });
+            }
+            PulseAudioClientRequest::SetDefaultSource(mut s, name) => {
+                context.set_default_source(&name, move |success| {
+                    if let Some(done) = s.take() {
+                        done(success);
+                    }
+                });
+            }
+            PulseAudioClientRequest::GetSinkInputInfoList(inputs) => {
+                let mut list = Vec::new();
+                let mut inputs = Some(inputs);
+                introspector.get_sink_input_info_list(move |res| match res {
+                    ListResult::Item(info) => list.push((info.index, info.sink)),
+                    ListResult::End => {
+                        if let Some(inputs) = inputs.take() {
+                            let _ = inputs.send(::std::mem::take(&mut list));
+                        }
+                    }
+                    // Dropping the sender fails the request.
+                    ListResult::Error => {
+                        inputs.take();
+                    }
+                });
+            }
+            PulseAudioClientRequest::MoveSinkInputByName(mut s, index, sink) => {
+                introspector.move_sink_input_by_name(
+                    index,
+                    &sink,
+                    Some(Box::new(move |success| {
+                        if let Some(done) = s.take() {
+                            done(success);
+                        }
+                    })),
+                );
+            }
+            PulseAudioClientRequest::PlaySample(mut s, name, sink) => {
+                context.play_sample(
+                    &name,
+                    Some(&sink),
+                    None,
+                    Some(Box::new(move |success| {
+                        if let Some(done) = s.take() {
+                            done(success);
+                        }
+                    })),
+                );
+            }
+        };
+        Ok(())
+    }
+
+    // Record a source through a peak detecting stream, which the server feeds one peak value per
+    // fragment, `rate` times per second.
+    fn start_meter(
+        &self,
+        context: &mut Context,
+        mut s: Option<Completion>,
+        id: u32,
+        source: &str,
+        rate: u32,
+        levels: Sender<f32>,
+    ) -> Result<(), ::std::io::Error> {
+        let meter_error = || {
+            ::std::io::Error::new(
+                ::std::io::ErrorKind::Other,
+                "unable to create peak meter stream",
+            )
+        };
+        let spec = Spec {
+            format: Format::F32le,
+            rate,
+            channels: 1,
+        };
+        let mut stream = Stream::new(context, "Peak meter", &spec, None).ok_or_else(meter_error)?;
+        let attr = BufferAttr {
+            maxlength: ::std::u32::MAX,
+            tlength: 0,
+            prebuf: 0,
+            minreq: 0,
+            fragsize: ::std::mem::size_of::<f32>() as u32,
+        };
+        stream
+            .connect_record(
+                Some(source),
+                Some(&attr),
+                libpulse_binding::stream::flags::PEAK_DETECT
+                    | libpulse_binding::stream::flags::ADJUST_LATENCY,
+            )
+            .map_err(|_e| meter_error())?;
+
+        // Shared between the callbacks, so that the channel closes once the stream is gone.
+        let levels = Rc::new(RefCell::new(Some(levels)));
+        let stream = Rc::new(RefCell::new(stream));
+
+        let weak = Rc::downgrade(&stream);
+        let read_levels = levels.clone();
+        stream.borrow_mut().set_read_callback(Some(Box::new(move |_| {
+            let stream = match weak.upgrade() {
+                Some(stream) => stream,
+                None => return,
+            };
+            let mut stream = match stream.try_borrow_mut() {
+                Ok(stream) => stream,
+                Err(_) => return,
+            };
+            loop {
+                let level = match stream.peek() {
+                    Ok(PeekResult::Data(data)) if data.len() >= 4 => {
+                        let last = &data[data.len() - 4..];
+                        Some(f32::from_le_bytes([last[0], last[1], last[2], last[3]]))
+                    }
+                    Ok(PeekResult::Data(_)) | Ok(PeekResult::Hole(_)) => None,
+                    Ok(PeekResult::Empty) | Err(_) => return,
+                };
+                let _ = stream.discard();
+                if let (Some(level), Some(levels)) = (level, &*read_levels.borrow()) {
+                    let _ = levels.send(level.clamp(0.0, 1.0));
+                }
+            }
+        })));
+
+        let weak = Rc::downgrade(&stream);
+        stream
+            .borrow_mut()
+            .set_state_callback(Some(Box::new(move || {
+                let stream = match weak.upgrade() {
+                    Some(stream) => stream,
+                    None => return,
+                };
+                let state = match stream.try_borrow() {
+                    Ok(stream) => stream.get_state(),
+                    Err(_) => return,
+                };
+                let success = match state {
+                    StreamState::Ready => true,
+                    StreamState::Failed | StreamState::Terminated => {
+                        levels.borrow_mut().take();
+                        false
+                    }
+                    _ => return,
+                };
+                if let Some(done) = s.take() {
+                    done(success);
+                }
+            })));
+        self.meters.borrow_mut().insert(id, stream);
+        Ok(())
+    }
+
+    // Upload a sample to the sample cache through an upload stream, which is written to once the
+    // server has accepted it.
+    fn upload(
+        &self,
+        context: &mut Context,
+        mut s: Option<Completion>,
+        name: &str,
+        sample: Arc<WavSample>,
+    ) -> Result<(), ::std::io::Error> {
+        // Streams that are done can be dropped now that their callbacks are not running.
+        self.uploads.borrow_mut().retain(|stream| match stream.borrow().get_state() {
+            StreamState::Terminated | StreamState::Failed => false,
+            _ => true,
+        });
+
+        let spec = Spec {
+            format: match sample.format {
+                SampleFormat::U8 => Format::U8,
+                SampleFormat::S16le => Format::S16le,
+                SampleFormat::S24le => Format::S24le,
+                SampleFormat::S32le => Format::S32le,
+                SampleFormat::F32le => Format::F32le,
+            },
+            rate: sample.rate,
+            channels: sample.channels,
+        };
+        let upload_error = || {
+            ::std::io::Error::new(
+                ::std::io::ErrorKind::Other,
+                "unable to upload sample",
+            )
+        };
+        let mut stream = Stream::new(context, name, &spec, None).ok_or_else(upload_error)?;
+        stream
+            .connect_upload(sample.data.len())
+            .map_err(|_e| upload_error())?;
+
+        let stream = Rc::new(RefCell::new(stream));
+        let weak = Rc::downgrade(&stream);
+        stream
+            .borrow_mut()
+            .set_state_callback(Some(Box::new(move || {
+                let stream = match weak.upgrade() {
+                    Some(stream) => stream,
+                    None => return,
+                };
+                let mut stream = match stream.try_borrow_mut() {
+                    Ok(stream) => stream,
+                    Err(_) => return,
+                };
+                let success = match stream.get_state() {
+                    StreamState::Ready => {
+                        let res = stream
+                            .write(&sample.data, None, 0, SeekMode::Relative)
+                            .and_then(|_| stream.finish_upload());
+                        if res.is_ok() {
+                            return;
+                        }
+                        false
+                    }
+                    StreamState::Terminated => true,
+                    StreamState::Failed => false,
+                    _ => return,
+                };
+                if let Some(done) = s.take() {
+                    done(success);
+                }
+            })));
+        self.uploads.borrow_mut().push(stream);
+        Ok(())
+    }
+}
+
+impl PulseAudioClient {
+    fn server_info_callback(handle: &PulseAudioHandle, server_info: &ServerInfo) {
+        if let Some(default_source) = &server_info.default_source_name {
+            let default_source = default_source.to_string();
+            let changed = {
+                let mut client = handle.client.lock().unwrap();
+                let changed = client.default_source != default_source;
+                client.default_source = default_source.clone();
+                changed
+            };
+            if changed {
+                (handle.listener)(
+                    handle.client.clone(),
+                    Some(AudioEvent::DefaultSourceChanged {
+                        source: default_source,
+                    }),
+                );
+            }
+        }
+        match server_info.default_sink_name.clone() {
+            None => {}
+            Some(default_sink) => {
+                let default_sink: String = default_sink.into();
+                let changed = {
+                    let mut client = handle.client.lock().unwrap();
+                    let changed = client.default_sink != default_sink;
+                    client.default_sink = default_sink.clone();
+                    if changed && !client.sinks.contains_key(&default_sink) {
+                        handle.send(PulseAudioClientRequest::GetSinkInfoByName(
+                            None,
+                            default_sink.clone(),
+                        ));
+                    }
+                    changed
+                };
+                if changed {
+                    (handle.listener)(
+                        handle.client.clone(),
+                        Some(AudioEvent::DefaultSinkChanged { sink: default_sink }),
+                    );
+                }
             }
-        } else {
-            volume.decrease(Volume(-step as u32));
         }
+    }
+
+    fn sink_info_callback(handle: &PulseAudioHandle, result: ListResult<&SinkInfo>, added: bool) {
+        match result {
+            ListResult::End | ListResult::Error => {}
+            ListResult::Item(sink_info) => match sink_info.name.clone() {
+                None => {}
+                Some(name) => {
+                    let name: String = name.into();
+                    let info = PulseAudioSinkInfo {
+                        index: sink_info.index,
+                        description: sink_info
+                            .description
+                            .clone()
+                            .map(|d| d.into_owned())
+                            .unwrap_or_default(),
+                        volume: sink_info.volume,
+                        channel_map: sink_info.channel_map,
+                        base_volume: sink_info.base_volume,
+                        mute: sink_info.mute,
+                        suspended: sink_info.state == SinkState::Suspended,
+                        active_port: sink_info
+                            .active_port
+                            .as_ref()
+                            .and_then(|port| port.name.clone())
+                            .map(|port| port.into_owned()),
+                        properties: sink_info
+                            .proplist
+                            .iter()
+                            .filter_map(|key| {
+                                let value = sink_info.proplist.get_str(&key)?;
+                                Some((key, value))
+                            })
+                            .collect(),
+                        card: sink_info.card,
+                        bluetooth: BluetoothInfo::from_properties(|key| {
+                            sink_info.proplist.get_str(key)
+                        }),
+                    };
+
+                    let mut events = Vec::new();
+                    let old = {
+                        let mut client = handle.client.lock().unwrap();
+                        client.sink_names.insert(sink_info.index, name.clone());
+                        client.sinks.insert(name.clone(), info.clone())
+                    };
+                    if added {
+                        events.push(AudioEvent::SinkAdded {
+                            index: sink_info.index,
+                            sink: name.clone(),
+                        });
+                    }
+                    if let Some(old) = old {
+                        if old.volume != info.volume {
+                            events.push(AudioEvent::VolumeChanged {
+                                sink: name.clone(),
+                                volume: info.volume.avg().0 as f32 / VOLUME_NORM.0 as f32,
+                            });
+                        }
+                        if old.mute != info.mute {
+                            events.push(AudioEvent::MuteChanged {
+                                sink: name.clone(),
+                                muted: info.mute,
+                            });
+                        }
+                        if old.suspended != info.suspended {
+                            events.push(AudioEvent::SinkSuspended {
+                                sink: name.clone(),
+                                suspended: info.suspended,
+                            });
+                        }
+                        if let (Some(old), Some(new)) = (&old.bluetooth, &info.bluetooth) {
+                            events.extend(new.changes(old, &name));
+                        }
+                    }
+
+                    if events.is_empty() {
+                        (handle.listener)(handle.client.clone(), None);
+                    } else {
+                        for event in events {
+                            (handle.listener)(handle.client.clone(), Some(event));
+                        }
+                    }
+                }
+            },
+        }
+    }
+
+    fn source_info_callback(handle: &PulseAudioHandle, result: ListResult<&SourceInfo>) {
+        let source_info = match result {
+            ListResult::Item(source_info) => source_info,
+            ListResult::End | ListResult::Error => return,
+        };
+        let name: String = match &source_info.name {
+            Some(name) => name.clone().into(),
+            None => return,
+        };
+        let info = PulseAudioSourceInfo {
+            name: name.clone(),
+            description: source_info
+                .description
+                .clone()
+                .map(|d| d.into_owned())
+                .unwrap_or_default(),
+            volume: source_info.volume,
+            mute: source_info.mute,
+            suspended: source_info.state == SourceState::Suspended,
+            active_port: source_info
+                .active_port
+                .as_ref()
+                .and_then(|port| port.name.clone())
+                .map(|port| port.into_owned()),
+            monitor: source_info.monitor_of_sink.is_some(),
+        };
+        let old = handle
+            .client
+            .lock()
+            .unwrap()
+            .sources
+            .insert(source_info.index, info.clone());
+        let mut events = Vec::new();
+        if let Some(old) = old {
+            if old.mute != info.mute {
+                events.push(AudioEvent::SourceMuteChanged {
+                    source: name.clone(),
+                    muted: info.mute,
+                });
+            }
+            if old.suspended != info.suspended {
+                events.push(AudioEvent::SourceSuspended {
+                    source: name,
+                    suspended: info.suspended,
+                });
+            }
+        }
+        for event in events {
+            (handle.listener)(handle.client.clone(), Some(event));
+        }
+    }
+
+    fn card_info_callback(handle: &PulseAudioHandle, result: ListResult<&CardInfo>) {
+        let card_info = match result {
+            ListResult::Item(card_info) => card_info,
+            ListResult::End | ListResult::Error => return,
+        };
+        let name: String = match &card_info.name {
+            Some(name) => name.clone().into(),
+            None => return,
+        };
+        let card = PulseAudioCard {
+            index: card_info.index,
+            name: name.clone(),
+            profile: card_info
+                .active_profile
+                .as_ref()
+                .and_then(|profile| profile.name.clone())
+                .map(|profile| profile.into_owned()),
+            bluetooth: BluetoothInfo::from_properties(|key| card_info.proplist.get_str(key)),
+        };
+        let old = handle
+            .client
+            .lock()
+            .unwrap()
+            .cards
+            .insert(card_info.index, card.clone());
+        let events = match (old.and_then(|old| old.bluetooth), &card.bluetooth) {
+            (Some(old), Some(new)) => new.changes(&old, &name),
+            _ => Vec::new(),
+        };
+        for event in events {
+            (handle.listener)(handle.client.clone(), Some(event));
+        }
+    }
+
+    fn subscribe_callback(
+        handle: &PulseAudioHandle,
+        facility: Option<Facility>,
+        operation: Option<SubscribeOperation>,
+        index: u32,
+    ) {
+        match facility {
+            None => {}
+            Some(facility) => match facility {
+                Facility::Server => {
+                    handle.send(PulseAudioClientRequest::GetDefaultDevice(None));
+                }
+                Facility::Sink => match operation {
+                    Some(SubscribeOperation::Removed) => {
+                        let name = {
+                            let mut client = handle.client.lock().unwrap();
+                            let name = client.sink_names.remove(&index);
+                            if let Some(name) = &name {
+                                client.sinks.remove(name);
+                            }
+                            name
+                        };
+                        if let Some(sink) = name {
+                            // Devices controlling this sink fall back to the default one.
+                            handle.send(PulseAudioClientRequest::GetDefaultDevice(None));
+                            (handle.listener)(
+                                handle.client.clone(),
+                                Some(AudioEvent::SinkRemoved { index, sink }),
+                            );
+                        }
+                    }
+                    operation => {
+                        let added = operation == Some(SubscribeOperation::New);
+                        handle.send(PulseAudioClientRequest::GetSinkInfoByIndex(
+                            None, index, added,
+                        ));
+                    }
+                },
+                Facility::Card => match operation {
+                    Some(SubscribeOperation::Removed) => {
+                        handle.client.lock().unwrap().cards.remove(&index);
+                    }
+                    _ => {
+                        handle.send(PulseAudioClientRequest::GetCardInfoByIndex(None, index));
+                    }
+                },
+                Facility::Source => match operation {
+                    Some(SubscribeOperation::Removed) => {
+                        handle.client.lock().unwrap().sources.remove(&index);
+                    }
+                    _ => {
+                        handle.send(PulseAudioClientRequest::GetSourceInfoByIndex(None, index));
+                    }
+                },
+                _ => {}
+            },
+        }
+    }
+}
+
+
+fn current_volume(
+    inner: &PulseAudioSoundDeviceInner,
+) -> Result<ChannelVolumes, ::std::io::Error> {
+    match inner.volume {
+        Some(volume) => Ok(volume),
+        None => Err(::std::io::Error::new(
+            ::std::io::ErrorKind::Other,
+            "unable to set volume",
+        )),
+    }
+}
+
+fn fraction_to_volume(fraction: f32) -> Volume {
+    let max = VOLUME_MAX.0 as f32 / VOLUME_NORM.0 as f32;
+    Volume((fraction.max(0.0).min(max) * VOLUME_NORM.0 as f32).round() as u32)
+}
+
+fn update_inner(inner: &mut PulseAudioSoundDeviceInner, client: &PulseAudioClient) {
+    inner.default_sink = client.default_sink.clone();
+    let name = inner
+        .name
+        .clone()
+        .unwrap_or_else(|| inner.default_sink.clone());
+    let sink_info = match client.sinks.get(&name) {
+        None => return,
+        Some(sink_info) => sink_info,
+    };
+
+    inner.volume = Some(sink_info.volume);
+    inner.channel_map = Some(sink_info.channel_map);
+    inner.base_volume = Some(sink_info.base_volume);
+    inner.volume_avg = sink_info.volume.avg().0 as f32 / VOLUME_NORM.0 as f32;
+    inner.muted = sink_info.mute;
+
+    // A silent sink has no balance to speak of, so keep the last known one around
+    // to restore once the volume is raised again.
+    if !sink_info.volume.is_muted() {
+        inner.balance = sink_info.volume.get_balance(&sink_info.channel_map);
+        inner.fade = sink_info.volume.get_fade(&sink_info.channel_map);
+    }
+}
+
+fn completion() -> (Completion, Receiver<bool>) {
+    let (tx, rx) = channel();
+    (
+        Box::new(move |success| {
+            let _ = tx.send(success);
+        }),
+        rx,
+    )
+}
+
+fn wait_for(wait: Option<Receiver<bool>>) -> Result<(), ::std::io::Error> {
+    if let Some(wait) = wait {
+        let success = wait.recv().map_err(|_e| {
+            ::std::io::Error::new(
+                ::std::io::ErrorKind::Other,
+                "pulseaudio request was dropped",
+            )
+        })?;
+        if !success {
+            return Err(::std::io::Error::new(
+                ::std::io::ErrorKind::Other,
+                "pulseaudio request failed",
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn collect_sinks(client: &PulseAudioClient) -> Vec<PulseAudioSink> {
+    let mut sinks: Vec<PulseAudioSink> = client
+        .sinks
+        .iter()
+        .map(|(name, info)| PulseAudioSink {
+            index: info.index,
+            name: name.clone(),
+            description: info.description.clone(),
+            volume: info.volume.avg().0 as f32 / VOLUME_NORM.0 as f32,
+            muted: info.mute,
+            suspended: info.suspended,
+            bluetooth: info.bluetooth.clone().map(|bluetooth| {
+                bluetooth.or(info.card.and_then(|card| client.cards.get(&card)?.bluetooth.as_ref()))
+            }),
+        })
+        .collect();
+    sinks.sort_by_key(|sink| sink.index);
+    sinks
+}
+
+// Scaling or stepping a volume through zero loses the ratio between channels, so
+// reapply the balance and fade we last knew about.
+fn restore_balance(inner: &PulseAudioSoundDeviceInner, volume: &mut ChannelVolumes) {
+    if let Some(map) = inner.channel_map {
+        if map.can_balance() {
+            volume.set_balance(&map, inner.balance);
+        }
+        if map.can_fade() {
+            volume.set_fade(&map, inner.fade);
+        }
+    }
+}
+
+impl PulseAudioSoundDevice {
+    pub fn new(sink: SinkSelector, block: bool) -> Result<Self, ::std::io::Error> {
+        PulseAudioSoundDevice::with_options(sink, block, PulseAudioOptions::default())
+    }
+
+    /// Connect with the given options, such as to a remote server.
+    pub fn with_options(
+        sink: SinkSelector,
+        block: bool,
+        options: PulseAudioOptions,
+    ) -> Result<Self, ::std::io::Error> {
+        let name = match &sink {
+            SinkSelector::FollowDefault => None,
+            SinkSelector::Fixed(name) => Some(name.clone()),
+        };
+        let inner = Arc::new(Mutex::new(PulseAudioSoundDeviceInner {
+            selector: sink,
+            name,
+            volume: None,
+            channel_map: None,
+            base_volume: None,
+            volume_avg: 0.0,
+            balance: 0.0,
+            fade: 0.0,
+            muted: false,
+            default_sink: "@DEFAULT_SINK@".to_string(),
+        }));
+        let client = Arc::new(Mutex::new(PulseAudioClient {
+            default_sink: "@DEFAULT_SINK@".to_string(),
+            default_source: "@DEFAULT_SOURCE@".to_string(),
+            sinks: HashMap::new(),
+            sink_names: HashMap::new(),
+            sources: HashMap::new(),
+            cards: HashMap::new(),
+        }));
+        let listeners = Arc::new(Mutex::new(Vec::new()));
+
+        // Runs on the mainloop thread, so it must not send requests itself.
+        let cb_inner = inner.clone();
+        let cb_listeners = listeners.clone();
+        let conn = PulseAudioConnection::new(
+            options,
+            client.clone(),
+            Arc::new(move |client: Arc<Mutex<PulseAudioClient>>, event: Option<AudioEvent>| {
+                {
+                    let mut inner = cb_inner.lock().unwrap();
+                    match &event {
+                        Some(AudioEvent::SinkRemoved { sink, .. }) => {
+                            // Our sink is gone, so fall back to the default sink.
+                            if inner.name.as_ref() == Some(sink) {
+                                inner.name = None;
+                                inner.volume = None;
+                            }
+                        }
+                        Some(AudioEvent::DefaultSinkChanged { .. }) if inner.name.is_none() => {
+                            // Forget the old sink's state until the new one has been fetched.
+                            inner.volume = None;
+                        }
+                        Some(AudioEvent::SinkAdded { sink, .. }) => {
+                            // Our sink is back.
+                            if inner.selector == SinkSelector::Fixed(sink.clone()) {
+                                inner.name = Some(sink.clone());
+                            }
+                        }
+                        _ => {}
+                    }
+                    update_inner(&mut inner, &client.lock().unwrap());
+                }
+                if let Some(event) = event {
+                    broadcast(&cb_listeners, event);
+                }
+            }),
+        )?;
+
+        let (done, wait) = completion();
+        conn.send(PulseAudioClientRequest::GetDefaultDevice(Some(done)))?;
+        wait_for(Some(wait))?;
+
+        let mut device = PulseAudioSoundDevice {
+            conn,
+            client,
+            inner,
+            block,
+            scale: VolumeScale::default(),
+            policy: VolumePolicy::default(),
+            feedback: None,
+            listeners,
+        };
+        let selector = device.inner.lock().unwrap().selector.clone();
+        device.set_sink(selector)?;
+
+        Ok(device)
+    }
+
+    /// Change which sink this device controls.
+    pub fn set_sink(&mut self, sink: SinkSelector) -> Result<(), ::std::io::Error> {
+        let name = match &sink {
+            SinkSelector::FollowDefault => self.client.lock().unwrap().default_sink.clone(),
+            SinkSelector::Fixed(name) => name.clone(),
+        };
+
+        let (done, wait) = completion();
+        self.conn
+            .send(PulseAudioClientRequest::GetSinkInfoByName(Some(done), name.clone()))?;
+        wait_for(Some(wait))?;
+
+        let mut inner = self.inner.lock().unwrap();
+        let client = self.client.lock().unwrap();
+        if let SinkSelector::Fixed(_) = &sink {
+            if !client.sinks.contains_key(&name) {
+                return Err(::std::io::Error::new(
+                    ::std::io::ErrorKind::NotFound,
+                    "no such sink",
+                ));
+            }
+        }
+
+        inner.name = match &sink {
+            SinkSelector::FollowDefault => None,
+            SinkSelector::Fixed(name) => Some(name.clone()),
+        };
+        inner.selector = sink;
+        inner.volume = None;
+        update_inner(&mut inner, &client);
+        Ok(())
+    }
+
+    /// The name of the sink currently being controlled.
+    pub fn sink_name(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .name
+            .clone()
+            .unwrap_or_else(|| inner.default_sink.clone())
+    }
+
+    /// List the sinks known to the server, ordered by index.
+    pub fn sinks(&self) -> Result<Vec<PulseAudioSink>, ::std::io::Error> {
+        let (done, wait) = completion();
+        self.conn
+            .send(PulseAudioClientRequest::GetSinkInfoList(Some(done)))?;
+        wait_for(Some(wait))?;
+        Ok(collect_sinks(&self.client.lock().unwrap()))
+    }
+
+    /// Load a module into the server, returning its index.
+    pub fn load_module(&self, module: &Module) -> Result<u32, ::std::io::Error> {
+        let (tx, rx) = channel();
+        self.conn.send(PulseAudioClientRequest::LoadModule(
+            tx,
+            module.name().to_string(),
+            module.argument(),
+        ))?;
+        match rx.recv() {
+            Ok(index) if index != ::std::u32::MAX => Ok(index),
+            Ok(_) => Err(::std::io::Error::new(
+                ::std::io::ErrorKind::InvalidInput,
+                format!("unable to load {}", module.name()),
+            )),
+            Err(_) => Err(::std::io::Error::new(
+                ::std::io::ErrorKind::Other,
+                "pulseaudio request was dropped",
+            )),
+        }
+    }
+
+    /// Unload a module by index.
+    pub fn unload_module(&self, index: u32) -> Result<(), ::std::io::Error> {
+        let (done, wait) = completion();
+        self.conn
+            .send(PulseAudioClientRequest::UnloadModule(Some(done), index))?;
+        wait_for(Some(wait)).map_err(|_e| {
+            ::std::io::Error::new(
+                ::std::io::ErrorKind::NotFound,
+                format!("unable to unload module {}", index),
+            )
+        })
+    }
+
+    /// List the modules loaded into the server, ordered by index.
+    pub fn list_modules(&self) -> Result<Vec<PulseAudioModule>, ::std::io::Error> {
+        let (tx, rx) = channel();
+        self.conn
+            .send(PulseAudioClientRequest::GetModuleInfoList(tx))?;
+        let mut modules = rx.recv().map_err(|_e| {
+            ::std::io::Error::new(
+                ::std::io::ErrorKind::Other,
+                "unable to list modules",
+            )
+        })?;
+        modules.sort_by_key(|module| module.index);
+        Ok(modules)
+    }
+
+    /// List the cards known to the server, ordered by index.
+    pub fn cards(&self) -> Result<Vec<PulseAudioCard>, ::std::io::Error> {
+        let (done, wait) = completion();
+        self.conn
+            .send(PulseAudioClientRequest::GetCardInfoList(Some(done)))?;
+        wait_for(Some(wait))?;
+        let mut cards: Vec<PulseAudioCard> =
+            self.client.lock().unwrap().cards.values().cloned().collect();
+        cards.sort_by_key(|card| card.index);
+        Ok(cards)
+    }
+
+    /// The name of the default source.
+    pub fn default_source(&self) -> String {
+        self.client.lock().unwrap().default_source.clone()
+    }
+
+    /// Mute or unmute a source, given by name.
+    pub fn set_source_muted(&self, source: &str, muted: bool) -> Result<(), ::std::io::Error> {
+        let (done, wait) = completion();
+        self.conn.send(PulseAudioClientRequest::SetSourceMuteByName(
+            Some(done),
+            source.to_string(),
+            muted,
+        ))?;
+        wait_for(Some(wait))
+    }
+
+    /// List the sources known to the server, ordered by index.
+    pub fn sources(&self) -> Result<Vec<PulseAudioSource>, ::std::io::Error> {
+        let (done, wait) = completion();
+        self.conn
+            .send(PulseAudioClientRequest::GetSourceInfoList(Some(done)))?;
+        wait_for(Some(wait))?;
+        let mut sources: Vec<PulseAudioSource> = self
+            .client
+            .lock()
+            .unwrap()
+            .sources
+            .iter()
+            .map(|(index, info)| PulseAudioSource {
+                index: *index,
+                name: info.name.clone(),
+                description: info.description.clone(),
+                muted: info.mute,
+                suspended: info.suspended,
+            })
+            .collect();
+        sources.sort_by_key(|source| source.index);
+        Ok(sources)
+    }
+
+    /// Suspend or resume a sink. A suspended sink releases its device, e.g. for use by JACK,
+    /// until resumed or played to.
+    pub fn suspend_sink(&self, sink: &DeviceId, suspend: bool) -> Result<(), ::std::io::Error> {
+        let (done, wait) = completion();
+        self.conn.send(PulseAudioClientRequest::SuspendSink(
+            Some(done),
+            sink.clone(),
+            suspend,
+        ))?;
+        wait_for(Some(wait)).map_err(|_e| {
+            ::std::io::Error::new(
+                ::std::io::ErrorKind::NotFound,
+                format!("unable to suspend sink {}", sink),
+            )
+        })
+    }
+
+    /// Suspend or resume a source.
+    pub fn suspend_source(
+        &self,
+        source: &DeviceId,
+        suspend: bool,
+    ) -> Result<(), ::std::io::Error> {
+        let (done, wait) = completion();
+        self.conn.send(PulseAudioClientRequest::SuspendSource(
+            Some(done),
+            source.clone(),
+            suspend,
+        ))?;
+        wait_for(Some(wait)).map_err(|_e| {
+            ::std::io::Error::new(
+                ::std::io::ErrorKind::NotFound,
+                format!("unable to suspend source {}", source),
+            )
+        })
+    }
+
+    /// Make a sink the default one. With `move_streams`, streams playing to other sinks are
+    /// moved to it as well; streams that cannot be moved are left in place.
+    pub fn set_default_sink(&self, sink: &str, move_streams: bool) -> Result<(), ::std::io::Error> {
+        let (done, wait) = completion();
+        self.conn.send(PulseAudioClientRequest::SetDefaultSink(
+            Some(done),
+            sink.to_string(),
+        ))?;
+        wait_for(Some(wait)).map_err(|_e| {
+            ::std::io::Error::new(
+                ::std::io::ErrorKind::NotFound,
+                format!("unable to make {} the default sink", sink),
+            )
+        })?;
+        if !move_streams {
+            return Ok(());
+        }
+
+        let index = self
+            .client
+            .lock()
+            .unwrap()
+            .sinks
+            .get(sink)
+            .map(|info| info.index);
+        let (tx, rx) = channel();
+        self.conn
+            .send(PulseAudioClientRequest::GetSinkInputInfoList(tx))?;
+        let inputs = rx.recv().map_err(|_e| {
+            ::std::io::Error::new(
+                ::std::io::ErrorKind::Other,
+                "unable to list streams",
+            )
+        })?;
+        for (input, _) in inputs.into_iter().filter(|(_, on)| Some(*on) != index) {
+            let (done, wait) = completion();
+            self.conn.send(PulseAudioClientRequest::MoveSinkInputByName(
+                Some(done),
+                input,
+                sink.to_string(),
+            ))?;
+            let _ = wait_for(Some(wait));
+        }
+        Ok(())
+    }
+
+    /// Find a sink by name, index, or a glob matched against its description.
+    pub fn find_sink(&self, pattern: &str) -> Result<Option<String>, ::std::io::Error> {
+        let sinks = self.sinks()?;
+        if let Some(sink) = sinks.iter().find(|sink| sink.name == pattern) {
+            return Ok(Some(sink.name.clone()));
+        }
+        if let Ok(index) = pattern.parse::<u32>() {
+            if let Some(sink) = sinks.iter().find(|sink| sink.index == index) {
+                return Ok(Some(sink.name.clone()));
+            }
+        }
+        Ok(sinks
+            .into_iter()
+            .find(|sink| glob_match(pattern, &sink.description) || glob_match(pattern, &sink.name))
+            .map(|sink| sink.name))
+    }
+
+    /// The scale used by `volume`, `add_volume` and `set_volume`.
+    pub fn volume_scale(&self) -> VolumeScale {
+        self.scale
+    }
+
+    pub fn set_volume_scale(&mut self, scale: VolumeScale) {
+        self.scale = scale;
+    }
+
+    pub fn volume_policy(&self) -> VolumePolicy {
+        self.policy
+    }
+
+    pub fn set_volume_policy(&mut self, policy: VolumePolicy) {
+        self.policy = policy;
+    }
+
+    /// Measure the peak level of a sink or source `rate` times per second.
+    pub fn meter(&self, target: MeterTarget, rate: u32) -> Result<PeakMeter, ::std::io::Error> {
+        if rate == 0 || rate > 1000 {
+            return Err(::std::io::Error::new(
+                ::std::io::ErrorKind::InvalidInput,
+                "meter rate must be between 1 and 1000",
+            ));
+        }
+        // Sinks are measured through their monitor, which is named after them.
+        let source = match target {
+            MeterTarget::Sink(sink) => format!("{}.monitor", sink),
+            MeterTarget::Source(source) => source,
+        };
+
+        let id = NEXT_METER.fetch_add(1, Ordering::Relaxed);
+        let (tx, levels) = channel();
+        let meter = PeakMeter {
+            conn: self.conn.clone(),
+            id,
+            levels,
+        };
+        let (done, wait) = completion();
+        self.conn.send(PulseAudioClientRequest::StartMeter(
+            Some(done),
+            id,
+            source,
+            rate,
+            tx,
+        ))?;
+        wait_for(Some(wait))?;
+        Ok(meter)
+    }
 
-        let name = inner
-            .name
-            .clone()
-            .unwrap_or_else(|| inner.default_sink.clone());
+    pub fn feedback(&self) -> Option<&Feedback> {
+        self.feedback.as_ref()
+    }
 
-        // update volumes
-        inner.volume = Some(volume);
-        inner.volume_avg = volume.avg().0 as f32 / VOLUME_NORM.0 as f32;
-        if self.block {
-            let (tx, rx) = channel();
-            self.client
-                .lock()
-                .unwrap()
-                .send(PulseAudioClientRequest::SetSinkVolumeByName(
-                    Some(tx), name, volume,
-                ))?;
-            let _ = rx.recv().unwrap();
-        } else {
-            self.client
+    /// Play a sound after each volume change, or stop doing so. The sound is uploaded to the
+    /// sample cache right away, which blocks even if the device does not.
+    pub fn set_feedback(&mut self, feedback: Option<Feedback>) -> Result<(), ::std::io::Error> {
+        if let Some(feedback) = &feedback {
+            let sample = match &feedback.file {
+                Some(file) => WavSample::open(file)?,
+                None => WavSample::parse(FEEDBACK_WAV)?,
+            };
+            let sample = Arc::new(sample);
+            let (done, wait) = completion();
+            self.conn.send(PulseAudioClientRequest::UploadSample(
+                Some(done),
+                FEEDBACK_SAMPLE.to_string(),
+                sample.clone(),
+            ))?;
+            wait_for(Some(wait))?;
+            self.conn
+                .samples
                 .lock()
                 .unwrap()
-                .send(PulseAudioClientRequest::SetSinkVolumeByName(
-                    None, name, volume,
-                ))?;
+                .insert(FEEDBACK_SAMPLE.to_string(), sample);
         }
+        self.feedback = feedback;
         Ok(())
     }
 
-    pub fn set_volume(&mut self, val: f32) -> Result<(), ::std::io::Error> {
+    /// Subscribe to audio events. The receiver is dropped from the subscriber list once it hangs
+    /// up.
+    pub fn subscribe(&self) -> Receiver<AudioEvent> {
+        let (tx, rx) = channel();
+        self.listeners
+            .lock()
+            .unwrap()
+            .push(Box::new(move |event| tx.send(event.clone()).is_ok()));
+        rx
+    }
+
+    pub fn volume(&self) -> f32 {
+        let inner = self.inner.lock().unwrap();
+        self.scale.from_fraction(inner.volume_avg)
+    }
+
+    pub fn muted(&self) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.muted
+    }
+
+    pub fn channel_map(&self) -> Option<Map> {
+        let inner = self.inner.lock().unwrap();
+        inner.channel_map
+    }
+
+    pub fn channel_names(&self) -> Vec<String> {
+        let inner = self.inner.lock().unwrap();
+        match inner.channel_map {
+            Some(map) => map
+                .get()
+                .iter()
+                .map(|pos| {
+                    Position::to_string(*pos)
+                        .map(|name| name.into_owned())
+                        .unwrap_or_else(|| "unknown".to_string())
+                })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn channel_volumes(&self) -> Vec<f32> {
+        let inner = self.inner.lock().unwrap();
+        match inner.volume {
+            Some(volume) => volume
+                .get()
+                .iter()
+                .map(|v| v.0 as f32 / VOLUME_NORM.0 as f32)
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn balance(&self) -> f32 {
+        let inner = self.inner.lock().unwrap();
+        inner.balance
+    }
+
+    pub fn fade(&self) -> f32 {
+        let inner = self.inner.lock().unwrap();
+        inner.fade
+    }
+
+    pub fn set_channel_volumes(&mut self, vals: &[f32]) -> Result<(), ::std::io::Error> {
+        let (done, wait) = self.completion(SinkOperation::SetVolume);
+        self.set_channel_volumes_with(vals, done)?;
+        wait_for(wait)
+    }
+
+    /// Set the left/right balance, from -1.0 (left only) to 1.0 (right only).
+    pub fn set_balance(&mut self, balance: f32) -> Result<(), ::std::io::Error> {
+        let (done, wait) = self.completion(SinkOperation::SetVolume);
+        self.set_balance_with(balance, done)?;
+        wait_for(wait)
+    }
+
+    /// Set the rear/front fade, from -1.0 (rear only) to 1.0 (front only).
+    pub fn set_fade(&mut self, fade: f32) -> Result<(), ::std::io::Error> {
+        let (done, wait) = self.completion(SinkOperation::SetVolume);
+        self.set_fade_with(fade, done)?;
+        wait_for(wait)
+    }
+
+    pub fn add_volume(&mut self, step: f32, cap: Option<f32>) -> Result<(), ::std::io::Error> {
+        let (done, wait) = self.completion(SinkOperation::SetVolume);
+        self.add_volume_with(step, cap, done)?;
+        wait_for(wait)
+    }
+
+    /// Set the volume of the loudest channel, keeping the ratio between channels.
+    pub fn set_volume(&mut self, val: f32, cap: Option<f32>) -> Result<(), ::std::io::Error> {
+        let (done, wait) = self.completion(SinkOperation::SetVolume);
+        self.set_volume_with(val, cap, done)?;
+        wait_for(wait)
+    }
+
+    pub fn set_muted(&mut self, muted: bool) -> Result<(), ::std::io::Error> {
+        let (done, wait) = self.completion(SinkOperation::SetMuted);
+        self.set_muted_with(muted, done)?;
+        wait_for(wait)
+    }
+
+    // A completion to wait for if this device is blocking. Otherwise failures are broadcast.
+    fn completion(&self, operation: SinkOperation) -> (Option<Completion>, Option<Receiver<bool>>) {
+        if self.block {
+            let (done, wait) = completion();
+            (Some(done), Some(wait))
+        } else {
+            let listeners = self.listeners.clone();
+            let sink = self.sink_name();
+            let done: Completion = Box::new(move |success| {
+                if !success {
+                    broadcast(&listeners, AudioEvent::OperationFailed { sink, operation });
+                }
+            });
+            (Some(done), None)
+        }
+    }
+
+    // Changes are cached before the server answers them, so wrap their completion to go back to
+    // what the server last reported if it rejects the change.
+    fn rollback(&self, done: Option<Completion>) -> Option<Completion> {
+        let inner = self.inner.clone();
+        let client = self.client.clone();
+        Some(Box::new(move |success| {
+            if !success {
+                update_inner(&mut inner.lock().unwrap(), &client.lock().unwrap());
+            }
+            if let Some(done) = done {
+                done(success);
+            }
+        }))
+    }
+
+    fn set_channel_volumes_with(
+        &self,
+        vals: &[f32],
+        done: Option<Completion>,
+    ) -> Result<(), ::std::io::Error> {
+        let mut inner = self.inner.lock().unwrap();
+        let mut volume = current_volume(&inner)?;
+        if vals.len() != volume.len() as usize {
+            return Err(::std::io::Error::new(
+                ::std::io::ErrorKind::InvalidInput,
+                "channel count mismatch",
+            ));
+        }
+
+        let loudest = vals.iter().cloned().fold(0.0, f32::max);
+        let limit = self.apply_policy(&inner, loudest);
+        for (v, val) in volume.get_mut().iter_mut().zip(vals) {
+            *v = fraction_to_volume(val.min(limit));
+        }
+
+        if let Some(map) = inner.channel_map {
+            if !volume.is_muted() {
+                inner.balance = volume.get_balance(&map);
+                inner.fade = volume.get_fade(&map);
+            }
+        }
+
+        self.commit_volume(inner, volume, done, None)
+    }
+
+    fn set_balance_with(
+        &self,
+        balance: f32,
+        done: Option<Completion>,
+    ) -> Result<(), ::std::io::Error> {
+        if !(-1.0..=1.0).contains(&balance) {
+            return Err(::std::io::Error::new(
+                ::std::io::ErrorKind::InvalidInput,
+                "balance must be between -1.0 and 1.0",
+            ));
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let mut volume = current_volume(&inner)?;
+        let map = match inner.channel_map {
+            Some(map) if map.can_balance() => map,
+            _ => {
+                return Err(::std::io::Error::new(
+                    ::std::io::ErrorKind::Other,
+                    "sink does not support balance",
+                ))
+            }
+        };
+
+        volume.set_balance(&map, balance);
+        inner.balance = balance;
+        self.commit_volume(inner, volume, done, None)
+    }
+
+    fn set_fade_with(&self, fade: f32, done: Option<Completion>) -> Result<(), ::std::io::Error> {
+        if !(-1.0..=1.0).contains(&fade) {
+            return Err(::std::io::Error::new(
+                ::std::io::ErrorKind::InvalidInput,
+                "fade must be between -1.0 and 1.0",
+            ));
+        }
+
         let mut inner = self.inner.lock().unwrap();
-        let mut volume = match inner.volume {
-            Some(volume) => volume,
-            None => {
+        let mut volume = current_volume(&inner)?;
+        let map = match inner.channel_map {
+            Some(map) if map.can_fade() => map,
+            _ => {
                 return Err(::std::io::Error::new(
                     ::std::io::ErrorKind::Other,
-                    "unable to set volume",
+                    "sink does not support fade",
                 ))
             }
         };
 
-        // apply step to volumes
-        volume.scale(Volume((val * VOLUME_NORM.0 as f32).round() as u32));
+        volume.set_fade(&map, fade);
+        inner.fade = fade;
+        self.commit_volume(inner, volume, done, None)
+    }
+
+    fn add_volume_with(
+        &self,
+        step: f32,
+        cap: Option<f32>,
+        done: Option<Completion>,
+    ) -> Result<(), ::std::io::Error> {
+        let inner = self.inner.lock().unwrap();
+        let mut volume = current_volume(&inner)?;
+
+        // apply step in the configured scale, relative to the loudest channel
+        let current = volume.max().0 as f32 / VOLUME_NORM.0 as f32;
+        let mut target = self.scale.step(current, step);
+        if step > 0.0 {
+            if let Some(cap) = cap {
+                target = target.min(self.scale.to_fraction(cap));
+            }
+        }
+        volume.scale(fraction_to_volume(self.apply_policy(&inner, target)));
+
+        restore_balance(&inner, &mut volume);
+        self.commit_volume(inner, volume, done, self.feedback_cue())
+    }
+
+    fn set_volume_with(
+        &self,
+        val: f32,
+        cap: Option<f32>,
+        done: Option<Completion>,
+    ) -> Result<(), ::std::io::Error> {
+        check_absolute(val, self.scale)?;
+
+        let inner = self.inner.lock().unwrap();
+        let mut volume = current_volume(&inner)?;
+
+        let mut target = self.scale.to_fraction(val);
+        if let Some(cap) = cap {
+            target = target.min(self.scale.to_fraction(cap));
+        }
+        volume.scale(fraction_to_volume(self.apply_policy(&inner, target)));
+
+        restore_balance(&inner, &mut volume);
+        self.commit_volume(inner, volume, done, self.feedback_cue())
+    }
+
+    // The feedback sound to play after a volume change, unless one was played too recently.
+    fn feedback_cue(&self) -> Option<FeedbackCue> {
+        let feedback = self.feedback.as_ref()?;
+
+        // The time of the last sound is kept in a file, as every key press may be a new process.
+        // Without a private runtime directory to keep it in, every change gets a sound.
+        let stamp = env::var_os("XDG_RUNTIME_DIR").map(|dir| Path::new(&dir).join(FEEDBACK_SAMPLE));
+        let recent = stamp
+            .as_ref()
+            .and_then(|stamp| fs::symlink_metadata(stamp).ok())
+            .and_then(|meta| meta.modified().ok())
+            .and_then(|time| SystemTime::now().duration_since(time).ok())
+            .map_or(false, |elapsed| elapsed < feedback.min_interval);
+        if recent {
+            None
+        } else {
+            Some(FeedbackCue { stamp })
+        }
+    }
+
+    fn set_muted_with(&self, muted: bool, done: Option<Completion>) -> Result<(), ::std::io::Error> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.muted = muted;
+        let name = inner
+            .name
+            .clone()
+            .unwrap_or_else(|| inner.default_sink.clone());
+        drop(inner);
+        self.conn.send(PulseAudioClientRequest::SetSinkMuteByName(
+            self.rollback(done),
+            name,
+            muted,
+        ))
+    }
+
+    // Clamp a target volume according to the volume policy, and warn if it engages boost.
+    fn apply_policy(&self, inner: &PulseAudioSoundDeviceInner, target: f32) -> f32 {
+        let base = inner
+            .base_volume
+            .filter(|v| v.0 > 0)
+            .map(|v| v.0 as f32 / VOLUME_NORM.0 as f32);
+        let current = inner
+            .volume
+            .map_or(0.0, |v| v.max().0 as f32 / VOLUME_NORM.0 as f32);
+        let (target, boosted) = self.policy.apply(target, current, base);
+        if boosted {
+            let sink = inner
+                .name
+                .clone()
+                .unwrap_or_else(|| inner.default_sink.clone());
+            broadcast(
+                &self.listeners,
+                AudioEvent::BoostEngaged {
+                    sink,
+                    volume: target,
+                },
+            );
+        }
+        target
+    }
 
+    // Callbacks lock the device state with the mainloop lock held, so release it before sending.
+    fn commit_volume(
+        &self,
+        mut inner: MutexGuard<PulseAudioSoundDeviceInner>,
+        volume: ChannelVolumes,
+        done: Option<Completion>,
+        cue: Option<FeedbackCue>,
+    ) -> Result<(), ::std::io::Error> {
         let name = inner
             .name
             .clone()
@@ -468,56 +2378,387 @@ impl PulseAudioSoundDevice {
         // update volumes
         inner.volume = Some(volume);
         inner.volume_avg = volume.avg().0 as f32 / VOLUME_NORM.0 as f32;
-        if self.block {
-            let (tx, rx) = channel();
-            self.client
-                .lock()
-                .unwrap()
-                .send(PulseAudioClientRequest::SetSinkVolumeByName(
-                    Some(tx), name, volume,
-                ))?;
-            let _ = rx.recv().unwrap();
-        } else {
-            self.client
-                .lock()
-                .unwrap()
-                .send(PulseAudioClientRequest::SetSinkVolumeByName(
-                    None, name, volume,
-                ))?;
+        drop(inner);
+        self.conn.send(PulseAudioClientRequest::SetSinkVolumeByName(
+            self.rollback(done),
+            name,
+            volume,
+            cue,
+        ))
+    }
+}
+
+impl SoundDevice for PulseAudioSoundDevice {
+    fn backend(&self) -> Backend {
+        Backend::PulseAudio
+    }
+
+    fn volume_scale(&self) -> VolumeScale {
+        PulseAudioSoundDevice::volume_scale(self)
+    }
+
+    fn set_volume_scale(&mut self, scale: VolumeScale) {
+        PulseAudioSoundDevice::set_volume_scale(self, scale)
+    }
+
+    fn volume_policy(&self) -> VolumePolicy {
+        PulseAudioSoundDevice::volume_policy(self)
+    }
+
+    fn set_volume_policy(&mut self, policy: VolumePolicy) {
+        PulseAudioSoundDevice::set_volume_policy(self, policy)
+    }
+
+    fn volume(&self) -> f32 {
+        PulseAudioSoundDevice::volume(self)
+    }
+
+    fn muted(&self) -> bool {
+        PulseAudioSoundDevice::muted(self)
+    }
+
+    fn channel_names(&self) -> Vec<String> {
+        PulseAudioSoundDevice::channel_names(self)
+    }
+
+    fn channel_volumes(&self) -> Vec<f32> {
+        PulseAudioSoundDevice::channel_volumes(self)
+    }
+
+    fn add_volume(&mut self, step: f32, cap: Option<f32>) -> Result<(), ::std::io::Error> {
+        PulseAudioSoundDevice::add_volume(self, step, cap)
+    }
+
+    fn set_volume(&mut self, val: f32, cap: Option<f32>) -> Result<(), ::std::io::Error> {
+        PulseAudioSoundDevice::set_volume(self, val, cap)
+    }
+
+    fn set_muted(&mut self, muted: bool) -> Result<(), ::std::io::Error> {
+        PulseAudioSoundDevice::set_muted(self, muted)
+    }
+
+    fn subscribe(&self) -> Receiver<AudioEvent> {
+        PulseAudioSoundDevice::subscribe(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+    use std::time::Instant;
+
+    // These tests need a running PulseAudio server. A null sink is loaded and made default for
+    // the duration of each test, so they are run explicitly with `cargo test -- --ignored`.
+    pub(super) struct NullSink {
+        module: String,
+        previous_default: String,
+        _default: MutexGuard<'static, ()>,
+    }
+
+    // Held while a null sink is the default one, so that tests running in parallel do not change
+    // it under each other.
+    static DEFAULT_SINK: Mutex<()> = Mutex::new(());
+
+    impl NullSink {
+        pub(super) fn load(name: &str) -> NullSink {
+            // A failed test only poisons the lock after restoring the default sink.
+            let default = DEFAULT_SINK.lock().unwrap_or_else(|e| e.into_inner());
+            let previous_default = pactl(&["get-default-sink"]);
+            let module = pactl(&[
+                "load-module",
+                "module-null-sink",
+                &format!("sink_name={}", name),
+                "channels=2",
+            ]);
+            pactl(&["set-default-sink", name]);
+            NullSink {
+                module,
+                previous_default,
+                _default: default,
+            }
         }
-        Ok(())
     }
 
-    pub fn set_muted(&mut self, muted: bool) -> Result<(), ::std::io::Error> {
-        let mut inner = self.inner.lock().unwrap();
-        inner.muted = muted;
-        if self.block {
-            let (tx, rx) = channel();
-        self.client
-            .lock()
-            .unwrap()
-            .send(PulseAudioClientRequest::SetSinkMuteByName(
-                Some(tx),
-                inner
-                    .name
-                    .clone()
-                    .unwrap_or_else(|| inner.default_sink.clone()),
-                inner.muted,
-            ))?;
-            let _ = rx.recv().unwrap();
-        } else {
-        self.client
+    impl Drop for NullSink {
+        fn drop(&mut self) {
+            if !self.previous_default.is_empty() {
+                pactl(&["set-default-sink", &self.previous_default]);
+            }
+            pactl(&["unload-module", &self.module]);
+        }
+    }
+
+    fn pactl(args: &[&str]) -> String {
+        let output = Command::new("pactl").args(args).output().unwrap();
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    }
+
+    fn device() -> PulseAudioSoundDevice {
+        PulseAudioSoundDevice::new(SinkSelector::FollowDefault, true).unwrap()
+    }
+
+    // Wait for the first event `f` picks a value out of, failing the test if none comes in time.
+    pub(super) fn wait_event<T, F>(events: &Receiver<AudioEvent>, mut f: F) -> T
+    where
+        F: FnMut(AudioEvent) -> Option<T>,
+    {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let left = deadline.saturating_duration_since(Instant::now());
+            match events.recv_timeout(left) {
+                Ok(event) => {
+                    if let Some(value) = f(event) {
+                        return value;
+                    }
+                }
+                Err(_) => panic!("timed out waiting for an event"),
+            }
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn set_volume_keeps_balance() {
+        let _sink = NullSink::load("platformctl_test_balance");
+        let mut dev = device();
+        dev.set_channel_volumes(&[0.5, 0.25]).unwrap();
+        dev.set_volume(0.8, None).unwrap();
+
+        let dev = device();
+        let volumes = dev.channel_volumes();
+        assert!((volumes[0] - 0.8).abs() < 0.01);
+        assert!((volumes[1] - 0.4).abs() < 0.01);
+    }
+
+    #[test]
+    #[ignore]
+    fn set_volume_honors_cap() {
+        let _sink = NullSink::load("platformctl_test_cap");
+        let mut dev = device();
+        dev.set_volume(1.5, Some(1.0)).unwrap();
+        assert!((device().volume() - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    #[ignore]
+    fn removed_sink_is_evicted() {
+        let sink = NullSink::load("platformctl_test_removed");
+        let dev = PulseAudioSoundDevice::new(
+            SinkSelector::Fixed("platformctl_test_removed".to_string()),
+            true,
+        )
+        .unwrap();
+        let events = dev.subscribe();
+        drop(sink);
+
+        let removed = wait_event(&events, |event| match event {
+            AudioEvent::SinkRemoved { sink, .. } => Some(sink),
+            _ => None,
+        });
+        assert_eq!(removed, "platformctl_test_removed");
+        assert!(!dev
+            .client
             .lock()
             .unwrap()
-            .send(PulseAudioClientRequest::SetSinkMuteByName(
-                None,
-                inner
-                    .name
-                    .clone()
-                    .unwrap_or_else(|| inner.default_sink.clone()),
-                inner.muted,
-            ))?;
+            .sinks
+            .contains_key("platformctl_test_removed"));
+        assert_eq!(dev.inner.lock().unwrap().name, None);
+    }
+
+    #[test]
+    #[ignore]
+    fn rejected_change_is_an_error() {
+        let _sink = NullSink::load("platformctl_test_rejected");
+        let mut dev = device();
+        let (volume, muted) = (dev.volume(), dev.muted());
+
+        // Make the device control a sink the server does not have, as if it just went away.
+        let missing = "platformctl_test_no_such_sink".to_string();
+        {
+            let mut client = dev.client.lock().unwrap();
+            let sink = client.sinks["platformctl_test_rejected"].clone();
+            client.sinks.insert(missing.clone(), sink);
         }
-        Ok(())
+        dev.inner.lock().unwrap().name = Some(missing);
+
+        assert!(dev.set_volume(volume / 2.0, None).is_err());
+        assert!(dev.set_muted(!muted).is_err());
+        assert_eq!(dev.volume(), volume);
+        assert_eq!(dev.muted(), muted);
+    }
+
+    #[test]
+    #[ignore]
+    fn connects_to_unix_socket() {
+        let socket = ::std::env::temp_dir().join("platformctl_test.socket");
+        let module = pactl(&[
+            "load-module",
+            "module-native-protocol-unix",
+            &format!("socket={}", socket.display()),
+            "auth-anonymous=1",
+        ]);
+        let _sink = NullSink::load("platformctl_test_server");
+
+        let options = PulseAudioOptions {
+            server: Some(format!("unix:{}", socket.display())),
+            app_name: "platformctl_test".to_string(),
+            properties: vec![("application.id".to_string(), "platformctl.test".to_string())],
+        };
+        let res = PulseAudioSoundDevice::with_options(SinkSelector::FollowDefault, true, options)
+            .map(|dev| dev.sink_name());
+        pactl(&["unload-module", &module]);
+        assert_eq!(res.unwrap(), "platformctl_test_server");
+    }
+
+    #[test]
+    fn bundled_feedback_parses() {
+        let sample = WavSample::parse(FEEDBACK_WAV).unwrap();
+        assert!(sample.duration() < Duration::from_millis(100));
+    }
+
+    #[test]
+    #[ignore]
+    fn feedback_is_uploaded() {
+        let _sink = NullSink::load("platformctl_test_feedback");
+        let mut dev = device();
+        dev.set_feedback(Some(Feedback::default())).unwrap();
+        dev.set_volume(0.5, None).unwrap();
+        assert!(pactl(&["list", "samples", "short"]).contains(FEEDBACK_SAMPLE));
+    }
+
+    #[test]
+    #[ignore]
+    fn meter_follows_playback() {
+        use std::io::Write;
+        use std::process::Stdio;
+
+        let _sink = NullSink::load("platformctl_test_meter");
+        let dev = device();
+        let meter = dev
+            .meter(MeterTarget::Sink("platformctl_test_meter".to_string()), 20)
+            .unwrap();
+
+        // Two seconds of a 440Hz tone at half scale.
+        let mut tone = Vec::new();
+        for i in 0..88200 {
+            let v = (i as f32 * 440.0 * 2.0 * ::std::f32::consts::PI / 44100.0).sin();
+            tone.extend_from_slice(&((v * 16384.0) as i16).to_le_bytes());
+        }
+        let mut pacat = Command::new("pacat")
+            .args([
+                "--device=platformctl_test_meter",
+                "--format=s16le",
+                "--rate=44100",
+                "--channels=1",
+            ])
+            .stdin(Stdio::piped())
+            .spawn()
+            .unwrap();
+        pacat.stdin.take().unwrap().write_all(&tone).unwrap();
+
+        let peak = (0..30)
+            .map(|_| {
+                meter
+                    .levels()
+                    .recv_timeout(Duration::from_secs(1))
+                    .expect("no level was reported")
+            })
+            .fold(0.0f32, |peak, level| peak.max(level));
+        pacat.wait().unwrap();
+        assert!(peak > 0.2, "peak level was {}", peak);
+    }
+
+    #[test]
+    fn module_arguments() {
+        let null = Module::NullSink {
+            name: "null".to_string(),
+            description: Some("My \"null\" sink".to_string()),
+            channels: Some(2),
+        };
+        assert_eq!(
+            null.argument(),
+            r#"sink_name=null sink_properties="device.description=\"My \\\"null\\\" sink\"" channels=2"#
+        );
+        let loopback = Module::Loopback {
+            source: Some("mic".to_string()),
+            sink: None,
+            latency_msec: Some(20),
+        };
+        assert_eq!(loopback.argument(), "source=mic latency_msec=20");
+        let combined = Module::CombineSink {
+            name: "both".to_string(),
+            description: None,
+            sinks: vec!["hdmi".to_string(), "analog".to_string()],
+        };
+        assert_eq!(combined.name(), "module-combine-sink");
+        assert_eq!(combined.argument(), "sink_name=both slaves=hdmi,analog");
+    }
+
+    #[test]
+    #[ignore]
+    fn load_and_unload_module() {
+        let dev = device();
+        let index = dev
+            .load_module(&Module::NullSink {
+                name: "platformctl_test_module".to_string(),
+                description: Some("platformctl test".to_string()),
+                channels: None,
+            })
+            .unwrap();
+        let loaded = dev.list_modules().unwrap();
+        let sinks = dev.sinks().unwrap();
+        dev.unload_module(index).unwrap();
+
+        assert!(loaded
+            .iter()
+            .any(|m| m.index == index && m.name == "module-null-sink"));
+        let sink = sinks
+            .iter()
+            .find(|s| s.name == "platformctl_test_module")
+            .unwrap();
+        assert_eq!(sink.description, "platformctl test");
+        assert!(!dev.list_modules().unwrap().iter().any(|m| m.index == index));
+        assert!(dev.unload_module(index).is_err());
+    }
+
+    #[test]
+    #[ignore]
+    fn suspend_is_reported() {
+        let _sink = NullSink::load("platformctl_test_suspend");
+        let dev = device();
+        let events = dev.subscribe();
+        dev.suspend_sink(&DeviceId::Name("platformctl_test_suspend".to_string()), true)
+            .unwrap();
+
+        let suspended = wait_event(&events, |event| match event {
+            AudioEvent::SinkSuspended { sink, suspended } => Some((sink, suspended)),
+            _ => None,
+        });
+        assert_eq!(suspended, ("platformctl_test_suspend".to_string(), true));
+        let sink = dev
+            .sinks()
+            .unwrap()
+            .into_iter()
+            .find(|s| s.name == "platformctl_test_suspend")
+            .unwrap();
+        assert!(sink.suspended);
+
+        let monitor = "platformctl_test_suspend.monitor";
+        dev.suspend_sink(&DeviceId::Index(sink.index), false).unwrap();
+        assert!(!dev.sources().unwrap().iter().any(|s| s.name == monitor && s.suspended));
+        let missing = DeviceId::Name("platformctl_test_no_such_source".to_string());
+        assert!(dev.suspend_source(&missing, true).is_err());
+    }
+
+    #[test]
+    #[ignore]
+    fn set_volume_rejects_invalid() {
+        let _sink = NullSink::load("platformctl_test_invalid");
+        let mut dev = device();
+        dev.set_volume(0.3, None).unwrap();
+        assert!(dev.set_volume(-0.5, None).is_err());
+        assert!(dev.set_volume(::std::f32::NAN, None).is_err());
+        assert!((device().volume() - 0.3).abs() < 0.01);
     }
 }
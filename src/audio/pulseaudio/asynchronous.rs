@@ -0,0 +1,245 @@
+//! A futures-based variant of `PulseAudioSoundDevice`, for use from async executors.
+//!
+//! Requests complete from the PulseAudio callbacks, so no executor thread is ever blocked
+//! waiting on the server.
+
+use std::io::{Error, ErrorKind};
+use std::thread;
+
+use futures::channel::{mpsc, oneshot};
+use futures::Future;
+
+use super::{
+    collect_sinks, Completion, PulseAudioClientRequest, PulseAudioOptions, PulseAudioSink,
+    PulseAudioSoundDevice,
+};
+use crate::audio::{AudioEvent, SinkSelector, VolumePolicy, VolumeScale};
+
+pub struct AsyncPulseAudioSoundDevice {
+    device: PulseAudioSoundDevice,
+}
+
+fn completion() -> (Completion, oneshot::Receiver<bool>) {
+    let (tx, rx) = oneshot::channel();
+    (
+        Box::new(move |success| {
+            let _ = tx.send(success);
+        }),
+        rx,
+    )
+}
+
+async fn wait_for(wait: oneshot::Receiver<bool>) -> Result<(), Error> {
+    let success = wait
+        .await
+        .map_err(|_e| Error::new(ErrorKind::Other, "pulseaudio request was dropped"))?;
+    if !success {
+        return Err(Error::new(ErrorKind::Other, "pulseaudio request failed"));
+    }
+    Ok(())
+}
+
+impl AsyncPulseAudioSoundDevice {
+    /// Connect to the server. The initial connection is made on a separate thread.
+    pub async fn new(sink: SinkSelector) -> Result<Self, Error> {
+        AsyncPulseAudioSoundDevice::with_options(sink, PulseAudioOptions::default()).await
+    }
+
+    /// Connect with the given options, such as to a remote server.
+    pub async fn with_options(sink: SinkSelector, options: PulseAudioOptions) -> Result<Self, Error> {
+        let (tx, rx) = oneshot::channel();
+        thread::Builder::new()
+            .name("pa_connect".to_string())
+            .spawn(move || {
+                let _ = tx.send(PulseAudioSoundDevice::with_options(sink, false, options));
+            })?;
+        let device = rx
+            .await
+            .map_err(|_e| Error::new(ErrorKind::Other, "unable to connect to pulseaudio"))??;
+        Ok(AsyncPulseAudioSoundDevice { device })
+    }
+
+    /// A stream of audio events.
+    pub fn events(&self) -> mpsc::UnboundedReceiver<AudioEvent> {
+        let (tx, rx) = mpsc::unbounded();
+        self.device
+            .listeners
+            .lock()
+            .unwrap()
+            .push(Box::new(move |event| {
+                tx.unbounded_send(event.clone()).is_ok()
+            }));
+        rx
+    }
+
+    pub fn sink_name(&self) -> String {
+        self.device.sink_name()
+    }
+
+    pub fn sinks(&self) -> impl Future<Output = Result<Vec<PulseAudioSink>, Error>> {
+        let (done, wait) = completion();
+        let client = self.device.client.clone();
+        let res = self
+            .device
+            .conn
+            .send(PulseAudioClientRequest::GetSinkInfoList(Some(done)));
+        async move {
+            res?;
+            wait_for(wait).await?;
+            let sinks = collect_sinks(&client.lock().unwrap());
+            Ok(sinks)
+        }
+    }
+
+    pub fn volume_scale(&self) -> VolumeScale {
+        self.device.volume_scale()
+    }
+
+    pub fn set_volume_scale(&mut self, scale: VolumeScale) {
+        self.device.set_volume_scale(scale)
+    }
+
+    pub fn volume_policy(&self) -> VolumePolicy {
+        self.device.volume_policy()
+    }
+
+    pub fn set_volume_policy(&mut self, policy: VolumePolicy) {
+        self.device.set_volume_policy(policy)
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.device.volume()
+    }
+
+    pub fn muted(&self) -> bool {
+        self.device.muted()
+    }
+
+    pub fn channel_names(&self) -> Vec<String> {
+        self.device.channel_names()
+    }
+
+    pub fn channel_volumes(&self) -> Vec<f32> {
+        self.device.channel_volumes()
+    }
+
+    pub fn balance(&self) -> f32 {
+        self.device.balance()
+    }
+
+    pub fn fade(&self) -> f32 {
+        self.device.fade()
+    }
+
+    pub fn set_channel_volumes(&self, vals: &[f32]) -> impl Future<Output = Result<(), Error>> {
+        let (done, wait) = completion();
+        let res = self.device.set_channel_volumes_with(vals, Some(done));
+        async move {
+            res?;
+            wait_for(wait).await
+        }
+    }
+
+    pub fn set_balance(&self, balance: f32) -> impl Future<Output = Result<(), Error>> {
+        let (done, wait) = completion();
+        let res = self.device.set_balance_with(balance, Some(done));
+        async move {
+            res?;
+            wait_for(wait).await
+        }
+    }
+
+    pub fn set_fade(&self, fade: f32) -> impl Future<Output = Result<(), Error>> {
+        let (done, wait) = completion();
+        let res = self.device.set_fade_with(fade, Some(done));
+        async move {
+            res?;
+            wait_for(wait).await
+        }
+    }
+
+    pub fn add_volume(
+        &self,
+        step: f32,
+        cap: Option<f32>,
+    ) -> impl Future<Output = Result<(), Error>> {
+        let (done, wait) = completion();
+        let res = self.device.add_volume_with(step, cap, Some(done));
+        async move {
+            res?;
+            wait_for(wait).await
+        }
+    }
+
+    pub fn set_volume(
+        &self,
+        val: f32,
+        cap: Option<f32>,
+    ) -> impl Future<Output = Result<(), Error>> {
+        let (done, wait) = completion();
+        let res = self.device.set_volume_with(val, cap, Some(done));
+        async move {
+            res?;
+            wait_for(wait).await
+        }
+    }
+
+    pub fn set_muted(&self, muted: bool) -> impl Future<Output = Result<(), Error>> {
+        let (done, wait) = completion();
+        let res = self.device.set_muted_with(muted, Some(done));
+        async move {
+            res?;
+            wait_for(wait).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::NullSink;
+    use super::*;
+    use futures::executor::block_on;
+    use futures::future::{select, Either};
+    use futures::StreamExt;
+    use std::time::Duration;
+
+    // Resolves after `duration`, from a sleeping thread as there is no timer to use.
+    fn timeout(duration: Duration) -> oneshot::Receiver<()> {
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            thread::sleep(duration);
+            let _ = tx.send(());
+        });
+        rx
+    }
+
+    // Needs a running PulseAudio server, see the tests of the blocking device.
+    #[test]
+    #[ignore]
+    fn volume_change_completes() {
+        let _sink = NullSink::load("platformctl_test_async");
+        block_on(async {
+            let dev = AsyncPulseAudioSoundDevice::new(SinkSelector::FollowDefault)
+                .await
+                .unwrap();
+            let mut events = dev.events();
+            let volume = dev.volume();
+            assert!(volume > 0.0);
+            dev.set_volume(volume / 2.0, None).await.unwrap();
+            dev.set_volume(volume, None).await.unwrap();
+
+            let changed = Box::pin(async {
+                while let Some(event) = events.next().await {
+                    if let AudioEvent::VolumeChanged { .. } = event {
+                        return true;
+                    }
+                }
+                false
+            });
+            match select(changed, timeout(Duration::from_secs(5))).await {
+                Either::Left((true, _)) => (),
+                _ => panic!("timed out waiting for a volume change"),
+            }
+        });
+    }
+}
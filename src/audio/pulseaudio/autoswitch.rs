@@ -0,0 +1,233 @@
+//! Make newly connected devices the default sink, following prioritized rules.
+//!
+//! Rules match sinks by name and by properties such as `device.bus` or `device.form_factor`,
+//! using globs. A sink that appears and outranks the default sink becomes the default, and when
+//! the default sink goes away, the highest ranked remaining sink takes its place.
+
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind};
+use std::str::FromStr;
+
+use super::PulseAudioSoundDevice;
+use crate::audio::{glob_match, AudioEvent};
+
+/// A rule giving matching sinks a priority, written as `PRIORITY:KEY=GLOB[,KEY=GLOB...]`, e.g.
+/// `100:device.bus=bluetooth` or `50:device.bus=usb,device.form_factor=headset`. The key `name`
+/// matches the sink name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub priority: i32,
+    pub conditions: Vec<(String, String)>,
+}
+
+impl Rule {
+    pub fn matches(&self, name: &str, properties: &HashMap<String, String>) -> bool {
+        self.conditions.iter().all(|(key, pattern)| {
+            let value = match key.as_str() {
+                "name" => Some(name),
+                key => properties.get(key).map(|value| value.as_str()),
+            };
+            value.map_or(false, |value| glob_match(pattern, value))
+        })
+    }
+}
+
+impl FromStr for Rule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid rule {}, expected PRIORITY:KEY=GLOB[,KEY=GLOB...]", s),
+            )
+        };
+        let at = s.find(':').ok_or_else(invalid)?;
+        let priority = s[..at].trim().parse().map_err(|_e| invalid())?;
+        let conditions = s[at + 1..]
+            .split(',')
+            .map(|condition| match condition.find('=') {
+                Some(at) if at > 0 => Ok((
+                    condition[..at].trim().to_string(),
+                    condition[at + 1..].trim().to_string(),
+                )),
+                _ => Err(invalid()),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Rule {
+            priority,
+            conditions,
+        })
+    }
+}
+
+/// Switches the default sink according to a set of rules.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AutoSwitch {
+    pub rules: Vec<Rule>,
+}
+
+impl AutoSwitch {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        AutoSwitch { rules }
+    }
+
+    /// The priority of a sink, from the highest priority rule it matches.
+    pub fn priority(&self, name: &str, properties: &HashMap<String, String>) -> Option<i32> {
+        self.rules
+            .iter()
+            .filter(|rule| rule.matches(name, properties))
+            .map(|rule| rule.priority)
+            .max()
+    }
+
+    /// The highest ranked of the given sinks, if any matches a rule. Ties go to the sink listed
+    /// first.
+    pub fn best<'a, I>(&self, sinks: I) -> Option<&'a str>
+    where
+        I: IntoIterator<Item = (&'a str, &'a HashMap<String, String>)>,
+    {
+        let mut best: Option<(&str, i32)> = None;
+        for (name, properties) in sinks {
+            if let Some(priority) = self.priority(name, properties) {
+                if best.map_or(true, |(_, best)| priority > best) {
+                    best = Some((name, priority));
+                }
+            }
+        }
+        best.map(|(name, _)| name)
+    }
+
+    // Whether `sink` should replace `current` as the default sink.
+    fn outranks(&self, device: &PulseAudioSoundDevice, sink: &str, current: &str) -> bool {
+        let client = device.client.lock().unwrap();
+        let priority = |name: &str| {
+            client
+                .sinks
+                .get(name)
+                .and_then(|info| self.priority(name, &info.properties))
+        };
+        sink != current && priority(sink).is_some() && priority(sink) > priority(current)
+    }
+
+    fn best_available(&self, device: &PulseAudioSoundDevice) -> Option<String> {
+        let client = device.client.lock().unwrap();
+        self.best(
+            client
+                .sinks
+                .iter()
+                .map(|(name, info)| (name.as_str(), &info.properties)),
+        )
+        .map(|name| name.to_string())
+    }
+
+    /// Watch for sinks coming and going, and switch the default sink and its streams
+    /// accordingly, calling `on_switch` with each new default sink. This only returns on errors.
+    pub fn run<F>(&self, device: &PulseAudioSoundDevice, mut on_switch: F) -> Result<(), Error>
+    where
+        F: FnMut(&str),
+    {
+        let events = device.subscribe();
+        let mut switch = |sink: &str| -> Result<(), Error> {
+            device.set_default_sink(sink, true)?;
+            on_switch(sink);
+            Ok(())
+        };
+
+        // Start out on the best sink that is already there.
+        device.sinks()?;
+        let mut default = device.client.lock().unwrap().default_sink.clone();
+        if let Some(best) = self.best_available(device) {
+            if self.outranks(device, &best, &default) {
+                switch(&best)?;
+            }
+        }
+
+        for event in events.iter() {
+            match event {
+                AudioEvent::DefaultSinkChanged { sink } => default = sink,
+                AudioEvent::SinkAdded { sink, .. } => {
+                    if self.outranks(device, &sink, &default) {
+                        switch(&sink)?;
+                    }
+                }
+                // The server picks a new default on its own, but not necessarily the one we prefer.
+                AudioEvent::SinkRemoved { sink, .. } if sink == default => {
+                    if let Some(best) = self.best_available(device) {
+                        switch(&best)?;
+                    }
+                }
+                AudioEvent::ServerRestarted => {
+                    device.sinks()?;
+                    if let Some(best) = self.best_available(device) {
+                        switch(&best)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Err(Error::new(
+            ErrorKind::Other,
+            "pulseaudio connection went away",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn properties(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn parse_rules() {
+        let rule: Rule = "50: device.bus=usb, device.form_factor=head*".parse().unwrap();
+        assert_eq!(rule.priority, 50);
+        assert_eq!(
+            rule.conditions,
+            vec![
+                ("device.bus".to_string(), "usb".to_string()),
+                ("device.form_factor".to_string(), "head*".to_string()),
+            ]
+        );
+        assert!("device.bus=usb".parse::<Rule>().is_err());
+        assert!("high:device.bus=usb".parse::<Rule>().is_err());
+        assert!("10:device.bus".parse::<Rule>().is_err());
+        assert!("10:=usb".parse::<Rule>().is_err());
+    }
+
+    #[test]
+    fn ranks_sinks() {
+        let switch = AutoSwitch::new(vec![
+            "100:device.bus=bluetooth".parse().unwrap(),
+            "50:device.bus=usb,device.form_factor=headset".parse().unwrap(),
+            "10:name=alsa_output.*analog*".parse().unwrap(),
+        ]);
+        let headset = properties(&[("device.bus", "usb"), ("device.form_factor", "headset")]);
+        let speaker = properties(&[("device.bus", "usb"), ("device.form_factor", "speaker")]);
+        let bluez = properties(&[("device.bus", "bluetooth")]);
+        let none = HashMap::new();
+
+        assert_eq!(switch.priority("usb_headset", &headset), Some(50));
+        assert_eq!(switch.priority("usb_speaker", &speaker), None);
+        assert_eq!(switch.priority("alsa_output.pci.analog-stereo", &none), Some(10));
+        assert_eq!(
+            switch.best(vec![
+                ("alsa_output.pci.analog-stereo", &none),
+                ("usb_headset", &headset),
+                ("usb_speaker", &speaker),
+            ]),
+            Some("usb_headset")
+        );
+        assert_eq!(
+            switch.best(vec![("usb_headset", &headset), ("bluez_sink.phones", &bluez)]),
+            Some("bluez_sink.phones")
+        );
+        assert_eq!(switch.best(vec![("usb_speaker", &speaker)]), None);
+    }
+}
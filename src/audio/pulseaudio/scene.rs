@@ -0,0 +1,675 @@
+//! Named snapshots of the mixer state, saved as plain text files and re-applied later.
+//!
+//! A scene holds the default devices, the active profile of each card, and the port, volume and
+//! mute state of each sink and source:
+//!
+//! ```text
+//! default-sink = alsa_output.pci-0000_00_1f.3.analog-stereo
+//! default-source = alsa_input.pci-0000_00_1f.3.analog-stereo
+//!
+//! [card alsa_card.pci-0000_00_1f.3]
+//! profile = output:analog-stereo+input:analog-stereo
+//!
+//! [sink alsa_output.pci-0000_00_1f.3.analog-stereo]
+//! port = analog-output-speaker
+//! volume = 0.5 0.45
+//! muted = false
+//! ```
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use libpulse_binding::volume::{ChannelVolumes, Volume, VOLUME_NORM};
+
+use super::{completion, wait_for, PulseAudioClientRequest, PulseAudioSoundDevice};
+
+/// The active profile of a card.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SceneCard {
+    pub name: String,
+    pub profile: String,
+}
+
+/// The state of a sink or source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SceneDevice {
+    pub name: String,
+    pub port: Option<String>,
+    /// Per-channel volumes, where 1.0 is the normal volume.
+    pub volumes: Vec<f32>,
+    pub muted: bool,
+}
+
+/// A snapshot of the mixer state.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Scene {
+    pub default_sink: Option<String>,
+    pub default_source: Option<String>,
+    pub cards: Vec<SceneCard>,
+    pub sinks: Vec<SceneDevice>,
+    /// Sources, not including sink monitors.
+    pub sources: Vec<SceneDevice>,
+}
+
+fn invalid(line: usize, msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, format!("line {}: {}", line, msg))
+}
+
+fn write_device(f: &mut fmt::Formatter, kind: &str, device: &SceneDevice) -> fmt::Result {
+    writeln!(f)?;
+    writeln!(f, "[{} {}]", kind, device.name)?;
+    if let Some(port) = &device.port {
+        writeln!(f, "port = {}", port)?;
+    }
+    let volumes: Vec<String> = device.volumes.iter().map(|v| v.to_string()).collect();
+    writeln!(f, "volume = {}", volumes.join(" "))?;
+    writeln!(f, "muted = {}", device.muted)
+}
+
+impl fmt::Display for Scene {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(sink) = &self.default_sink {
+            writeln!(f, "default-sink = {}", sink)?;
+        }
+        if let Some(source) = &self.default_source {
+            writeln!(f, "default-source = {}", source)?;
+        }
+        for card in &self.cards {
+            writeln!(f)?;
+            writeln!(f, "[card {}]", card.name)?;
+            writeln!(f, "profile = {}", card.profile)?;
+        }
+        for sink in &self.sinks {
+            write_device(f, "sink", sink)?;
+        }
+        for source in &self.sources {
+            write_device(f, "source", source)?;
+        }
+        Ok(())
+    }
+}
+
+// The section a line of a scene file belongs to.
+enum Section {
+    Top,
+    Card(usize),
+    Sink(usize),
+    Source(usize),
+    // Sections written by newer versions, which are skipped.
+    Unknown,
+}
+
+impl FromStr for Scene {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut scene = Scene::default();
+        let mut section = Section::Top;
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            let n = i + 1;
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                if !line.ends_with(']') {
+                    return Err(invalid(n, "unterminated section header"));
+                }
+                let header = &line[1..line.len() - 1];
+                let (kind, name) = match header.find(' ') {
+                    Some(at) => (&header[..at], header[at + 1..].trim().to_string()),
+                    None => return Err(invalid(n, "section header without a name")),
+                };
+                let device = SceneDevice {
+                    name: name.clone(),
+                    port: None,
+                    volumes: Vec::new(),
+                    muted: false,
+                };
+                section = match kind {
+                    "card" => {
+                        scene.cards.push(SceneCard {
+                            name,
+                            profile: String::new(),
+                        });
+                        Section::Card(scene.cards.len() - 1)
+                    }
+                    "sink" => {
+                        scene.sinks.push(device);
+                        Section::Sink(scene.sinks.len() - 1)
+                    }
+                    "source" => {
+                        scene.sources.push(device);
+                        Section::Source(scene.sources.len() - 1)
+                    }
+                    _ => Section::Unknown,
+                };
+                continue;
+            }
+
+            let (key, value) = match line.find('=') {
+                Some(at) => (line[..at].trim(), line[at + 1..].trim()),
+                None => return Err(invalid(n, "expected key = value")),
+            };
+            let device = match section {
+                Section::Top => {
+                    match key {
+                        "default-sink" => scene.default_sink = Some(value.to_string()),
+                        "default-source" => scene.default_source = Some(value.to_string()),
+                        _ => {}
+                    }
+                    continue;
+                }
+                Section::Card(card) => {
+                    if key == "profile" {
+                        scene.cards[card].profile = value.to_string();
+                    }
+                    continue;
+                }
+                Section::Sink(sink) => &mut scene.sinks[sink],
+                Section::Source(source) => &mut scene.sources[source],
+                Section::Unknown => continue,
+            };
+            match key {
+                "port" => device.port = Some(value.to_string()),
+                "volume" => {
+                    device.volumes = value
+                        .split_whitespace()
+                        .map(|v| match v.parse::<f32>() {
+                            Ok(v) if v.is_finite() && v >= 0.0 => Ok(v),
+                            _ => Err(invalid(n, "invalid volume")),
+                        })
+                        .collect::<Result<_, _>>()?;
+                }
+                "muted" => {
+                    device.muted = value
+                        .parse()
+                        .map_err(|_e| invalid(n, "invalid mute state"))?;
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(card) = scene.cards.iter().find(|card| card.profile.is_empty()) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("card {} has no profile", card.name),
+            ));
+        }
+        Ok(scene)
+    }
+}
+
+/// A directory of scene files, named `<scene>.scene`.
+pub struct SceneStore {
+    dir: PathBuf,
+}
+
+impl SceneStore {
+    pub fn new(dir: PathBuf) -> Self {
+        SceneStore { dir }
+    }
+
+    /// The store in `$XDG_CONFIG_HOME/platformctl/scenes`, or `~/.config/platformctl/scenes`.
+    pub fn open_default() -> Result<Self, Error> {
+        let config = match env::var_os("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => match env::var_os("HOME") {
+                Some(home) => PathBuf::from(home).join(".config"),
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::NotFound,
+                        "neither XDG_CONFIG_HOME nor HOME is set",
+                    ))
+                }
+            },
+        };
+        Ok(SceneStore::new(config.join("platformctl").join("scenes")))
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf, Error> {
+        let valid = !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.');
+        if !valid {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("invalid scene name: {}", name),
+            ));
+        }
+        Ok(self.dir.join(format!("{}.scene", name)))
+    }
+
+    pub fn save(&self, name: &str, scene: &Scene) -> Result<(), Error> {
+        let path = self.path(name)?;
+        fs::create_dir_all(&self.dir)?;
+        fs::write(path, scene.to_string())
+    }
+
+    pub fn load(&self, name: &str) -> Result<Scene, Error> {
+        let path = self.path(name)?;
+        let contents = fs::read_to_string(&path).map_err(|e| match e.kind() {
+            ErrorKind::NotFound => {
+                Error::new(ErrorKind::NotFound, format!("no scene named {}", name))
+            }
+            _ => e,
+        })?;
+        contents.parse()
+    }
+
+    /// The names of the saved scenes, sorted.
+    pub fn list(&self) -> Result<Vec<String>, Error> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut names = Vec::new();
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().map_or(false, |ext| ext == "scene") {
+                if let Some(name) = path.file_stem().and_then(|name| name.to_str()) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+}
+
+fn fractions(volume: &ChannelVolumes) -> Vec<f32> {
+    volume
+        .get()
+        .iter()
+        .map(|v| v.0 as f32 / VOLUME_NORM.0 as f32)
+        .collect()
+}
+
+// Apply saved volumes to a device's current volume. The channel count only changes along with
+// the port or profile, in which case the loudest saved channel is used for all of them.
+fn restore_volumes(current: &ChannelVolumes, saved: &[f32]) -> ChannelVolumes {
+    let mut volume = *current;
+    let channels = volume.len() as usize;
+    let loudest = saved.iter().cloned().fold(0.0f32, f32::max);
+    for (i, v) in volume.get_mut().iter_mut().enumerate() {
+        let fraction = if saved.len() == channels {
+            saved[i]
+        } else {
+            loudest
+        };
+        *v = Volume((fraction * VOLUME_NORM.0 as f32).round() as u32);
+    }
+    volume
+}
+
+impl PulseAudioSoundDevice {
+    // Run a request, reporting failure as a warning rather than an error.
+    fn try_request<F>(
+        &self,
+        warnings: &mut Vec<String>,
+        what: String,
+        request: F,
+    ) -> Result<(), Error>
+    where
+        F: FnOnce(super::Completion) -> PulseAudioClientRequest,
+    {
+        let (done, wait) = completion();
+        self.conn.send(request(done))?;
+        if wait_for(Some(wait)).is_err() {
+            warnings.push(format!("unable to {}", what));
+        }
+        Ok(())
+    }
+
+    // Refresh the sinks, sources and default devices known to the client.
+    fn refresh(&self) -> Result<(), Error> {
+        let (done, wait) = completion();
+        self.conn
+            .send(PulseAudioClientRequest::GetDefaultDevice(Some(done)))?;
+        wait_for(Some(wait))?;
+        let (done, wait) = completion();
+        self.conn
+            .send(PulseAudioClientRequest::GetSinkInfoList(Some(done)))?;
+        wait_for(Some(wait))?;
+        let (done, wait) = completion();
+        self.conn
+            .send(PulseAudioClientRequest::GetSourceInfoList(Some(done)))?;
+        wait_for(Some(wait))
+    }
+
+    /// Capture the current mixer state.
+    pub fn capture_scene(&self) -> Result<Scene, Error> {
+        let cards = self.cards()?;
+        self.refresh()?;
+        let client = self.client.lock().unwrap();
+
+        let mut sinks: Vec<_> = client.sinks.iter().collect();
+        sinks.sort_by_key(|(_, info)| info.index);
+        let mut sources: Vec<_> = client
+            .sources
+            .iter()
+            .filter(|(_, info)| !info.monitor)
+            .collect();
+        sources.sort_by_key(|(index, _)| **index);
+
+        Ok(Scene {
+            default_sink: Some(client.default_sink.clone()),
+            default_source: Some(client.default_source.clone()),
+            cards: cards
+                .into_iter()
+                .filter_map(|card| {
+                    Some(SceneCard {
+                        profile: card.profile?,
+                        name: card.name,
+                    })
+                })
+                .collect(),
+            sinks: sinks
+                .into_iter()
+                .map(|(name, info)| SceneDevice {
+                    name: name.clone(),
+                    port: info.active_port.clone(),
+                    volumes: fractions(&info.volume),
+                    muted: info.mute,
+                })
+                .collect(),
+            sources: sources
+                .into_iter()
+                .map(|(_, info)| SceneDevice {
+                    name: info.name.clone(),
+                    port: info.active_port.clone(),
+                    volumes: fractions(&info.volume),
+                    muted: info.mute,
+                })
+                .collect(),
+        })
+    }
+
+    /// Apply a scene, returning warnings about the parts that could not be applied, such as
+    /// devices that are no longer present.
+    pub fn apply_scene(&self, scene: &Scene) -> Result<Vec<String>, Error> {
+        let mut warnings = Vec::new();
+
+        // Profiles come first, since they decide which sinks and sources exist.
+        let cards = self.cards()?;
+        for card in &scene.cards {
+            match cards.iter().find(|c| c.name == card.name) {
+                None => warnings.push(format!("card {} not found", card.name)),
+                Some(c) if c.profile.as_ref() == Some(&card.profile) => {}
+                Some(_) => {
+                    self.try_request(
+                        &mut warnings,
+                        format!("set profile {} on card {}", card.profile, card.name),
+                        |done| {
+                            PulseAudioClientRequest::SetCardProfileByName(
+                                Some(done),
+                                card.name.clone(),
+                                card.profile.clone(),
+                            )
+                        },
+                    )?;
+                }
+            }
+        }
+        self.refresh()?;
+
+        for sink in &scene.sinks {
+            let info = self.client.lock().unwrap().sinks.get(&sink.name).cloned();
+            let info = match info {
+                Some(info) => info,
+                None => {
+                    warnings.push(format!("sink {} not found", sink.name));
+                    continue;
+                }
+            };
+            if let Some(port) = sink
+                .port
+                .as_ref()
+                .filter(|port| info.active_port.as_ref() != Some(port))
+            {
+                self.try_request(
+                    &mut warnings,
+                    format!("set port {} on sink {}", port, sink.name),
+                    |done| {
+                        PulseAudioClientRequest::SetSinkPortByName(
+                            Some(done),
+                            sink.name.clone(),
+                            port.clone(),
+                        )
+                    },
+                )?;
+            }
+            if !sink.volumes.is_empty() {
+                let volume = restore_volumes(&info.volume, &sink.volumes);
+                self.try_request(
+                    &mut warnings,
+                    format!("set volume on sink {}", sink.name),
+                    |done| {
+                        PulseAudioClientRequest::SetSinkVolumeByName(
+                            Some(done),
+                            sink.name.clone(),
+                            volume,
+                            None,
+                        )
+                    },
+                )?;
+            }
+            self.try_request(
+                &mut warnings,
+                format!("set mute on sink {}", sink.name),
+                |done| {
+                    PulseAudioClientRequest::SetSinkMuteByName(
+                        Some(done),
+                        sink.name.clone(),
+                        sink.muted,
+                    )
+                },
+            )?;
+        }
+
+        for source in &scene.sources {
+            let info = self
+                .client
+                .lock()
+                .unwrap()
+                .sources
+                .values()
+                .find(|info| info.name == source.name)
+                .cloned();
+            let info = match info {
+                Some(info) => info,
+                None => {
+                    warnings.push(format!("source {} not found", source.name));
+                    continue;
+                }
+            };
+            if let Some(port) = source
+                .port
+                .as_ref()
+                .filter(|port| info.active_port.as_ref() != Some(port))
+            {
+                self.try_request(
+                    &mut warnings,
+                    format!("set port {} on source {}", port, source.name),
+                    |done| {
+                        PulseAudioClientRequest::SetSourcePortByName(
+                            Some(done),
+                            source.name.clone(),
+                            port.clone(),
+                        )
+                    },
+                )?;
+            }
+            if !source.volumes.is_empty() {
+                let volume = restore_volumes(&info.volume, &source.volumes);
+                self.try_request(
+                    &mut warnings,
+                    format!("set volume on source {}", source.name),
+                    |done| {
+                        PulseAudioClientRequest::SetSourceVolumeByName(
+                            Some(done),
+                            source.name.clone(),
+                            volume,
+                        )
+                    },
+                )?;
+            }
+            self.try_request(
+                &mut warnings,
+                format!("set mute on source {}", source.name),
+                |done| {
+                    PulseAudioClientRequest::SetSourceMuteByName(
+                        Some(done),
+                        source.name.clone(),
+                        source.muted,
+                    )
+                },
+            )?;
+        }
+
+        if let Some(sink) = &scene.default_sink {
+            if self.client.lock().unwrap().sinks.contains_key(sink) {
+                self.try_request(
+                    &mut warnings,
+                    format!("make {} the default sink", sink),
+                    |done| PulseAudioClientRequest::SetDefaultSink(Some(done), sink.clone()),
+                )?;
+            } else {
+                warnings.push(format!("default sink {} not found", sink));
+            }
+        }
+        if let Some(source) = &scene.default_source {
+            let found = self
+                .client
+                .lock()
+                .unwrap()
+                .sources
+                .values()
+                .any(|info| info.name == *source);
+            if found {
+                self.try_request(
+                    &mut warnings,
+                    format!("make {} the default source", source),
+                    |done| PulseAudioClientRequest::SetDefaultSource(Some(done), source.clone()),
+                )?;
+            } else {
+                warnings.push(format!("default source {} not found", source));
+            }
+        }
+
+        Ok(warnings)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::NullSink;
+    use super::super::SinkSelector;
+    use super::*;
+
+    fn scene() -> Scene {
+        Scene {
+            default_sink: Some("speakers".to_string()),
+            default_source: None,
+            cards: vec![SceneCard {
+                name: "alsa_card.usb".to_string(),
+                profile: "output:analog-stereo+input:mono-fallback".to_string(),
+            }],
+            sinks: vec![SceneDevice {
+                name: "speakers".to_string(),
+                port: Some("analog-output-speaker".to_string()),
+                volumes: vec![0.5, 0.25],
+                muted: false,
+            }],
+            sources: vec![SceneDevice {
+                name: "mic".to_string(),
+                port: None,
+                volumes: vec![1.0],
+                muted: true,
+            }],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let text = scene().to_string();
+        assert!(text.contains("[sink speakers]\nport = analog-output-speaker\nvolume = 0.5 0.25\n"));
+        assert_eq!(text.parse::<Scene>().unwrap(), scene());
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert!("[sink speakers\nmuted = false".parse::<Scene>().is_err());
+        assert!("[sink]".parse::<Scene>().is_err());
+        assert!("[sink speakers]\nvolume = loud".parse::<Scene>().is_err());
+        assert!("[card usb]\n".parse::<Scene>().is_err());
+        assert!("default-sink speakers".parse::<Scene>().is_err());
+        // Unknown keys and sections are skipped.
+        let scene = "# comment\nfuture = 1\n[monitor x]\nfoo = bar\n[sink a]\nmuted = true"
+            .parse::<Scene>()
+            .unwrap();
+        assert_eq!(scene.sinks.len(), 1);
+        assert!(scene.sinks[0].muted);
+    }
+
+    #[test]
+    fn store() {
+        let dir = env::temp_dir().join(format!("platformctl_scenes_{}", ::std::process::id()));
+        let store = SceneStore::new(dir.clone());
+        assert!(store.list().unwrap().is_empty());
+        store.save("meeting", &scene()).unwrap();
+        store.save("desk", &Scene::default()).unwrap();
+        assert_eq!(store.list().unwrap(), vec!["desk", "meeting"]);
+        assert_eq!(store.load("meeting").unwrap(), scene());
+        assert_eq!(store.load("nope").unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(
+            store.save("../x", &scene()).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn apply_restores_sink() {
+        let _sink = NullSink::load("platformctl_test_scene");
+        let dev = PulseAudioSoundDevice::new(SinkSelector::FollowDefault, true).unwrap();
+        // Only the test sinks, so that nothing else on the machine is touched.
+        let scene = Scene {
+            sinks: vec![
+                SceneDevice {
+                    name: "platformctl_test_scene".to_string(),
+                    port: None,
+                    volumes: vec![0.5, 0.25],
+                    muted: true,
+                },
+                SceneDevice {
+                    name: "platformctl_test_missing".to_string(),
+                    port: None,
+                    volumes: vec![1.0],
+                    muted: false,
+                },
+            ],
+            ..Scene::default()
+        };
+
+        let warnings = dev.apply_scene(&scene).unwrap();
+        assert_eq!(warnings, vec!["sink platformctl_test_missing not found"]);
+        let restored = dev.capture_scene().unwrap();
+        let sink = restored
+            .sinks
+            .iter()
+            .find(|sink| sink.name == "platformctl_test_scene")
+            .unwrap();
+        assert_eq!(sink.volumes, vec![0.5, 0.25]);
+        assert!(sink.muted);
+    }
+}
@@ -0,0 +1,447 @@
+//! Push-to-talk: keep the microphone muted except while a key is held, or toggle it with a key.
+//!
+//! Key presses come from an evdev device such as `/dev/input/event3`, or as `press`, `release`
+//! and `toggle` lines written to a control socket, e.g. by a window manager binding.
+
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Read};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use byteorder::{NativeEndian, ReadBytesExt};
+
+#[cfg(feature = "pulseaudio")]
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+
+#[cfg(feature = "pulseaudio")]
+use super::pulseaudio::PulseAudioSoundDevice;
+
+const EV_KEY: u16 = 1;
+
+/// An event read from an evdev device, as a `struct input_event`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InputEvent {
+    pub time: Duration,
+    pub kind: u16,
+    pub code: u16,
+    pub value: i32,
+}
+
+impl InputEvent {
+    /// Read the next event. The timestamp is a `struct timeval`, so its size follows the
+    /// platform's `long`.
+    pub fn read<R: Read>(r: &mut R) -> Result<Self, Error> {
+        let (sec, usec) = if cfg!(target_pointer_width = "64") {
+            (r.read_i64::<NativeEndian>()?, r.read_i64::<NativeEndian>()?)
+        } else {
+            (
+                r.read_i32::<NativeEndian>()? as i64,
+                r.read_i32::<NativeEndian>()? as i64,
+            )
+        };
+        Ok(InputEvent {
+            time: Duration::from_secs(sec.max(0) as u64)
+                + Duration::from_micros(usec.max(0) as u64),
+            kind: r.read_u16::<NativeEndian>()?,
+            code: r.read_u16::<NativeEndian>()?,
+            value: r.read_i32::<NativeEndian>()?,
+        })
+    }
+}
+
+// Names of the keys most likely to be used for talking, from linux/input-event-codes.h.
+const KEY_NAMES: &[(&str, u16)] = &[
+    ("KEY_LEFTCTRL", 29),
+    ("KEY_LEFTSHIFT", 42),
+    ("KEY_RIGHTSHIFT", 54),
+    ("KEY_LEFTALT", 56),
+    ("KEY_SPACE", 57),
+    ("KEY_CAPSLOCK", 58),
+    ("KEY_SCROLLLOCK", 70),
+    ("KEY_RIGHTCTRL", 97),
+    ("KEY_RIGHTALT", 100),
+    ("KEY_PAUSE", 119),
+    ("KEY_LEFTMETA", 125),
+    ("KEY_RIGHTMETA", 126),
+    ("KEY_COMPOSE", 127),
+    ("KEY_F13", 183),
+    ("KEY_F14", 184),
+    ("KEY_F15", 185),
+    ("KEY_F16", 186),
+    ("KEY_F17", 187),
+    ("KEY_F18", 188),
+    ("KEY_F19", 189),
+    ("KEY_F20", 190),
+    ("KEY_MICMUTE", 248),
+    ("BTN_SIDE", 0x113),
+    ("BTN_EXTRA", 0x114),
+    ("BTN_FORWARD", 0x115),
+    ("BTN_BACK", 0x116),
+];
+
+/// Parse a key code, either as a number or as a name such as `KEY_F13` or `f13`.
+pub fn parse_key(s: &str) -> Result<u16, Error> {
+    if let Ok(code) = s.parse() {
+        return Ok(code);
+    }
+    let upper = s.to_uppercase();
+    KEY_NAMES
+        .iter()
+        .find(|(name, _)| *name == upper || name[4..] == upper)
+        .map(|(_, code)| *code)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("unknown key: {}", s)))
+}
+
+/// What the talk key did.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TalkInput {
+    Press,
+    Release,
+    /// Toggle talking regardless of the mode, as from a control socket.
+    Toggle,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TalkMode {
+    /// Talk while the key is held.
+    Push,
+    /// Start or stop talking with each press.
+    Toggle,
+}
+
+/// Decides when to talk, given key presses and releases.
+#[derive(Debug, Clone)]
+pub struct TalkState {
+    mode: TalkMode,
+    release_delay: Duration,
+    talking: bool,
+    // When to stop talking, after the key was released.
+    stop_at: Option<Instant>,
+}
+
+impl TalkState {
+    pub fn new(mode: TalkMode, release_delay: Duration) -> Self {
+        TalkState {
+            mode,
+            release_delay,
+            talking: false,
+            stop_at: None,
+        }
+    }
+
+    pub fn talking(&self) -> bool {
+        self.talking
+    }
+
+    /// When `expire` should be called next.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.stop_at
+    }
+
+    /// Handle an input, returning the new talking state if it changed.
+    pub fn input(&mut self, input: TalkInput, now: Instant) -> Option<bool> {
+        let talking = match (input, self.mode) {
+            (TalkInput::Toggle, _) | (TalkInput::Press, TalkMode::Toggle) => {
+                self.stop_at = None;
+                !self.talking
+            }
+            (TalkInput::Press, TalkMode::Push) => {
+                self.stop_at = None;
+                true
+            }
+            (TalkInput::Release, TalkMode::Push) if self.talking => {
+                if self.release_delay == Duration::from_secs(0) {
+                    false
+                } else {
+                    // Keep talking a little longer, so the end of a sentence is not cut off.
+                    self.stop_at = Some(now + self.release_delay);
+                    true
+                }
+            }
+            (TalkInput::Release, _) => self.talking,
+        };
+        self.set(talking)
+    }
+
+    /// Stop talking once the release delay has passed.
+    pub fn expire(&mut self, now: Instant) -> Option<bool> {
+        match self.stop_at {
+            Some(stop_at) if now >= stop_at => {
+                self.stop_at = None;
+                self.set(false)
+            }
+            _ => None,
+        }
+    }
+
+    fn set(&mut self, talking: bool) -> Option<bool> {
+        if talking == self.talking {
+            return None;
+        }
+        self.talking = talking;
+        Some(talking)
+    }
+}
+
+/// Forward presses and releases of `key` read from an evdev stream. Key repeats are ignored.
+pub fn watch_events<R>(mut events: R, key: u16, inputs: Sender<TalkInput>) -> Result<(), Error>
+where
+    R: Read,
+{
+    loop {
+        let event = InputEvent::read(&mut events)?;
+        if event.kind != EV_KEY || event.code != key {
+            continue;
+        }
+        let input = match event.value {
+            0 => TalkInput::Release,
+            1 => TalkInput::Press,
+            _ => continue,
+        };
+        if inputs.send(input).is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Watch an evdev device on a new thread.
+pub fn spawn_evdev(path: &Path, key: u16, inputs: Sender<TalkInput>) -> Result<(), Error> {
+    let device = File::open(path)?;
+    thread::Builder::new()
+        .name("ptt_evdev".to_string())
+        .spawn(move || watch_events(BufReader::new(device), key, inputs))?;
+    Ok(())
+}
+
+/// The default control socket, in `$XDG_RUNTIME_DIR`. There is none if that is not set, as a
+/// shared directory would let other users send commands.
+pub fn default_socket() -> Option<PathBuf> {
+    ::std::env::var_os("XDG_RUNTIME_DIR").map(|dir| PathBuf::from(dir).join("platformctl-ptt.sock"))
+}
+
+/// Listen on a control socket on a new thread, taking one command per line.
+pub fn spawn_socket(path: &Path, inputs: Sender<TalkInput>) -> Result<(), Error> {
+    // A socket left behind by an earlier run keeps the address in use, but anything else at the
+    // path is not ours to remove, and neither is a socket another instance still listens on.
+    if let Ok(meta) = fs::symlink_metadata(path) {
+        if !meta.file_type().is_socket() {
+            return Err(Error::new(
+                ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        match UnixStream::connect(path) {
+            Ok(_) => {
+                return Err(Error::new(
+                    ErrorKind::AddrInUse,
+                    format!("{} is in use by another instance", path.display()),
+                ))
+            }
+            Err(e) if e.kind() == ErrorKind::ConnectionRefused => fs::remove_file(path)?,
+            Err(e) => return Err(e),
+        }
+    }
+    let listener = UnixListener::bind(path)?;
+    thread::Builder::new()
+        .name("ptt_socket".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                for line in BufReader::new(stream).lines() {
+                    let input = match line.as_ref().map(|line| line.trim()) {
+                        Ok("press") => TalkInput::Press,
+                        Ok("release") => TalkInput::Release,
+                        Ok("toggle") => TalkInput::Toggle,
+                        Ok(_) => continue,
+                        Err(_) => break,
+                    };
+                    if inputs.send(input).is_err() {
+                        return;
+                    }
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Mute the default source, and unmute it while talking, until all inputs have gone away. The
+/// source is left muted.
+#[cfg(feature = "pulseaudio")]
+pub fn run(
+    device: &PulseAudioSoundDevice,
+    mut state: TalkState,
+    inputs: Receiver<TalkInput>,
+) -> Result<(), Error> {
+    device.set_source_muted(&device.default_source(), true)?;
+    loop {
+        let changed = match state.deadline() {
+            Some(deadline) => {
+                let timeout = deadline.saturating_duration_since(Instant::now());
+                match inputs.recv_timeout(timeout) {
+                    Ok(input) => state.input(input, Instant::now()),
+                    Err(RecvTimeoutError::Timeout) => state.expire(Instant::now()),
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            None => match inputs.recv() {
+                Ok(input) => state.input(input, Instant::now()),
+                Err(_) => break,
+            },
+        };
+        if let Some(talking) = changed {
+            device.set_source_muted(&device.default_source(), !talking)?;
+        }
+    }
+    device.set_source_muted(&device.default_source(), true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use std::sync::mpsc::channel;
+
+    // Encode an event as the kernel would on this platform.
+    fn event(kind: u16, code: u16, value: i32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        if cfg!(target_pointer_width = "64") {
+            bytes.extend_from_slice(&1_700_000_000i64.to_ne_bytes());
+            bytes.extend_from_slice(&250_000i64.to_ne_bytes());
+        } else {
+            bytes.extend_from_slice(&1_700_000_000i32.to_ne_bytes());
+            bytes.extend_from_slice(&250_000i32.to_ne_bytes());
+        }
+        bytes.extend_from_slice(&kind.to_ne_bytes());
+        bytes.extend_from_slice(&code.to_ne_bytes());
+        bytes.extend_from_slice(&value.to_ne_bytes());
+        bytes
+    }
+
+    #[test]
+    fn reads_recorded_events() {
+        let f13 = parse_key("KEY_F13").unwrap();
+        assert_eq!(parse_key("f13").unwrap(), 183);
+        assert_eq!(parse_key("275").unwrap(), 275);
+        assert!(parse_key("KEY_NOPE").is_err());
+
+        // Press, repeat and release F13, with a scan code and sync events in between, and
+        // another key press.
+        let mut recording = Vec::new();
+        recording.extend(event(4, 4, 0x64));
+        recording.extend(event(EV_KEY, f13, 1));
+        recording.extend(event(0, 0, 0));
+        recording.extend(event(EV_KEY, f13, 2));
+        recording.extend(event(EV_KEY, 30, 1));
+        recording.extend(event(EV_KEY, f13, 0));
+        recording.extend(event(0, 0, 0));
+
+        let first = InputEvent::read(&mut Cursor::new(&recording)).unwrap();
+        assert_eq!(first.kind, 4);
+        assert_eq!(first.time, Duration::from_millis(1_700_000_000_250));
+
+        let (tx, rx) = channel();
+        let res = watch_events(Cursor::new(recording), f13, tx);
+        assert_eq!(res.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        assert_eq!(
+            rx.iter().collect::<Vec<_>>(),
+            vec![TalkInput::Press, TalkInput::Release]
+        );
+    }
+
+    #[test]
+    fn push_with_release_delay() {
+        let now = Instant::now();
+        let mut state = TalkState::new(TalkMode::Push, Duration::from_millis(300));
+        assert_eq!(state.input(TalkInput::Press, now), Some(true));
+        assert_eq!(state.input(TalkInput::Release, now), None);
+        assert_eq!(state.deadline(), Some(now + Duration::from_millis(300)));
+        assert_eq!(state.expire(now + Duration::from_millis(100)), None);
+
+        // Pressing again during the delay keeps talking.
+        assert_eq!(
+            state.input(TalkInput::Press, now + Duration::from_millis(200)),
+            None
+        );
+        assert_eq!(state.deadline(), None);
+        assert_eq!(
+            state.input(TalkInput::Release, now + Duration::from_millis(400)),
+            None
+        );
+        assert_eq!(state.expire(now + Duration::from_millis(700)), Some(false));
+        assert!(!state.talking());
+
+        let mut state = TalkState::new(TalkMode::Push, Duration::from_secs(0));
+        assert_eq!(state.input(TalkInput::Press, now), Some(true));
+        assert_eq!(state.input(TalkInput::Release, now), Some(false));
+    }
+
+    #[test]
+    fn toggle_to_talk() {
+        let now = Instant::now();
+        let mut state = TalkState::new(TalkMode::Toggle, Duration::from_millis(300));
+        assert_eq!(state.input(TalkInput::Press, now), Some(true));
+        assert_eq!(state.input(TalkInput::Release, now), None);
+        assert_eq!(state.deadline(), None);
+        assert_eq!(state.input(TalkInput::Press, now), Some(false));
+        assert_eq!(state.input(TalkInput::Toggle, now), Some(true));
+    }
+
+    #[test]
+    fn control_socket() {
+        let path =
+            ::std::env::temp_dir().join(format!("platformctl_ptt_{}.sock", ::std::process::id()));
+        let (tx, rx) = channel();
+        spawn_socket(&path, tx).unwrap();
+        let mut client = UnixStream::connect(&path).unwrap();
+        client
+            .write_all(b"press\nbogus\nrelease\ntoggle\n")
+            .unwrap();
+        drop(client);
+
+        let inputs: Vec<_> = (0..3)
+            .map(|_| {
+                rx.recv_timeout(Duration::from_secs(5))
+                    .expect("no input was read")
+            })
+            .collect();
+        assert_eq!(
+            inputs,
+            vec![TalkInput::Press, TalkInput::Release, TalkInput::Toggle]
+        );
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn socket_keeps_other_files() {
+        let path =
+            ::std::env::temp_dir().join(format!("platformctl_ptt_{}.file", ::std::process::id()));
+        fs::write(&path, b"keep").unwrap();
+        let (tx, _rx) = channel();
+        let err = spawn_socket(&path, tx).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&path).unwrap(), b"keep");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn socket_is_not_taken_over() {
+        let path =
+            ::std::env::temp_dir().join(format!("platformctl_ptt_{}.live", ::std::process::id()));
+        // A stale socket nobody listens on any more is replaced.
+        drop(UnixListener::bind(&path).unwrap());
+        let (tx, _rx) = channel();
+        spawn_socket(&path, tx).unwrap();
+
+        let (tx, _rx) = channel();
+        let err = spawn_socket(&path, tx).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AddrInUse);
+        fs::remove_file(path).unwrap();
+    }
+}
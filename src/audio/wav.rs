@@ -0,0 +1,166 @@
+//! A minimal reader for PCM WAV files, as played for feedback sounds.
+
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::time::Duration;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+/// The format of the samples in a WAV file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SampleFormat {
+    U8,
+    S16le,
+    S24le,
+    S32le,
+    F32le,
+}
+
+impl SampleFormat {
+    /// The size of a single sample, in bytes.
+    pub fn size(self) -> usize {
+        match self {
+            SampleFormat::U8 => 1,
+            SampleFormat::S16le => 2,
+            SampleFormat::S24le => 3,
+            SampleFormat::S32le | SampleFormat::F32le => 4,
+        }
+    }
+}
+
+/// The audio data of a WAV file, along with its format.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WavSample {
+    pub format: SampleFormat,
+    pub rate: u32,
+    pub channels: u8,
+    /// Interleaved samples, truncated to whole frames.
+    pub data: Vec<u8>,
+}
+
+fn invalid(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+impl WavSample {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        WavSample::parse(&fs::read(path)?)
+    }
+
+    /// Parse a RIFF WAVE file holding uncompressed PCM or float samples.
+    pub fn parse(bytes: &[u8]) -> Result<Self, Error> {
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+            return Err(invalid("not a wav file"));
+        }
+
+        let mut spec = None;
+        let mut data = None;
+        let mut rest = &bytes[12..];
+        while rest.len() >= 8 {
+            let id = &rest[0..4];
+            let len = LittleEndian::read_u32(&rest[4..8]) as usize;
+            let chunk = &rest[8..];
+            let chunk = &chunk[..len.min(chunk.len())];
+            match id {
+                b"fmt " => {
+                    if chunk.len() < 16 {
+                        return Err(invalid("truncated wav format"));
+                    }
+                    let tag = LittleEndian::read_u16(&chunk[0..2]);
+                    let channels = LittleEndian::read_u16(&chunk[2..4]);
+                    let rate = LittleEndian::read_u32(&chunk[4..8]);
+                    let bits = LittleEndian::read_u16(&chunk[14..16]);
+                    // WAVE_FORMAT_EXTENSIBLE keeps the actual tag in its sub-format.
+                    let tag = if tag == 0xfffe && chunk.len() >= 26 {
+                        LittleEndian::read_u16(&chunk[24..26])
+                    } else {
+                        tag
+                    };
+                    let format = match (tag, bits) {
+                        (1, 8) => SampleFormat::U8,
+                        (1, 16) => SampleFormat::S16le,
+                        (1, 24) => SampleFormat::S24le,
+                        (1, 32) => SampleFormat::S32le,
+                        (3, 32) => SampleFormat::F32le,
+                        _ => return Err(invalid("unsupported wav sample format")),
+                    };
+                    if channels == 0 || channels > 32 || rate == 0 {
+                        return Err(invalid("invalid wav format"));
+                    }
+                    spec = Some((format, rate, channels as u8));
+                }
+                b"data" => data = Some(chunk),
+                _ => {}
+            }
+            // Chunks are padded to an even length.
+            let next = match len.checked_add(8 + (len & 1)) {
+                Some(next) if next <= rest.len() => next,
+                _ => break,
+            };
+            rest = &rest[next..];
+        }
+
+        let (format, rate, channels) = spec.ok_or_else(|| invalid("wav file has no format"))?;
+        let data = data.ok_or_else(|| invalid("wav file has no data"))?;
+        let frame = format.size() * channels as usize;
+        let data = data[..data.len() - data.len() % frame].to_vec();
+        if data.is_empty() {
+            return Err(invalid("wav file is empty"));
+        }
+        Ok(WavSample {
+            format,
+            rate,
+            channels,
+            data,
+        })
+    }
+
+    pub fn duration(&self) -> Duration {
+        let frames = self.data.len() / (self.format.size() * self.channels as usize);
+        Duration::from_secs_f64(frames as f64 / self.rate as f64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wav(fmt: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut body = b"WAVEfmt ".to_vec();
+        body.extend_from_slice(&(fmt.len() as u32).to_le_bytes());
+        body.extend_from_slice(fmt);
+        body.extend_from_slice(b"LIST\x03\x00\x00\x00abc\x00");
+        body.extend_from_slice(b"data");
+        body.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        body.extend_from_slice(data);
+
+        let mut file = b"RIFF".to_vec();
+        file.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        file.extend_from_slice(&body);
+        file
+    }
+
+    #[test]
+    fn parse_pcm() {
+        // 16-bit stereo at 8kHz, with a trailing partial frame.
+        let fmt = [1, 0, 2, 0, 0x40, 0x1f, 0, 0, 0, 0x7d, 0, 0, 4, 0, 16, 0];
+        let sample = WavSample::parse(&wav(&fmt, &[1, 2, 3, 4, 5, 6, 7, 8, 9])).unwrap();
+        assert_eq!(sample.format, SampleFormat::S16le);
+        assert_eq!(sample.rate, 8000);
+        assert_eq!(sample.channels, 2);
+        assert_eq!(sample.data, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!(sample.duration(), Duration::from_micros(250));
+    }
+
+    #[test]
+    fn reject_invalid() {
+        assert!(WavSample::parse(b"RIFF\x04\x00\x00\x00WAVE").is_err());
+        assert!(WavSample::parse(b"not a wav file").is_err());
+        // A-law is not supported.
+        let fmt = [6, 0, 1, 0, 0x40, 0x1f, 0, 0, 0x40, 0x1f, 0, 0, 1, 0, 8, 0];
+        assert!(WavSample::parse(&wav(&fmt, &[0; 4])).is_err());
+        // A chunk claiming to be as long as possible ends parsing instead of overflowing.
+        assert!(WavSample::parse(b"RIFF\x10\x00\x00\x00WAVELIST\xff\xff\xff\xffabcd").is_err());
+    }
+}
@@ -1,7 +1,29 @@
-use clap::{crate_authors, crate_version, App, Arg, SubCommand, AppSettings};
+use clap::{crate_authors, crate_version, App, Arg, ArgMatches, SubCommand, AppSettings};
+#[cfg(feature = "pulseaudio")]
+use std::path::PathBuf;
+use std::sync::mpsc::Receiver;
 
-use platformctl::audio::pulseaudio::PulseAudioSoundDevice;
+use platformctl::audio::{
+    self, parse_volume, AudioEvent, Backend, SoundDevice, VolumeChange, VolumePolicy, VolumeScale,
+};
+#[cfg(feature = "pulseaudio")]
+use platformctl::audio::led::MuteLeds;
+#[cfg(feature = "pulseaudio")]
+use platformctl::audio::push_to_talk::{self, TalkMode, TalkState};
+#[cfg(feature = "alsa")]
+use platformctl::audio::alsa::AlsaSoundDevice;
+#[cfg(feature = "pulseaudio")]
+use platformctl::audio::pulseaudio::{
+    DeviceId, Feedback, MeterTarget, Module, PulseAudioOptions, PulseAudioSoundDevice,
+};
+#[cfg(feature = "pulseaudio")]
+use platformctl::audio::pulseaudio::autoswitch::{AutoSwitch, Rule};
+#[cfg(feature = "pulseaudio")]
+use platformctl::audio::pulseaudio::scene::SceneStore;
+#[cfg(feature = "pulseaudio")]
+use platformctl::audio::SinkSelector;
 use platformctl::backlight::Backlight;
+use platformctl::osd::{Osd, OsdKind};
 
 fn parse_bool(value: &str, current: bool) -> bool {
     match value {
@@ -16,7 +38,938 @@ fn parse_bool(value: &str, current: bool) -> bool {
     }
 }
 
+// A missing notification daemon should not fail the change itself, so only warn about it.
+fn show_osd(kind: OsdKind, value: f32, muted: bool) {
+    let res = Osd::new().and_then(|osd| osd.show(kind, value, muted));
+    if let Err(e) = res {
+        eprintln!("warning: unable to show notification: {:}", e);
+    }
+}
+
+fn parse_scale(val: &ArgMatches) -> VolumeScale {
+    match val.value_of_lossy("scale") {
+        None => VolumeScale::default(),
+        Some(v) => match v.parse() {
+            Err(e) => {
+                eprintln!("unable to parse scale: {:}", e);
+                std::process::exit(1);
+            }
+            Ok(v) => v,
+        },
+    }
+}
+
+// Parse a volume change along with its cap, converted to the scale of the change.
+fn parse_change(v: &str, val: &ArgMatches, scale: VolumeScale) -> (VolumeChange, Option<f32>) {
+    let change = match VolumeChange::parse(v, scale) {
+        Err(e) => {
+            eprintln!("unable to parse volume: {:}", e);
+            std::process::exit(1);
+        }
+        Ok(v) => v,
+    };
+    let scale = match change {
+        VolumeChange::Step(_, scale) | VolumeChange::Set(_, scale) => scale,
+    };
+    let cap: Option<f32> = match val.value_of_lossy("max") {
+        None => None,
+        Some(v) => {
+            match parse_volume(&v, scale) {
+                Err(e) => {
+                    eprintln!("unable to parse max: {:}", e);
+                    std::process::exit(1);
+                }
+                Ok((v, cap_scale)) => {
+                    Some(scale.from_fraction(cap_scale.to_fraction(v)))
+                }
+            }
+        },
+    };
+    (change, cap)
+}
+
+// The volume policy allowing the boost of a volume change, if one was given.
+fn parse_policy(val: &ArgMatches) -> Option<VolumePolicy> {
+    let v = val.value_of_lossy("boost")?;
+    let boost = match parse_volume(&v, VolumeScale::Linear) {
+        Err(e) => {
+            eprintln!("unable to parse boost: {:}", e);
+            std::process::exit(1);
+        }
+        Ok((v, boost_scale)) => boost_scale.to_fraction(v),
+    };
+    Some(VolumePolicy {
+        boost: Some(boost),
+        ..VolumePolicy::default()
+    })
+}
+
+// Warn about changes the volume policy let past the hard maximum.
+fn warn_boost(events: &Receiver<AudioEvent>) {
+    for event in events.try_iter() {
+        if let AudioEvent::BoostEngaged { volume, .. } = event {
+            eprintln!("warning: boosting volume to {:.0}%", volume * 100.0);
+        }
+    }
+}
+
+#[cfg(feature = "alsa")]
+fn alsa_main(sub: &ArgMatches) -> ! {
+    let card = sub.value_of_lossy("card").unwrap_or_else(|| "default".into());
+    let element = sub.value_of_lossy("element");
+    let a = match AlsaSoundDevice::new(&card, element.as_ref().map(|e| e.as_ref())) {
+        Err(_) => {
+            eprintln!("could not initialize an audio connector");
+            std::process::exit(2);
+        }
+        Ok(v) => v,
+    };
+    device_main(Box::new(a), sub);
+}
+
+// Handle the subcommands every backend supports.
+fn device_main(mut a: Box<dyn SoundDevice>, sub: &ArgMatches) -> ! {
+    match sub.subcommand() {
+        ("mute", Some(val)) => {
+            match val.value_of_lossy("state") {
+                Some(state) => {
+                    let mute_state = parse_bool(&state, a.muted());
+                    match a.set_muted(mute_state) {
+                        Err(e) => {
+                            eprintln!("unable to change mute: {:}", e);
+                            std::process::exit(3);
+                        }
+                        Ok(_) => (),
+                    };
+                    if val.is_present("osd") {
+                        show_osd(OsdKind::Volume, a.volume_scale().to_fraction(a.volume()), a.muted());
+                    }
+                },
+                None => {
+                    eprintln!("Must specify mute state");
+                    std::process::exit(1);
+                },
+            };
+        },
+        ("volume", Some(val)) => {
+            let scale = parse_scale(val);
+            match val.value_of_lossy("add") {
+                Some(v) => {
+                    let (change, cap) = parse_change(&v, val, scale);
+                    if let Some(policy) = parse_policy(val) {
+                        a.set_volume_policy(policy);
+                    }
+                    let events = a.subscribe();
+                    let res = match change {
+                        VolumeChange::Step(step, scale) => {
+                            a.set_volume_scale(scale);
+                            a.add_volume(step, cap)
+                        }
+                        VolumeChange::Set(value, scale) => {
+                            a.set_volume_scale(scale);
+                            a.set_volume(value, cap)
+                        }
+                    };
+                    warn_boost(&events);
+                    match res {
+                        Err(e) => {
+                            eprintln!("unable to change volume: {:}", e);
+                            std::process::exit(3);
+                        }
+                        Ok(_) => (),
+                    };
+                    if val.is_present("osd") {
+                        show_osd(OsdKind::Volume, a.volume_scale().to_fraction(a.volume()), a.muted());
+                    }
+                },
+                None => {
+                    a.set_volume_scale(scale);
+                    println!("{}", a.volume());
+                },
+            };
+        },
+        ("channels", Some(_)) => {
+            for (name, volume) in a.channel_names().iter().zip(a.channel_volumes()) {
+                println!("{}\t{}", name, volume);
+            }
+        },
+        (name, Some(_)) => {
+            eprintln!("{} is not supported by the {} backend", name, a.backend());
+            std::process::exit(1);
+        },
+        _ => {
+            println!("{}", a.volume());
+        }
+    }
+    std::process::exit(0);
+}
+
+// Handle the PulseAudio-specific options and subcommands.
+#[cfg(feature = "pulseaudio")]
+fn parse_u32(value: &str, what: &str) -> u32 {
+    match value.parse() {
+        Err(e) => {
+            eprintln!("unable to parse {}: {:}", what, e);
+            std::process::exit(1);
+        }
+        Ok(v) => v,
+    }
+}
+
+#[cfg(feature = "pulseaudio")]
+fn module_main(p: &PulseAudioSoundDevice, sub: &ArgMatches) {
+    let module = match sub.subcommand() {
+        ("list", Some(_)) => {
+            let modules = match p.list_modules() {
+                Err(e) => {
+                    eprintln!("unable to list modules: {:}", e);
+                    std::process::exit(3);
+                }
+                Ok(v) => v,
+            };
+            for module in modules {
+                println!("{}\t{}\t{}", module.index, module.name, module.argument);
+            }
+            return;
+        },
+        ("unload", Some(val)) => {
+            let index = parse_u32(&val.value_of_lossy("index").unwrap(), "module index");
+            if let Err(e) = p.unload_module(index) {
+                eprintln!("unable to unload module: {:}", e);
+                std::process::exit(3);
+            }
+            return;
+        },
+        ("load", Some(val)) => Module::Other {
+            name: val.value_of_lossy("name").unwrap().to_string(),
+            argument: val
+                .values_of_lossy("argument")
+                .unwrap_or_default()
+                .join(" "),
+        },
+        ("null-sink", Some(val)) => Module::NullSink {
+            name: val.value_of_lossy("name").unwrap().to_string(),
+            description: val.value_of_lossy("description").map(|v| v.to_string()),
+            channels: val.value_of_lossy("channels").map(|v| {
+                match v.parse() {
+                    Err(e) => {
+                        eprintln!("unable to parse channels: {:}", e);
+                        std::process::exit(1);
+                    }
+                    Ok(v) => v,
+                }
+            }),
+        },
+        ("loopback", Some(val)) => Module::Loopback {
+            source: val.value_of_lossy("source").map(|v| v.to_string()),
+            sink: val.value_of_lossy("sink").map(|v| v.to_string()),
+            latency_msec: val.value_of_lossy("latency").map(|v| parse_u32(&v, "latency")),
+        },
+        ("combine", Some(val)) => Module::CombineSink {
+            name: val.value_of_lossy("name").unwrap().to_string(),
+            description: val.value_of_lossy("description").map(|v| v.to_string()),
+            sinks: val.values_of_lossy("sinks").unwrap_or_default(),
+        },
+        _ => {
+            eprintln!("{}", sub.usage());
+            std::process::exit(1);
+        },
+    };
+    match p.load_module(&module) {
+        Err(e) => {
+            eprintln!("unable to load module: {:}", e);
+            std::process::exit(3);
+        }
+        Ok(index) => println!("{}", index),
+    };
+}
+
+#[cfg(feature = "pulseaudio")]
+fn scene_main(p: &PulseAudioSoundDevice, sub: &ArgMatches) {
+    let store = match SceneStore::open_default() {
+        Err(e) => {
+            eprintln!("unable to find scenes: {:}", e);
+            std::process::exit(1);
+        }
+        Ok(v) => v,
+    };
+    match sub.subcommand() {
+        ("list", Some(_)) => {
+            let scenes = match store.list() {
+                Err(e) => {
+                    eprintln!("unable to list scenes: {:}", e);
+                    std::process::exit(1);
+                }
+                Ok(v) => v,
+            };
+            for scene in scenes {
+                println!("{}", scene);
+            }
+        },
+        ("save", Some(val)) => {
+            let name = val.value_of_lossy("name").unwrap();
+            let scene = match p.capture_scene() {
+                Err(e) => {
+                    eprintln!("unable to capture scene: {:}", e);
+                    std::process::exit(3);
+                }
+                Ok(v) => v,
+            };
+            if let Err(e) = store.save(&name, &scene) {
+                eprintln!("unable to save scene: {:}", e);
+                std::process::exit(1);
+            }
+        },
+        ("apply", Some(val)) => {
+            let name = val.value_of_lossy("name").unwrap();
+            let scene = match store.load(&name) {
+                Err(e) => {
+                    eprintln!("unable to load scene: {:}", e);
+                    std::process::exit(1);
+                }
+                Ok(v) => v,
+            };
+            match p.apply_scene(&scene) {
+                Err(e) => {
+                    eprintln!("unable to apply scene: {:}", e);
+                    std::process::exit(3);
+                }
+                Ok(warnings) => {
+                    for warning in warnings {
+                        eprintln!("warning: {}", warning);
+                    }
+                }
+            };
+        },
+        _ => {
+            eprintln!("{}", sub.usage());
+            std::process::exit(1);
+        },
+    }
+}
+
+#[cfg(feature = "pulseaudio")]
+fn pulse_main(sub: &ArgMatches) -> ! {
+    let options = PulseAudioOptions {
+        server: sub.value_of("server").map(|v| v.to_string()),
+        ..PulseAudioOptions::default()
+    };
+    let mut p = match PulseAudioSoundDevice::with_options(
+        SinkSelector::FollowDefault,
+        true,
+        options,
+    ) {
+        Err(_) => {
+            eprintln!("could not initialize an audio connector");
+            std::process::exit(2);
+        }
+        Ok(v) => v,
+    };
+
+    if let Some(pattern) = sub.value_of_lossy("sink") {
+        let name = match p.find_sink(&pattern) {
+            Err(e) => {
+                eprintln!("unable to list sinks: {:}", e);
+                std::process::exit(3);
+            }
+            Ok(None) => {
+                eprintln!("no sink matching {:}", pattern);
+                std::process::exit(1);
+            }
+            Ok(Some(name)) => name,
+        };
+        match p.set_sink(SinkSelector::Fixed(name)) {
+            Err(e) => {
+                eprintln!("unable to select sink: {:}", e);
+                std::process::exit(3);
+            }
+            Ok(_) => (),
+        };
+    }
+
+    match sub.subcommand() {
+        ("volume", Some(val)) => {
+            let scale = parse_scale(val);
+            match val.value_of_lossy("add") {
+                Some(v) => {
+                    let (change, cap) = parse_change(&v, val, scale);
+                    let scale = match change {
+                        VolumeChange::Step(_, scale) | VolumeChange::Set(_, scale) => scale,
+                    };
+                    if let Some(policy) = parse_policy(val) {
+                        p.set_volume_policy(policy);
+                    }
+                    if val.is_present("feedback") {
+                        let feedback = Feedback {
+                            file: val.value_of_os("feedback").map(PathBuf::from),
+                            ..Feedback::default()
+                        };
+                        match p.set_feedback(Some(feedback)) {
+                            Err(e) => {
+                                eprintln!("unable to load feedback sound: {:}", e);
+                                std::process::exit(3);
+                            }
+                            Ok(_) => (),
+                        };
+                    }
+                    p.set_volume_scale(scale);
+                    let events = p.subscribe();
+                    let res = match change {
+                        VolumeChange::Step(step, _) => p.add_volume(step, cap),
+                        VolumeChange::Set(value, _) => p.set_volume(value, cap),
+                    };
+                    warn_boost(&events);
+                    match res {
+                        Err(e) => {
+                            eprintln!("unable to change volume: {:}", e);
+                            std::process::exit(3);
+                        }
+                        Ok(_) => (),
+                    };
+                    if val.is_present("osd") {
+                        show_osd(OsdKind::Volume, p.volume_scale().to_fraction(p.volume()), p.muted());
+                    }
+                },
+                None => {
+                    p.set_volume_scale(scale);
+                    println!("{}", p.volume());
+                },
+            };
+        },
+        ("balance", Some(val)) => {
+            match val.value_of_lossy("value") {
+                Some(v) => {
+                    let balance: f32 = match v.parse() {
+                        Err(e) => {
+                            eprintln!("unable to parse balance: {:}", e);
+                            std::process::exit(1);
+                        }
+                        Ok(v) => v,
+                    };
+                    match p.set_balance(balance) {
+                        Err(e) => {
+                            eprintln!("unable to set balance: {:}", e);
+                            std::process::exit(3);
+                        }
+                        Ok(_) => (),
+                    };
+                },
+                None => println!("{}", p.balance()),
+            };
+        },
+        ("fade", Some(val)) => {
+            match val.value_of_lossy("value") {
+                Some(v) => {
+                    let fade: f32 = match v.parse() {
+                        Err(e) => {
+                            eprintln!("unable to parse fade: {:}", e);
+                            std::process::exit(1);
+                        }
+                        Ok(v) => v,
+                    };
+                    match p.set_fade(fade) {
+                        Err(e) => {
+                            eprintln!("unable to set fade: {:}", e);
+                            std::process::exit(3);
+                        }
+                        Ok(_) => (),
+                    };
+                },
+                None => println!("{}", p.fade()),
+            };
+        },
+        ("sinks", Some(_)) => {
+            let sinks = match p.sinks() {
+                Err(e) => {
+                    eprintln!("unable to list sinks: {:}", e);
+                    std::process::exit(3);
+                }
+                Ok(v) => v,
+            };
+            let current = p.sink_name();
+            for sink in sinks {
+                let bluetooth = sink.bluetooth.unwrap_or_default();
+                println!(
+                    "{}{}\t{}\t{}\t{}{}{}{}{}",
+                    if sink.name == current { "*" } else { " " },
+                    sink.index,
+                    sink.name,
+                    sink.description,
+                    sink.volume,
+                    if sink.muted { "\tmuted" } else { "" },
+                    if sink.suspended { "\tsuspended" } else { "" },
+                    bluetooth.battery.map_or(String::new(), |b| format!("\tbattery {}%", b)),
+                    bluetooth.codec.map_or(String::new(), |c| format!("\tcodec {}", c)),
+                );
+            }
+        },
+        ("sources", Some(_)) => {
+            let sources = match p.sources() {
+                Err(e) => {
+                    eprintln!("unable to list sources: {:}", e);
+                    std::process::exit(3);
+                }
+                Ok(v) => v,
+            };
+            for source in sources {
+                println!(
+                    "{}\t{}\t{}{}",
+                    source.index,
+                    source.name,
+                    source.description,
+                    if source.suspended { "\tsuspended" } else { "" },
+                );
+            }
+        },
+        ("suspend", Some(val)) => {
+            let source = val.value_of_lossy("source");
+            // A source is looked up by name first, so that names made of digits still work.
+            let current = match &source {
+                Some(source) => p.sources().map(|sources| {
+                    sources
+                        .iter()
+                        .find(|s| s.name == *source)
+                        .or_else(|| sources.iter().find(|s| s.index.to_string() == *source))
+                        .map(|s| (DeviceId::Index(s.index), s.suspended))
+                }),
+                None => p.sinks().map(|sinks| {
+                    let name = p.sink_name();
+                    sinks
+                        .into_iter()
+                        .find(|s| s.name == name)
+                        .map(|s| (DeviceId::Name(s.name), s.suspended))
+                }),
+            };
+            let current = match current {
+                Err(e) => {
+                    eprintln!("unable to query suspend state: {:}", e);
+                    std::process::exit(3);
+                }
+                Ok(None) => {
+                    eprintln!("no such device");
+                    std::process::exit(1);
+                }
+                Ok(Some(v)) => v,
+            };
+            let (device, current) = current;
+            match val.value_of_lossy("state") {
+                Some(state) => {
+                    let suspend = parse_bool(&state, current);
+                    let res = match &source {
+                        Some(_) => p.suspend_source(&device, suspend),
+                        None => p.suspend_sink(&device, suspend),
+                    };
+                    match res {
+                        Err(e) => {
+                            eprintln!("unable to change suspend state: {:}", e);
+                            std::process::exit(3);
+                        }
+                        Ok(_) => (),
+                    };
+                },
+                None => println!("{}", if current { "on" } else { "off" }),
+            };
+        },
+        ("meter", Some(val)) => {
+            let rate: u32 = match val.value_of_lossy("rate").unwrap_or("10".into()).parse() {
+                Err(e) => {
+                    eprintln!("unable to parse rate: {:}", e);
+                    std::process::exit(1);
+                }
+                Ok(v) => v,
+            };
+            let count: Option<usize> = match val.value_of_lossy("count") {
+                None => None,
+                Some(v) => match v.parse() {
+                    Err(e) => {
+                        eprintln!("unable to parse count: {:}", e);
+                        std::process::exit(1);
+                    }
+                    Ok(v) => Some(v),
+                },
+            };
+            let target = match val.value_of_lossy("source") {
+                Some(source) => MeterTarget::Source(source.to_string()),
+                None => MeterTarget::Sink(p.sink_name()),
+            };
+            let meter = match p.meter(target, rate) {
+                Err(e) => {
+                    eprintln!("unable to open meter: {:}", e);
+                    std::process::exit(3);
+                }
+                Ok(v) => v,
+            };
+            let levels = meter.levels().iter();
+            let levels: Box<dyn Iterator<Item = f32>> = match count {
+                Some(count) => Box::new(levels.take(count)),
+                None => Box::new(levels),
+            };
+            for level in levels {
+                println!("{:.3}", level);
+            }
+        },
+        ("module", Some(val)) => module_main(&p, val),
+        ("scene", Some(val)) => scene_main(&p, val),
+        ("push-to-talk", Some(val)) => {
+            let mode = if val.is_present("toggle") { TalkMode::Toggle } else { TalkMode::Push };
+            let delay = match val.value_of_lossy("release-delay").unwrap_or("0".into()).parse() {
+                Err(e) => {
+                    eprintln!("unable to parse release delay: {:}", e);
+                    std::process::exit(1);
+                }
+                Ok(v) => std::time::Duration::from_millis(v),
+            };
+            let (tx, rx) = std::sync::mpsc::channel();
+            if let Some(device) = val.value_of_os("device") {
+                let key = match push_to_talk::parse_key(&val.value_of_lossy("key").unwrap()) {
+                    Err(e) => {
+                        eprintln!("{:}", e);
+                        std::process::exit(1);
+                    }
+                    Ok(v) => v,
+                };
+                if let Err(e) = push_to_talk::spawn_evdev(device.as_ref(), key, tx.clone()) {
+                    eprintln!("unable to open input device: {:}", e);
+                    std::process::exit(1);
+                }
+            }
+            if val.is_present("socket") || !val.is_present("device") {
+                let socket = match val
+                    .value_of_os("socket")
+                    .map(PathBuf::from)
+                    .or_else(push_to_talk::default_socket)
+                {
+                    Some(socket) => socket,
+                    None => {
+                        eprintln!("XDG_RUNTIME_DIR is not set, pass a path to --socket");
+                        std::process::exit(1);
+                    }
+                };
+                if let Err(e) = push_to_talk::spawn_socket(&socket, tx.clone()) {
+                    eprintln!("unable to listen on {}: {:}", socket.display(), e);
+                    std::process::exit(1);
+                }
+            }
+            drop(tx);
+            if let Err(e) = push_to_talk::run(&p, TalkState::new(mode, delay), rx) {
+                eprintln!("push-to-talk stopped: {:}", e);
+                std::process::exit(3);
+            }
+        },
+        ("mute-leds", Some(_)) => {
+            let leds = match MuteLeds::detect() {
+                Err(e) => {
+                    eprintln!("unable to detect mute LEDs: {:}", e);
+                    std::process::exit(1);
+                }
+                Ok(v) => v,
+            };
+            if leds.is_empty() {
+                eprintln!("no mute LEDs found");
+                std::process::exit(1);
+            }
+            if let Err(e) = leds.sync(&p) {
+                eprintln!("unable to sync mute LEDs: {:}", e);
+                std::process::exit(3);
+            }
+        },
+        ("autoswitch", Some(val)) => {
+            let rules = val
+                .values_of_lossy("rule")
+                .unwrap_or_default()
+                .iter()
+                .map(|rule| rule.parse::<Rule>())
+                .collect::<Result<Vec<_>, _>>();
+            let rules = match rules {
+                Err(e) => {
+                    eprintln!("{:}", e);
+                    std::process::exit(1);
+                }
+                Ok(v) => v,
+            };
+            let res = AutoSwitch::new(rules).run(&p, |sink| println!("{}", sink));
+            if let Err(e) = res {
+                eprintln!("autoswitch stopped: {:}", e);
+                std::process::exit(3);
+            }
+        },
+        _ => device_main(Box::new(p), sub),
+    }
+    std::process::exit(0);
+}
+
 fn main() {
+    let audio = SubCommand::with_name("audio")
+        .about("Control audio devices")
+        .arg(
+            Arg::with_name("backend")
+                .long("backend")
+                .takes_value(true)
+                .help("Backends to try in order, comma-separated (pulseaudio, pipewire, alsa)"),
+        )
+        .arg(
+            Arg::with_name("server")
+                .long("server")
+                .takes_value(true)
+                .help("PulseAudio server to connect to (e.g. unix:/path/to/socket, tcp:host:4713)"),
+        )
+        .arg(
+            Arg::with_name("sink")
+                .long("sink")
+                .takes_value(true)
+                .help("Sink to control, by name, index or description glob"),
+        )
+        .subcommand(SubCommand::with_name("volume")
+            .setting(AppSettings::AllowLeadingHyphen)
+            .arg(
+                Arg::with_name("add")
+                    .help("Value to add to sound volume (e.g. 0.05, +5%, -3dB), or =value to set it")
+                    .index(1),
+            )
+            .arg(
+                Arg::with_name("max")
+                    .help("Max value to cap volume to")
+                    .index(2),
+            )
+            .arg(
+                Arg::with_name("scale")
+                    .long("scale")
+                    .takes_value(true)
+                    .possible_values(&["linear", "cubic", "db"])
+                    .help("Scale to read volume and interpret bare numbers in"),
+            )
+            .arg(
+                Arg::with_name("boost")
+                    .long("boost")
+                    .takes_value(true)
+                    .help("Allow amplifying past the sink's base volume, or 100%, up to this limit"),
+            )
+            .arg(
+                Arg::with_name("feedback")
+                    .long("feedback")
+                    .takes_value(true)
+                    .min_values(0)
+                    .help("Play a sound on the sink after changing its volume, optionally from a WAV file"),
+            )
+            .arg(
+                Arg::with_name("osd")
+                    .long("osd")
+                    .help("Show the new volume through the desktop notification daemon"),
+            )
+        )
+        .subcommand(SubCommand::with_name("mute")
+            .arg(
+                Arg::with_name("state")
+                    .help("Mute state to set (on|off|toggle)")
+                    .index(1),
+            )
+            .arg(
+                Arg::with_name("osd")
+                    .long("osd")
+                    .help("Show the new mute state through the desktop notification daemon"),
+            )
+        )
+        .subcommand(SubCommand::with_name("mute"))
+        .subcommand(SubCommand::with_name("balance")
+            .setting(AppSettings::AllowLeadingHyphen)
+            .arg(
+                Arg::with_name("value")
+                    .help("Balance to set, from -1.0 (left) to 1.0 (right)")
+                    .index(1),
+            )
+        )
+        .subcommand(SubCommand::with_name("fade")
+            .setting(AppSettings::AllowLeadingHyphen)
+            .arg(
+                Arg::with_name("value")
+                    .help("Fade to set, from -1.0 (rear) to 1.0 (front)")
+                    .index(1),
+            )
+        )
+        .subcommand(SubCommand::with_name("channels")
+            .about("Show per-channel volumes")
+        )
+        .subcommand(SubCommand::with_name("sinks")
+            .about("List available sinks")
+        )
+        .subcommand(SubCommand::with_name("sources")
+            .about("List available sources")
+        )
+        .subcommand(SubCommand::with_name("suspend")
+            .about("Suspend the sink, or a source, releasing its device")
+            .arg(
+                Arg::with_name("state")
+                    .help("Suspend state to set (on|off|toggle)")
+                    .index(1),
+            )
+            .arg(
+                Arg::with_name("source")
+                    .long("source")
+                    .takes_value(true)
+                    .help("Source to suspend instead of the sink, by name or index"),
+            )
+        )
+        .subcommand(SubCommand::with_name("meter")
+            .about("Print peak levels of the sink, or of a source")
+            .arg(
+                Arg::with_name("source")
+                    .long("source")
+                    .takes_value(true)
+                    .help("Source to measure instead of the sink's monitor"),
+            )
+            .arg(
+                Arg::with_name("rate")
+                    .long("rate")
+                    .takes_value(true)
+                    .help("Levels to print per second (default: 10)"),
+            )
+            .arg(
+                Arg::with_name("count")
+                    .long("count")
+                    .takes_value(true)
+                    .help("Stop after printing this many levels"),
+            )
+        )
+        .subcommand(SubCommand::with_name("push-to-talk")
+            .about("Keep the default source muted except while a key is held")
+            .arg(
+                Arg::with_name("device")
+                    .long("device")
+                    .takes_value(true)
+                    .requires("key")
+                    .help("Input device to read the key from (e.g. /dev/input/event3)"),
+            )
+            .arg(
+                Arg::with_name("key")
+                    .long("key")
+                    .takes_value(true)
+                    .help("Key to talk with, by name or code (e.g. KEY_F13, BTN_SIDE, 183)"),
+            )
+            .arg(
+                Arg::with_name("socket")
+                    .long("socket")
+                    .takes_value(true)
+                    .min_values(0)
+                    .help("Also take press, release and toggle commands from a socket (default without --device)"),
+            )
+            .arg(
+                Arg::with_name("toggle")
+                    .long("toggle")
+                    .help("Start and stop talking with each press instead"),
+            )
+            .arg(
+                Arg::with_name("release-delay")
+                    .long("release-delay")
+                    .takes_value(true)
+                    .help("Milliseconds to keep talking after the key is released"),
+            )
+        )
+        .subcommand(SubCommand::with_name("mute-leds")
+            .about("Keep the speaker and microphone mute LEDs in sync with the default sink and source")
+        )
+        .subcommand(SubCommand::with_name("autoswitch")
+            .about("Keep switching the default sink to the best connected device, printing each switch")
+            .arg(
+                Arg::with_name("rule")
+                    .long("rule")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+                    .required(true)
+                    .help("Priority for matching sinks, as PRIORITY:KEY=GLOB[,KEY=GLOB...] (e.g. 100:device.bus=bluetooth, 10:name=alsa_output.*)"),
+            )
+        )
+        .subcommand(SubCommand::with_name("scene")
+            .about("Save and apply snapshots of the mixer state")
+            .subcommand(SubCommand::with_name("list")
+                .about("List saved scenes")
+            )
+            .subcommand(SubCommand::with_name("save")
+                .about("Save the current sinks, sources, volumes and profiles as a scene")
+                .arg(Arg::with_name("name").required(true).index(1))
+            )
+            .subcommand(SubCommand::with_name("apply")
+                .about("Restore a saved scene")
+                .arg(Arg::with_name("name").required(true).index(1))
+            )
+        )
+        .subcommand(SubCommand::with_name("module")
+            .about("Load, unload and list server modules")
+            .subcommand(SubCommand::with_name("list")
+                .about("List loaded modules")
+            )
+            .subcommand(SubCommand::with_name("load")
+                .about("Load a module by name, printing its index")
+                .setting(AppSettings::TrailingVarArg)
+                .arg(Arg::with_name("name").required(true).index(1))
+                .arg(Arg::with_name("argument").multiple(true).index(2))
+            )
+            .subcommand(SubCommand::with_name("unload")
+                .about("Unload a module by index")
+                .arg(Arg::with_name("index").required(true).index(1))
+            )
+            .subcommand(SubCommand::with_name("null-sink")
+                .about("Create a sink that discards its input")
+                .arg(Arg::with_name("name").required(true).index(1))
+                .arg(
+                    Arg::with_name("description")
+                        .long("description")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("channels")
+                        .long("channels")
+                        .takes_value(true),
+                )
+            )
+            .subcommand(SubCommand::with_name("loopback")
+                .about("Play a source to a sink")
+                .arg(
+                    Arg::with_name("source")
+                        .long("source")
+                        .takes_value(true)
+                        .help("Source to play (default: the default source)"),
+                )
+                .arg(
+                    Arg::with_name("sink")
+                        .long("sink")
+                        .takes_value(true)
+                        .help("Sink to play to (default: the default sink)"),
+                )
+                .arg(
+                    Arg::with_name("latency")
+                        .long("latency")
+                        .takes_value(true)
+                        .help("Latency in milliseconds"),
+                )
+            )
+            .subcommand(SubCommand::with_name("combine")
+                .about("Create a sink that plays to several sinks")
+                .arg(Arg::with_name("name").required(true).index(1))
+                .arg(Arg::with_name("sinks").required(true).multiple(true).index(2))
+                .arg(
+                    Arg::with_name("description")
+                        .long("description")
+                        .takes_value(true),
+                )
+            )
+        );
+
+    #[cfg(feature = "alsa")]
+    let audio = audio
+        .arg(
+            Arg::with_name("card")
+                .long("card")
+                .takes_value(true)
+                .help("ALSA card to control, bypassing PulseAudio"),
+        )
+        .arg(
+            Arg::with_name("element")
+                .long("element")
+                .takes_value(true)
+                .help("ALSA mixer element to control (default: Master, PCM or Capture)"),
+        );
+
     let matches = App::new("platformctl")
         .version(crate_version!())
         .author(crate_authors!())
@@ -28,33 +981,14 @@ fn main() {
                         .help("Value to add to backlight output")
                         .index(1),
                 )
-                .arg(Arg::with_name("device").help("Device to access")),
-        )
-        .subcommand(
-            SubCommand::with_name("audio")
-                .about("Control audio devices")
-                .subcommand(SubCommand::with_name("volume")
-                    .setting(AppSettings::AllowLeadingHyphen)
-                    .arg(
-                        Arg::with_name("add")
-                            .help("Value to add to sound volume")
-                            .index(1),
-                    )
-                    .arg(
-                        Arg::with_name("max")
-                            .help("Max value to cap volume to")
-                            .index(2),
-                    )
-                )
-                .subcommand(SubCommand::with_name("mute")
-                    .arg(
-                        Arg::with_name("state")
-                            .help("Mute state to set (on|off|toggle)")
-                            .index(1),
-                    )
-                )
-                .subcommand(SubCommand::with_name("mute"))
+                .arg(Arg::with_name("device").help("Device to access"))
+                .arg(
+                    Arg::with_name("osd")
+                        .long("osd")
+                        .help("Show the new brightness through the desktop notification daemon"),
+                ),
         )
+        .subcommand(audio)
         .get_matches();
 
     match matches.subcommand() {
@@ -89,77 +1023,65 @@ fn main() {
                             Ok(_) => (),
                         },
                     };
+                    if sub.is_present("osd") {
+                        show_osd(OsdKind::Brightness, b.brightness(), false);
+                    }
                 }
             };
         }
         ("audio", Some(sub)) => {
-            let mut p = match PulseAudioSoundDevice::new(|| {}, true) {
-                Err(_) => {
-                    eprintln!("could not initialize an audio connector");
-                    std::process::exit(2);
+            #[cfg(feature = "alsa")]
+            {
+                if sub.is_present("card") || sub.is_present("element") {
+                    alsa_main(sub);
+                }
+            }
+
+            let backends = match sub.value_of_lossy("backend") {
+                None => audio::default_backends(),
+                Some(v) => audio::parse_backends(&v),
+            };
+            let backends = match backends {
+                Err(e) => {
+                    eprintln!("unable to parse backends: {:}", e);
+                    std::process::exit(1);
                 }
                 Ok(v) => v,
             };
 
-            match sub.subcommand() {
-                ("mute", Some(val)) => {
-                    match val.value_of_lossy("state") {
-                        Some(state) => {
-                            let mute_state = parse_bool(&state, p.muted());
-                            match p.set_muted(mute_state) {
-                                Err(e) => {
-                                    eprintln!("unable to change mute: {:}", e);
-                                    std::process::exit(3);
-                                }
-                                Ok(_) => (),
-                            };
-                        },
-                        None => {
-                            eprintln!("Must specify mute state");
-                            std::process::exit(1);
-                        },
-                    };
-                },
-                ("volume", Some(val)) => {
-                    match val.value_of_lossy("add") {
-                        Some(v) => {
-                            let step: f32 = match v.parse() {
-                                Err(e) => {
-                                    eprintln!("unable to parse increment: {:}", e);
-                                    std::process::exit(1);
-                                }
-                                Ok(v) => v,
-                            };
-                            let cap: Option<f32> = match val.value_of_lossy("max") {
-                                None => None,
-                                Some(v) => {
-                                    match v.parse::<f32>() {
-                                        Err(e) => {
-                                            eprintln!("unable to parse max: {:}", e);
-                                            std::process::exit(1);
-                                        }
-                                        Ok(v) => Some(v)
-                                    }
-                                },
-                            };
-                            match p.add_volume(step, cap) {
-                                Err(e) => {
-                                    eprintln!("unable to add volume: {:}", e);
-                                    std::process::exit(3);
-                                }
-                                Ok(_) => (),
-                            };
-                        },
-                        None => {
-                            eprintln!("Must specify volume increment");
-                            std::process::exit(1);
-                        },
-                    };
-                },
-                _ => {
-                    println!("{}", p.volume());
+            // Servers, sink selection, feedback, balance, fade and listing sinks need PulseAudio.
+            let pulse_only = if sub.is_present("server") {
+                Some("--server")
+            } else if sub.is_present("sink") {
+                Some("--sink")
+            } else {
+                match sub.subcommand() {
+                    ("balance", _) | ("fade", _) | ("sinks", _) | ("meter", _)
+                        | ("module", _) | ("sources", _) | ("suspend", _)
+                        | ("scene", _) | ("autoswitch", _) | ("mute-leds", _)
+                        | ("push-to-talk", _) => sub.subcommand_name(),
+                    ("volume", Some(val)) if val.is_present("feedback") => Some("--feedback"),
+                    _ => None,
+                }
+            };
+            if let Some(option) = pulse_only {
+                #[cfg(feature = "pulseaudio")]
+                {
+                    if backends.contains(&Backend::PulseAudio) {
+                        pulse_main(sub);
+                    }
                 }
+                eprintln!("{} is only supported by the {} backend", option, Backend::PulseAudio);
+                std::process::exit(1);
             }
+
+            match audio::open(&backends) {
+                Err(e) => {
+                    eprintln!("could not initialize an audio connector: {:}", e);
+                    std::process::exit(2);
+                }
+                Ok(v) => device_main(v, sub),
+            };
         }
         _ => {
             eprintln!("must specify subcommand");
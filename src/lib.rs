@@ -1,5 +1,6 @@
 pub mod audio;
 pub mod backlight;
+pub mod osd;
 
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,314 @@
+//! On-screen display of volume and brightness changes through the desktop notification daemon.
+//!
+//! Notifications are sent with `org.freedesktop.Notifications.Notify`, carrying the level as a
+//! `value` hint. Each kind of notification replaces the previous one, both through the
+//! `x-canonical-private-synchronous` hint and by passing the id of the last notification as the
+//! replace id, so that successive key presses update a single bubble. As every invocation of
+//! platformctl is a new process, the last ids are kept in files under `$XDG_RUNTIME_DIR`, or the
+//! user's cache directory.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+
+use dbus::arg::{RefArg, Variant};
+use dbus::{BusType, Connection, Message};
+
+const NOTIFICATIONS_NAME: &str = "org.freedesktop.Notifications";
+const NOTIFICATIONS_PATH: &str = "/org/freedesktop/Notifications";
+
+fn dbus_error(e: dbus::Error) -> Error {
+    Error::new(
+        ErrorKind::Other,
+        e.message().unwrap_or("D-Bus error").to_string(),
+    )
+}
+
+/// What an on-screen display shows the level of.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OsdKind {
+    Volume,
+    Brightness,
+}
+
+impl OsdKind {
+    /// The tag shared by all notifications of this kind, used to replace one another.
+    pub fn tag(&self) -> &'static str {
+        match self {
+            OsdKind::Volume => "volume",
+            OsdKind::Brightness => "brightness",
+        }
+    }
+
+    fn summary(&self) -> &'static str {
+        match self {
+            OsdKind::Volume => "Volume",
+            OsdKind::Brightness => "Brightness",
+        }
+    }
+
+    fn icon(&self, value: f32, muted: bool) -> &'static str {
+        match self {
+            OsdKind::Volume if muted || value <= 0.0 => "audio-volume-muted",
+            OsdKind::Volume if value < 0.34 => "audio-volume-low",
+            OsdKind::Volume if value < 0.67 => "audio-volume-medium",
+            OsdKind::Volume => "audio-volume-high",
+            OsdKind::Brightness => "display-brightness",
+        }
+    }
+}
+
+/// A connection to the notification daemon.
+pub struct Osd {
+    conn: Connection,
+    state_dir: PathBuf,
+    timeout_ms: i32,
+}
+
+impl Osd {
+    /// Connect to the notification daemon on the session bus.
+    pub fn new() -> Result<Self, Error> {
+        let conn = Connection::get_private(BusType::Session).map_err(dbus_error)?;
+        Ok(Osd::with_connection(conn, Osd::default_state_dir()?))
+    }
+
+    /// Connect to the notification daemon on the bus at `address`, e.g.
+    /// `unix:path=/run/user/1000/bus`.
+    pub fn open(address: &str, state_dir: PathBuf) -> Result<Self, Error> {
+        let conn = Connection::open_private(address).map_err(dbus_error)?;
+        conn.register().map_err(dbus_error)?;
+        Ok(Osd::with_connection(conn, state_dir))
+    }
+
+    pub fn with_connection(conn: Connection, state_dir: PathBuf) -> Self {
+        Osd {
+            conn,
+            state_dir,
+            timeout_ms: 2000,
+        }
+    }
+
+    /// Where the ids of the last notifications are kept, `$XDG_RUNTIME_DIR/platformctl`, or
+    /// `platformctl` in the user's cache directory if that is not set.
+    pub fn default_state_dir() -> Result<PathBuf, Error> {
+        let dir = env::var_os("XDG_RUNTIME_DIR")
+            .or_else(|| env::var_os("XDG_CACHE_HOME"))
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache")))
+            .ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    "none of XDG_RUNTIME_DIR, XDG_CACHE_HOME and HOME is set",
+                )
+            })?;
+        Ok(dir.join("platformctl"))
+    }
+
+    /// How long notifications stay up, in milliseconds.
+    pub fn set_timeout(&mut self, timeout_ms: i32) {
+        self.timeout_ms = timeout_ms;
+    }
+
+    fn id_path(&self, kind: OsdKind) -> PathBuf {
+        self.state_dir.join(format!("osd-{}", kind.tag()))
+    }
+
+    // The id of the last notification of this kind, or 0 to create a new one.
+    fn last_id(&self, kind: OsdKind) -> u32 {
+        fs::read_to_string(self.id_path(kind))
+            .ok()
+            .and_then(|id| id.trim().parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// Show `value`, a fraction of the full level, replacing the last notification of the same
+    /// kind. Returns the id of the notification.
+    pub fn show(&self, kind: OsdKind, value: f32, muted: bool) -> Result<u32, Error> {
+        let percent = (value.max(0.0) * 100.0).round() as i32;
+        let body = if muted {
+            "Muted".to_string()
+        } else {
+            format!("{}%", percent)
+        };
+        let mut hints: HashMap<&str, Variant<Box<dyn RefArg>>> = HashMap::new();
+        hints.insert("value", Variant(Box::new(percent)));
+        hints.insert(
+            "x-canonical-private-synchronous",
+            Variant(Box::new(kind.tag().to_string())),
+        );
+        hints.insert("urgency", Variant(Box::new(0u8)));
+
+        let msg = Message::new_method_call(
+            NOTIFICATIONS_NAME,
+            NOTIFICATIONS_PATH,
+            NOTIFICATIONS_NAME,
+            "Notify",
+        )
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?
+        .append3("platformctl", self.last_id(kind), kind.icon(value, muted))
+        .append3(kind.summary(), body, Vec::<&str>::new())
+        .append2(hints, self.timeout_ms);
+        let reply = self
+            .conn
+            .send_with_reply_and_block(msg, 1000)
+            .map_err(dbus_error)?;
+        let id: u32 = reply
+            .get1()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "invalid reply to Notify"))?;
+
+        fs::create_dir_all(&self.state_dir)?;
+        fs::write(self.id_path(kind), id.to_string())?;
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dbus::{MessageType, NameFlag};
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+
+    // A private session bus, shut down when dropped.
+    struct Bus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl Bus {
+        fn start() -> Self {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address=1"])
+                .stdout(Stdio::piped())
+                .spawn()
+                .expect("unable to start dbus-daemon");
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap())
+                .read_line(&mut address)
+                .unwrap();
+            Bus {
+                daemon,
+                address: address.trim().to_string(),
+            }
+        }
+    }
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    // What the mock notification server received for each Notify call.
+    #[derive(Debug, PartialEq)]
+    struct Notified {
+        replaces_id: u32,
+        summary: String,
+        body: String,
+        value: i64,
+        tag: String,
+    }
+
+    // A notification server handing out ids like a real one, until `count` calls were answered.
+    fn mock_server(address: &str, count: usize) -> Receiver<Notified> {
+        let address = address.to_string();
+        let (tx, rx) = channel();
+        let (ready_tx, ready_rx) = channel();
+        // Connections can not be sent between threads, so the server connects on its own.
+        thread::spawn(move || {
+            let conn = Connection::open_private(&address).unwrap();
+            conn.register().unwrap();
+            conn.register_name(NOTIFICATIONS_NAME, NameFlag::DoNotQueue as u32)
+                .unwrap();
+            // Method calls to unregistered paths are rejected by libdbus.
+            conn.register_object_path(NOTIFICATIONS_PATH).unwrap();
+            ready_tx.send(()).unwrap();
+            let mut next_id = 1;
+            let mut answered = 0;
+            while answered < count {
+                for msg in conn.incoming(100) {
+                    if msg.msg_type() != MessageType::MethodCall {
+                        continue;
+                    }
+                    let mut args = msg.iter_init();
+                    let _app_name: String = args.read().unwrap();
+                    let replaces_id: u32 = args.read().unwrap();
+                    let _icon: String = args.read().unwrap();
+                    let summary: String = args.read().unwrap();
+                    let body: String = args.read().unwrap();
+                    let _actions: Vec<String> = args.read().unwrap();
+                    let hints: HashMap<String, Variant<Box<dyn RefArg>>> = args.read().unwrap();
+                    let id = if replaces_id != 0 {
+                        replaces_id
+                    } else {
+                        next_id += 1;
+                        next_id - 1
+                    };
+                    conn.send(msg.method_return().append1(id)).unwrap();
+                    tx.send(Notified {
+                        replaces_id,
+                        summary,
+                        body,
+                        value: hints["value"].0.as_i64().unwrap(),
+                        tag: hints["x-canonical-private-synchronous"]
+                            .0
+                            .as_str()
+                            .unwrap()
+                            .to_string(),
+                    })
+                    .unwrap();
+                    answered += 1;
+                }
+            }
+        });
+        ready_rx.recv().unwrap();
+        rx
+    }
+
+    // Needs dbus-daemon, so it is run explicitly with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn successive_changes_replace_one_bubble() {
+        let bus = Bus::start();
+        let notified = mock_server(&bus.address, 3);
+        let state_dir = env::temp_dir().join(format!("platformctl_osd_{}", ::std::process::id()));
+        let osd = Osd::open(&bus.address, state_dir.clone()).unwrap();
+
+        assert_eq!(osd.show(OsdKind::Volume, 0.5, false).unwrap(), 1);
+        assert_eq!(
+            notified.recv().unwrap(),
+            Notified {
+                replaces_id: 0,
+                summary: "Volume".to_string(),
+                body: "50%".to_string(),
+                value: 50,
+                tag: "volume".to_string(),
+            }
+        );
+
+        // A later invocation picks up the bubble of the earlier one.
+        let osd = Osd::open(&bus.address, state_dir.clone()).unwrap();
+        assert_eq!(osd.show(OsdKind::Volume, 0.55, true).unwrap(), 1);
+        assert_eq!(
+            notified.recv().unwrap(),
+            Notified {
+                replaces_id: 1,
+                summary: "Volume".to_string(),
+                body: "Muted".to_string(),
+                value: 55,
+                tag: "volume".to_string(),
+            }
+        );
+
+        assert_eq!(osd.show(OsdKind::Brightness, 0.3, false).unwrap(), 2);
+        let brightness = notified.recv().unwrap();
+        assert_eq!(brightness.replaces_id, 0);
+        assert_eq!(brightness.tag, "brightness");
+        fs::remove_dir_all(state_dir).unwrap();
+    }
+}